@@ -0,0 +1,30 @@
+-- migrations/0006_create_sessions.sql
+
+-- One row per login (device). A session is a refresh token family: refresh_tokens.family_id
+-- is the session id. Access tokens carry it as `sid`, so revoking a session also rejects
+-- its outstanding access tokens.
+CREATE TABLE IF NOT EXISTS sessions (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    device_label TEXT,
+    user_agent TEXT,
+    ip TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user_id ON sessions(user_id);
+
+-- Backfill sessions for refresh token families issued before sessions existed
+INSERT INTO sessions (id, user_id, ip, created_at, last_seen_at, expires_at, revoked_at)
+SELECT family_id, user_id, 'unknown', MIN(created_at), MAX(created_at), MAX(expires_at),
+       CASE WHEN BOOL_AND(revoked_at IS NOT NULL OR used_at IS NOT NULL) THEN NOW() END
+FROM refresh_tokens
+GROUP BY family_id, user_id
+ON CONFLICT (id) DO NOTHING;
+
+ALTER TABLE refresh_tokens
+    ADD CONSTRAINT fk_refresh_tokens_session
+    FOREIGN KEY (family_id) REFERENCES sessions(id) ON DELETE CASCADE;
@@ -0,0 +1,13 @@
+-- migrations/0012_add_user_profiles.sql
+
+-- Optional profile fields shown in the app. deletion_scheduled_at is set when the user
+-- asks to delete their account: the account and everything it owns is purged once it
+-- passes, and signing in before then keeps the account.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS display_name TEXT,
+    ADD COLUMN IF NOT EXISTS avatar_url TEXT,
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN IF NOT EXISTS deletion_scheduled_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_users_deletion_scheduled_at
+    ON users(deletion_scheduled_at) WHERE deletion_scheduled_at IS NOT NULL;
@@ -0,0 +1,11 @@
+-- migrations/0013_create_user_preferences.sql
+
+-- Per-user settings document. `settings` only holds values the user changed, keyed by
+-- setting name; `schema_version` is the layout those keys follow, so older documents can
+-- be upgraded when read.
+CREATE TABLE IF NOT EXISTS user_preferences (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    schema_version INT NOT NULL,
+    settings JSONB NOT NULL DEFAULT '{}',
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,18 @@
+-- migrations/0014_create_note_permissions.sql
+
+-- Who may access a note, and how: owner (share, delete), editor (change) or viewer (read).
+-- A grant stops working at expires_at; owner grants never expire. notes.user_id remains
+-- the note's creator, who gets no grant: their access comes from their workspace role.
+CREATE TABLE IF NOT EXISTS note_permissions (
+    id UUID PRIMARY KEY,
+    note_id UUID NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+    granted_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (note_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_note_permissions_user_id ON note_permissions(user_id);
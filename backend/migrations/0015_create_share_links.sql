@@ -0,0 +1,22 @@
+-- migrations/0015_create_share_links.sql
+
+-- Anonymous links to a note, stored as SHA-256 hashes of their token. A link works until
+-- it is revoked, expires or has been opened max_uses times; use_count counts successful
+-- opens. password_hash (Argon2id or bcrypt) is set for password-protected links.
+CREATE TABLE IF NOT EXISTS share_links (
+    id UUID PRIMARY KEY,
+    note_id UUID NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    token_prefix TEXT NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('view', 'comment', 'edit')),
+    password_hash TEXT,
+    expires_at TIMESTAMPTZ,
+    max_uses INT,
+    use_count INT NOT NULL DEFAULT 0,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_share_links_note_id ON share_links(note_id);
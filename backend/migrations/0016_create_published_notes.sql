@@ -0,0 +1,17 @@
+-- migrations/0016_create_published_notes.sql
+
+-- Notes published as public pages at /p/{slug}. Each row holds a snapshot of the note as
+-- it was at its last publish (revision and updated_at included), so later edits stay
+-- private until the note is published again. Unpublishing keeps the row and its slug, so
+-- publishing again restores the same URL.
+CREATE TABLE IF NOT EXISTS published_notes (
+    note_id UUID PRIMARY KEY REFERENCES notes(id) ON DELETE CASCADE,
+    slug TEXT NOT NULL UNIQUE,
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    revision BIGINT NOT NULL,
+    note_updated_at TIMESTAMPTZ NOT NULL,
+    published_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    published_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    unpublished_at TIMESTAMPTZ
+);
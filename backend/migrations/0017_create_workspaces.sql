@@ -0,0 +1,68 @@
+-- migrations/0017_create_workspaces.sql
+
+-- Workspaces own notes. Every user has a personal workspace (personal_owner_id set), made
+-- on first use; team workspaces have members with one of four roles:
+--   owner  - everything below, plus delete the workspace and manage owners
+--   admin  - rename, invite and manage members, own every note
+--   member - create notes, edit every note
+--   guest  - only the notes shared with them individually
+CREATE TABLE IF NOT EXISTS workspaces (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    personal_owner_id UUID UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS workspace_members (
+    workspace_id UUID NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'admin', 'member', 'guest')),
+    invited_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (workspace_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_workspace_members_user_id ON workspace_members(user_id);
+
+-- Invitations by email, accepted with the emailed token (stored as its SHA-256 hash)
+CREATE TABLE IF NOT EXISTS workspace_invitations (
+    id UUID PRIMARY KEY,
+    workspace_id UUID NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('admin', 'member', 'guest')),
+    token_hash TEXT NOT NULL UNIQUE,
+    invited_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    accepted_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_workspace_invitations_workspace_id ON workspace_invitations(workspace_id);
+
+-- Existing notes move to their creator's personal workspace
+INSERT INTO workspaces (id, name, personal_owner_id, created_by)
+SELECT gen_random_uuid(), 'Personal', id, id FROM users
+ON CONFLICT (personal_owner_id) DO NOTHING;
+
+INSERT INTO workspace_members (workspace_id, user_id, role)
+SELECT id, personal_owner_id, 'owner' FROM workspaces WHERE personal_owner_id IS NOT NULL
+ON CONFLICT (workspace_id, user_id) DO NOTHING;
+
+ALTER TABLE notes ADD COLUMN IF NOT EXISTS workspace_id UUID REFERENCES workspaces(id) ON DELETE CASCADE;
+
+UPDATE notes n SET workspace_id = w.id
+FROM workspaces w WHERE w.personal_owner_id = n.user_id AND n.workspace_id IS NULL;
+
+ALTER TABLE notes ALTER COLUMN workspace_id SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_notes_workspace_id ON notes(workspace_id);
+
+-- Notes belong to their workspace, so a creator deleting their account no longer takes
+-- team notes along; personal notes still go with the personal workspace
+ALTER TABLE notes ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE notes DROP CONSTRAINT IF EXISTS notes_user_id_fkey;
+ALTER TABLE notes ADD CONSTRAINT notes_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL;
@@ -0,0 +1,39 @@
+-- migrations/0018_create_folders.sql
+
+-- Folder tree within a workspace. parent_id is NULL for top-level folders; siblings are
+-- ordered by position, then name. Deleting a folder deletes its subfolders and notes.
+CREATE TABLE IF NOT EXISTS folders (
+    id UUID PRIMARY KEY,
+    workspace_id UUID NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    parent_id UUID REFERENCES folders(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    position INT NOT NULL DEFAULT 0,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CHECK (parent_id <> id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_folders_workspace_parent ON folders(workspace_id, parent_id);
+CREATE INDEX IF NOT EXISTS idx_folders_parent_id ON folders(parent_id);
+
+-- Access to a folder, inherited by its subfolders and the notes inside them. A folder's
+-- creator gets no grant: like for notes, their access comes from their workspace role.
+CREATE TABLE IF NOT EXISTS folder_permissions (
+    id UUID PRIMARY KEY,
+    folder_id UUID NOT NULL REFERENCES folders(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+    granted_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (folder_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_folder_permissions_user_id ON folder_permissions(user_id);
+
+-- Notes outside any folder sit at the root of their workspace
+ALTER TABLE notes ADD COLUMN IF NOT EXISTS folder_id UUID REFERENCES folders(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS idx_notes_folder_id ON notes(folder_id);
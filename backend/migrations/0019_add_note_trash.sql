@@ -0,0 +1,8 @@
+-- migrations/0019_add_note_trash.sql
+
+-- Deleting a note moves it to the trash; it is restorable until purged after
+-- TRASH_RETENTION_DAYS, which deletes it with its revisions, grants and links
+ALTER TABLE notes ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+ALTER TABLE notes ADD COLUMN IF NOT EXISTS deleted_by UUID REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_notes_deleted_at ON notes(deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,15 @@
+-- migrations/0020_create_note_user_states.sql
+
+-- Each user's own pin, favorite and archive flags on a note (set when the timestamp is).
+-- Kept per user, since a shared note may be pinned by one person and archived by another.
+CREATE TABLE IF NOT EXISTS note_user_states (
+    note_id UUID NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    pinned_at TIMESTAMPTZ,
+    favorited_at TIMESTAMPTZ,
+    archived_at TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (note_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_note_user_states_user_id ON note_user_states(user_id);
@@ -0,0 +1,8 @@
+-- migrations/0021_add_folder_trash.sql
+
+-- Deleting a folder marks it and its subfolders deleted instead of removing them, so notes
+-- trashed with it keep their folder_id: its grants still reach them in the trash, and
+-- restoring puts them back. Deleted folders are purged along with those notes.
+ALTER TABLE folders ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_folders_deleted_at ON folders(deleted_at) WHERE deleted_at IS NOT NULL;
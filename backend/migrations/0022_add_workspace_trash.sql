@@ -0,0 +1,9 @@
+-- migrations/0022_add_workspace_trash.sql
+
+-- Deleting a team workspace marks it deleted and moves its notes and folders to the trash,
+-- all at the same time, so restoring it brings back exactly what the deletion took.
+-- Deleted workspaces are purged with their notes after TRASH_RETENTION_DAYS.
+ALTER TABLE workspaces ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+ALTER TABLE workspaces ADD COLUMN IF NOT EXISTS deleted_by UUID REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_workspaces_deleted_at ON workspaces(deleted_at) WHERE deleted_at IS NOT NULL;
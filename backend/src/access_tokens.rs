@@ -0,0 +1,260 @@
+use crate::{
+    auth::SessionUser,
+    errors::{AppError, AppResult},
+    models::PersonalAccessToken,
+    monitoring, utils,
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Marks personal access tokens, so they can be told apart from JWTs (and spotted by
+/// secret scanners).
+const TOKEN_PREFIX: &str = "nfp_";
+
+/// Characters of a token kept in `token_prefix` for display.
+const DISPLAY_PREFIX_LEN: usize = 12;
+
+/// What a personal access token may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    /// Read notes
+    #[serde(rename = "notes:read")]
+    NotesRead,
+    /// Create, update and delete notes
+    #[serde(rename = "notes:write")]
+    NotesWrite,
+    /// Read note revision history
+    #[serde(rename = "revisions:read")]
+    RevisionsRead,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::NotesRead => "notes:read",
+            Scope::NotesWrite => "notes:write",
+            Scope::RevisionsRead => "revisions:read",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Scope> {
+        match value {
+            "notes:read" => Some(Scope::NotesRead),
+            "notes:write" => Some(Scope::NotesWrite),
+            "revisions:read" => Some(Scope::RevisionsRead),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Lifetime in days; the token doesn't expire when omitted
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    info: PersonalAccessToken,
+    /// The token itself, shown only in this response
+    token: String,
+}
+
+/// A valid personal access token presented with a request.
+pub struct AuthenticatedToken {
+    pub user_id: Uuid,
+    pub scopes: Vec<Scope>,
+}
+
+/// Whether a bearer credential is a personal access token rather than a JWT.
+pub fn is_access_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+/// Resolve a personal access token that is neither revoked nor expired, recording its use.
+pub async fn authenticate(pool: &PgPool, token: &str) -> AppResult<Option<AuthenticatedToken>> {
+    let row = monitoring::timed(
+        "use_personal_access_token",
+        sqlx::query!(
+            r#"
+            UPDATE personal_access_tokens SET last_used_at = NOW()
+            WHERE token_hash = $1 AND revoked_at IS NULL
+              AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING user_id, scopes
+            "#,
+            utils::hash_token(token)
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Use personal access token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok(row.map(|row| AuthenticatedToken {
+        user_id: row.user_id,
+        scopes: row.scopes.iter().filter_map(|s| Scope::parse(s)).collect(),
+    }))
+}
+
+/// POST /api/users/me/tokens: create a personal access token. The token is returned once;
+/// only its hash is stored.
+#[tracing::instrument(name = "access_tokens.create", skip_all, fields(user_id = %user.id))]
+pub async fn create_token(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Json(payload): Json<CreateTokenRequest>,
+) -> AppResult<impl IntoResponse> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(AppError::BadRequest(
+            "Token name must be 1 to 100 characters".into(),
+        ));
+    }
+    if payload.scopes.is_empty() {
+        return Err(AppError::BadRequest(
+            "At least one scope is required".into(),
+        ));
+    }
+    let expires_at: Option<DateTime<Utc>> = match payload.expires_in_days {
+        Some(days) if !(1..=3650).contains(&days) => {
+            return Err(AppError::BadRequest(
+                "expires_in_days must be between 1 and 3650".into(),
+            ))
+        }
+        Some(days) => Some(Utc::now() + chrono::Duration::days(days)),
+        None => None,
+    };
+    let mut scopes: Vec<String> = payload
+        .scopes
+        .iter()
+        .map(|scope| scope.as_str().to_string())
+        .collect();
+    scopes.sort();
+    scopes.dedup();
+
+    let token = format!("{}{}", TOKEN_PREFIX, utils::generate_token());
+    let info = monitoring::timed(
+        "insert_personal_access_token",
+        sqlx::query_as!(
+            PersonalAccessToken,
+            r#"
+            INSERT INTO personal_access_tokens (id, user_id, name, token_hash, token_prefix, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, name, token_prefix, scopes, expires_at, last_used_at, created_at
+            "#,
+            Uuid::new_v4(),
+            user.id,
+            name,
+            utils::hash_token(&token),
+            &token[..DISPLAY_PREFIX_LEN],
+            &scopes,
+            expires_at
+        )
+        .fetch_one(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert personal access token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(user_id = %user.id, token_id = %info.id, "Personal access token created");
+    monitoring::record_auth("create_access_token", "success");
+    Ok((StatusCode::CREATED, AxumJson(CreatedToken { info, token })))
+}
+
+/// GET /api/users/me/tokens: the caller's personal access tokens that are not revoked,
+/// including expired ones.
+#[tracing::instrument(name = "access_tokens.list", skip_all, fields(user_id = %user.id))]
+pub async fn list_tokens(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    let tokens = monitoring::timed(
+        "list_personal_access_tokens",
+        sqlx::query_as!(
+            PersonalAccessToken,
+            r#"
+            SELECT id, name, token_prefix, scopes, expires_at, last_used_at, created_at
+            FROM personal_access_tokens
+            WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY created_at DESC
+            "#,
+            user.id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List personal access tokens error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(tokens)))
+}
+
+/// DELETE /api/users/me/tokens/{token_id}: revoke one of the caller's tokens. Requests made
+/// with it are rejected from then on.
+#[tracing::instrument(name = "access_tokens.revoke", skip_all, fields(user_id = %user.id, %token_id))]
+pub async fn revoke_token(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(token_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let revoked = monitoring::timed(
+        "revoke_personal_access_token",
+        sqlx::query!(
+            r#"
+            UPDATE personal_access_tokens SET revoked_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+            token_id,
+            user.id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke personal access token error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    if !revoked {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(user_id = %user.id, %token_id, "Personal access token revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Revoke every personal access token of a user, e.g. after a password reset.
+pub async fn revoke_all(pool: &PgPool, user_id: Uuid) -> AppResult<()> {
+    monitoring::timed(
+        "revoke_personal_access_tokens",
+        sqlx::query!(
+            "UPDATE personal_access_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke personal access tokens error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    Ok(())
+}
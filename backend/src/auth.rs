@@ -1,156 +1,1285 @@
-use crate::{models::Claims, utils};
+use crate::{
+    access_tokens::{self, Scope},
+    email_verification,
+    errors::{AppError, AppResult},
+    mailer::SharedMailer,
+    mfa,
+    models::{Claims, LoginAttempt, Session},
+    monitoring,
+    revocation::TokenDenylist,
+    users,
+    utils::{self, ClientIp},
+};
 use axum::{
-    extract::{Extension, Json},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Extension, FromRequestParts, Json, Path, Query},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json as AxumJson,
 };
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use tokio::sync::OnceCell;
 use uuid::Uuid;
 
+/// Hash verified against when the username does not exist, so unknown and known
+/// usernames take the same time to reject.
+static DUMMY_PASSWORD_HASH: OnceCell<String> = OnceCell::const_new();
+
+async fn dummy_password_hash() -> &'static str {
+    DUMMY_PASSWORD_HASH
+        .get_or_init(|| async {
+            utils::hash_password("noteflow-dummy-password")
+                .await
+                .unwrap_or_default()
+        })
+        .await
+}
+
 #[derive(Deserialize)]
 pub struct SignupRequest {
     pub username: String,
     pub password: String,
+    /// Optional; a verification link is sent to it
+    pub email: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
+    /// Optional device name shown in the session list, e.g. "Work laptop"
+    pub device_label: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct MfaLoginRequest {
+    /// Challenge token returned by `/api/login`
+    pub mfa_token: String,
+    /// 6-digit TOTP code or a recovery code
+    pub code: String,
+}
+
+/// Returned by `/api/login` instead of tokens when the account has TOTP enabled.
 #[derive(Serialize)]
-struct TokenResponse {
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    /// Exchanged together with a code at `/api/login/mfa`
+    pub mfa_token: String,
+    /// Challenge lifetime in seconds
+    pub expires_in: i64,
+}
+
+/// Outcome of a sign-in through an external identity provider.
+pub enum ExternalLogin {
+    Tokens(TokenResponse),
+    /// The account has TOTP enabled; finish at `/api/login/mfa`
+    MfaRequired(MfaChallengeResponse),
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    /// Short-lived access JWT, sent as `Authorization: Bearer`
     pub token: String,
+    /// Opaque single-use refresh token, exchanged at `/api/token/refresh`
+    pub refresh_token: String,
+    /// Access token lifetime in seconds
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginHistoryQuery {
+    pub limit: Option<i64>,
+}
+
+/// Caller signed in interactively, resolved from a valid `Authorization: Bearer` JWT that
+/// has not been revoked. Rejects the request with 401 otherwise, and personal access tokens
+/// with 403: account settings, sessions and tokens are only managed from a login.
+#[derive(Debug, Clone)]
+pub struct SessionUser {
+    pub id: Uuid,
+    /// ID of the access token used for this request
+    pub jti: Uuid,
+    /// Expiry of the access token used for this request (Unix seconds)
+    pub expires_at: i64,
+    /// Session the access token belongs to
+    pub session_id: Uuid,
 }
 
+impl SessionUser {
+    /// Authenticate an access JWT that has not been revoked.
+    async fn from_jwt(denylist: &TokenDenylist, token: &str) -> AppResult<SessionUser> {
+        if access_tokens::is_access_token(token) {
+            return Err(AppError::Forbidden(
+                "Personal access tokens can't be used here".into(),
+            ));
+        }
+        let claims = utils::decode_jwt(token).map_err(|_| AppError::Unauthorized)?;
+        if denylist.is_revoked(&claims).await {
+            return Err(AppError::Unauthorized);
+        }
+        Ok(SessionUser {
+            id: claims.sub,
+            jti: claims.jti,
+            expires_at: claims.exp,
+            session_id: claims.sid,
+        })
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for SessionUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = utils::bearer_token(&parts.headers).ok_or(AppError::Unauthorized)?;
+        let denylist = parts.extensions.get::<TokenDenylist>().ok_or_else(|| {
+            tracing::error!("TokenDenylist extension missing; rejecting authenticated request");
+            AppError::InternalServerError
+        })?;
+        SessionUser::from_jwt(denylist, token).await
+    }
+}
+
+/// Authenticated caller, signed in either interactively (JWT) or with a personal access
+/// token. Handlers check what the caller may do with [`AuthUser::require`].
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: Uuid,
+    /// Scopes of the personal access token used; `None` for a login, which may do anything
+    pub scopes: Option<Vec<Scope>>,
+}
+
+impl AuthUser {
+    /// Authenticate a JWT or personal access token. Used by the extractor, and directly for
+    /// credentials passed another way, like the `token` query parameter of a WebSocket.
+    pub async fn from_token(
+        pool: &PgPool,
+        denylist: &TokenDenylist,
+        token: &str,
+    ) -> AppResult<AuthUser> {
+        if access_tokens::is_access_token(token) {
+            let token = access_tokens::authenticate(pool, token)
+                .await?
+                .ok_or(AppError::Unauthorized)?;
+            return Ok(AuthUser {
+                id: token.user_id,
+                scopes: Some(token.scopes),
+            });
+        }
+        let user = SessionUser::from_jwt(denylist, token).await?;
+        Ok(AuthUser {
+            id: user.id,
+            scopes: None,
+        })
+    }
+
+    /// Reject the request with 403 unless the caller's credential grants `scope`.
+    pub fn require(&self, scope: Scope) -> AppResult<()> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(AppError::Forbidden(format!(
+                "Token lacks the {} scope",
+                scope.as_str()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = utils::bearer_token(&parts.headers).ok_or(AppError::Unauthorized)?;
+        let pool = parts.extensions.get::<PgPool>().ok_or_else(|| {
+            tracing::error!("PgPool extension missing; rejecting authenticated request");
+            AppError::InternalServerError
+        })?;
+        let denylist = parts.extensions.get::<TokenDenylist>().ok_or_else(|| {
+            tracing::error!("TokenDenylist extension missing; rejecting authenticated request");
+            AppError::InternalServerError
+        })?;
+        AuthUser::from_token(pool, denylist, token).await
+    }
+}
+
+/// Result of a login attempt as stored in `login_attempts.outcome`.
+#[derive(Debug, Clone, Copy)]
+enum LoginOutcome {
+    Success,
+    InvalidPassword,
+    UnknownUser,
+    Locked,
+    /// Correct password, second factor still pending
+    MfaRequired,
+    InvalidMfaCode,
+}
+
+impl LoginOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            LoginOutcome::Success => "success",
+            LoginOutcome::InvalidPassword => "invalid_password",
+            LoginOutcome::UnknownUser => "unknown_user",
+            LoginOutcome::Locked => "locked",
+            LoginOutcome::MfaRequired => "mfa_required",
+            LoginOutcome::InvalidMfaCode => "invalid_mfa_code",
+        }
+    }
+}
+
+/// Where a login attempt came from.
+struct AttemptSource {
+    username: String,
+    ip: String,
+    user_agent: Option<String>,
+}
+
+#[tracing::instrument(name = "auth.signup", skip_all, fields(username = %payload.username))]
 pub async fn signup(
     Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<SharedMailer>,
     Json(payload): Json<SignupRequest>,
-) -> impl IntoResponse {
+) -> AppResult<impl IntoResponse> {
+    let email = payload
+        .email
+        .as_deref()
+        .map(email_verification::normalize_email)
+        .transpose()?;
+
     // Hash password
-    let hashed = match utils::hash_password(&payload.password) {
-        Ok(h) => h,
-        Err(err) => {
+    let hashed = utils::hash_password(&payload.password)
+        .await
+        .map_err(|err| {
             tracing::error!("Password hashing error: {:?}", err);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(serde_json::json!({ "error": "Failed to hash password" })),
-            )
-                .into_response();
-        }
-    };
+            AppError::InternalServerError
+        })?;
 
     // Create user
     let user_id = Uuid::new_v4();
-    let res = sqlx::query!(
-        "INSERT INTO users (id, username, password_hash) VALUES ($1, $2, $3)",
-        user_id,
-        payload.username,
-        hashed
-    )
-    .execute(&pool)
-    .await;
-
-    match res {
-        Ok(_) => (
-            StatusCode::CREATED,
-            AxumJson(serde_json::json!({ "status": "ok" })),
+    monitoring::timed(
+        "insert_user",
+        sqlx::query!(
+            "INSERT INTO users (id, username, password_hash, email) VALUES ($1, $2, $3, $4)",
+            user_id,
+            payload.username,
+            hashed,
+            email
         )
-            .into_response(),
-        Err(e) => {
-            tracing::error!("DB insert user error: {:?}", e);
-            if e.to_string().contains("duplicate key") {
-                (
-                    StatusCode::CONFLICT,
-                    AxumJson(serde_json::json!({ "error": "Username already exists" })),
-                )
-                    .into_response()
-            } else {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    AxumJson(serde_json::json!({ "error": "Failed to create user" })),
-                )
-                    .into_response()
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("DB insert user error: {:?}", e);
+        monitoring::record_auth("signup", "failure");
+        match e.as_database_error() {
+            Some(db) if db.constraint() == Some("idx_users_email_lower") => {
+                AppError::Conflict("Email address already in use".into())
             }
+            Some(db) if db.is_unique_violation() => {
+                AppError::Conflict("Username already exists".into())
+            }
+            _ => AppError::InternalServerError,
+        }
+    })?;
+
+    // The account exists either way; a failed link can be re-sent later
+    if let Some(email) = &email {
+        let _ = email_verification::send_verification(&pool, mailer, user_id, email).await;
+    }
+
+    monitoring::record_auth("signup", "success");
+    Ok((
+        StatusCode::CREATED,
+        AxumJson(serde_json::json!({ "status": "ok" })),
+    ))
+}
+
+/// Replace a password hash made with an outdated algorithm or cost, now that the plaintext
+/// is at hand. Failures are only logged: the login itself is unaffected.
+async fn upgrade_password_hash(pool: &PgPool, user_id: Uuid, password: &str, old_hash: &str) {
+    let new_hash = match utils::hash_password(password).await {
+        Ok(hash) => hash,
+        Err(err) => {
+            tracing::error!("Password rehash error: {:?}", err);
+            return;
         }
+    };
+    // Only replace the hash that was verified, in case the password changed meanwhile
+    let result = monitoring::timed(
+        "upgrade_password_hash",
+        sqlx::query!(
+            "UPDATE users SET password_hash = $1 WHERE id = $2 AND password_hash = $3",
+            new_hash,
+            user_id,
+            old_hash
+        )
+        .execute(pool),
+    )
+    .await;
+    match result {
+        Ok(_) => tracing::info!(%user_id, "Password hash upgraded"),
+        Err(e) => tracing::error!("Upgrade password hash error: {:?}", e),
     }
 }
 
+#[tracing::instrument(name = "auth.login", skip_all, fields(username = %payload.username))]
 pub async fn login(
     Extension(pool): Extension<PgPool>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     Json(payload): Json<LoginRequest>,
-) -> impl IntoResponse {
+) -> AppResult<Response> {
+    let source = AttemptSource {
+        username: payload.username.clone(),
+        ip: ip.to_string(),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned),
+    };
+
     // Fetch user by username
-    let row = sqlx::query!(
-        "SELECT id, username, password_hash FROM users WHERE username = $1",
-        payload.username
+    let user = monitoring::timed(
+        "get_user_by_username",
+        sqlx::query!(
+            "SELECT id, username, password_hash, locked_until, totp_enabled_at FROM users WHERE username = $1",
+            payload.username
+        )
+        .fetch_optional(&pool),
     )
-    .fetch_optional(&pool)
-    .await;
+    .await
+    .map_err(|e| {
+        tracing::error!("DB query error: {:?}", e);
+        AppError::InternalServerError
+    })?;
 
-    let user = match row {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                AxumJson(serde_json::json!({ "error": "Invalid username or password" })),
-            )
-                .into_response()
-        }
-        Err(e) => {
-            tracing::error!("DB query error: {:?}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(serde_json::json!({ "error": "Login failed" })),
-            )
-                .into_response();
-        }
+    // Every rejection below is the same InvalidCredentials response, so callers can't
+    // tell unknown usernames, wrong passwords and locked accounts apart.
+    let Some(user) = user else {
+        let _ = utils::verify_password(&payload.password, dummy_password_hash().await).await;
+        record_login_attempt(&pool, None, &source, LoginOutcome::UnknownUser, false).await;
+        monitoring::record_auth("login", "failure");
+        return Err(AppError::InvalidCredentials);
     };
 
     // Verify password
-    let valid_password = match utils::verify_password(&payload.password, &user.password_hash) {
-        Ok(valid) => valid,
-        Err(err) => {
+    let valid_password = utils::verify_password(&payload.password, &user.password_hash)
+        .await
+        .map_err(|err| {
             tracing::error!("Password verification error: {:?}", err);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(serde_json::json!({ "error": "Login failed" })),
-            )
-                .into_response();
-        }
-    };
+            AppError::InternalServerError
+        })?;
+
+    if user.locked_until.is_some_and(|until| until > Utc::now()) {
+        record_login_attempt(&pool, Some(user.id), &source, LoginOutcome::Locked, false).await;
+        monitoring::record_auth("login", "locked");
+        return Err(AppError::InvalidCredentials);
+    }
 
     if !valid_password {
-        return (
-            StatusCode::UNAUTHORIZED,
-            AxumJson(serde_json::json!({ "error": "Invalid username or password" })),
+        register_failed_login(&pool, user.id).await;
+        record_login_attempt(
+            &pool,
+            Some(user.id),
+            &source,
+            LoginOutcome::InvalidPassword,
+            false,
+        )
+        .await;
+        monitoring::record_auth("login", "failure");
+        return Err(AppError::InvalidCredentials);
+    }
+
+    if utils::password_needs_rehash(&user.password_hash) {
+        upgrade_password_hash(&pool, user.id, &payload.password, &user.password_hash).await;
+    }
+
+    let device_label = payload
+        .device_label
+        .map(|label| label.trim().chars().take(100).collect::<String>())
+        .filter(|label| !label.is_empty());
+
+    // With TOTP enabled the password only earns a challenge. Failure counters are left
+    // alone until the second factor succeeds, so it can't be guessed past the lockout.
+    if user.totp_enabled_at.is_some() {
+        let mfa_token = mfa::create_challenge(&pool, user.id, device_label).await?;
+        record_login_attempt(
+            &pool,
+            Some(user.id),
+            &source,
+            LoginOutcome::MfaRequired,
+            false,
+        )
+        .await;
+        monitoring::record_auth("login", "mfa_required");
+        let challenge = MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+            expires_in: mfa::challenge_ttl(),
+        };
+        return Ok((StatusCode::OK, AxumJson(challenge)).into_response());
+    }
+
+    let tokens = complete_login(&pool, user.id, &user.username, &source, device_label).await?;
+    monitoring::record_auth("login", "success");
+    Ok((StatusCode::OK, AxumJson(tokens)).into_response())
+}
+
+/// POST /api/login/mfa: second login step. Exchanges the challenge from `/api/login` and a
+/// TOTP or recovery code for tokens. Each challenge allows `MFA_MAX_ATTEMPTS` (default 5)
+/// codes, and wrong codes count towards the account lockout like wrong passwords.
+#[tracing::instrument(name = "auth.login_mfa", skip_all)]
+pub async fn login_mfa(
+    Extension(pool): Extension<PgPool>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<MfaLoginRequest>,
+) -> AppResult<impl IntoResponse> {
+    let max_attempts: i32 = utils::env_or("MFA_MAX_ATTEMPTS", 5);
+
+    // Count the attempt up front, so concurrent guesses can't exceed the limit
+    let challenge = monitoring::timed(
+        "claim_mfa_attempt",
+        sqlx::query!(
+            r#"
+            UPDATE mfa_challenges SET attempts = attempts + 1
+            FROM users u
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW() AND attempts < $2
+              AND u.id = mfa_challenges.user_id
+            RETURNING mfa_challenges.id, mfa_challenges.device_label, u.id AS user_id,
+                      u.username, u.locked_until
+            "#,
+            utils::hash_token(&payload.mfa_token),
+            max_attempts
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Claim MFA attempt error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let Some(challenge) = challenge else {
+        monitoring::record_auth("login_mfa", "invalid_challenge");
+        return Err(AppError::Unauthorized);
+    };
+    let source = AttemptSource {
+        username: challenge.username.clone(),
+        ip: ip.to_string(),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned),
+    };
+
+    if challenge
+        .locked_until
+        .is_some_and(|until| until > Utc::now())
+    {
+        record_login_attempt(
+            &pool,
+            Some(challenge.user_id),
+            &source,
+            LoginOutcome::Locked,
+            false,
+        )
+        .await;
+        monitoring::record_auth("login_mfa", "locked");
+        return Err(AppError::Unauthorized);
+    }
+
+    if !mfa::verify_code(&pool, challenge.user_id, &payload.code).await? {
+        register_failed_login(&pool, challenge.user_id).await;
+        record_login_attempt(
+            &pool,
+            Some(challenge.user_id),
+            &source,
+            LoginOutcome::InvalidMfaCode,
+            false,
+        )
+        .await;
+        monitoring::record_auth("login_mfa", "failure");
+        return Err(AppError::Unauthorized);
+    }
+
+    let claimed = monitoring::timed(
+        "use_mfa_challenge",
+        sqlx::query!(
+            "UPDATE mfa_challenges SET used_at = NOW() WHERE id = $1 AND used_at IS NULL",
+            challenge.id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Use MFA challenge error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    if !claimed {
+        monitoring::record_auth("login_mfa", "invalid_challenge");
+        return Err(AppError::Unauthorized);
+    }
+
+    let tokens = complete_login(
+        &pool,
+        challenge.user_id,
+        &challenge.username,
+        &source,
+        challenge.device_label,
+    )
+    .await?;
+    monitoring::record_auth("login_mfa", "success");
+    Ok((StatusCode::OK, AxumJson(tokens)))
+}
+
+/// Finish a successful login: reset the failure counters, record the attempt, and start a
+/// new session, which is also its refresh token family.
+async fn complete_login(
+    pool: &PgPool,
+    user_id: Uuid,
+    username: &str,
+    source: &AttemptSource,
+    device_label: Option<String>,
+) -> AppResult<TokenResponse> {
+    monitoring::timed(
+        "reset_failed_logins",
+        sqlx::query!(
+            "UPDATE users SET failed_login_count = 0, lockout_count = 0, locked_until = NULL WHERE id = $1",
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Reset failed logins error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    users::cancel_scheduled_deletion(pool, user_id).await?;
+
+    let new_ip = is_new_ip(pool, user_id, &source.ip).await;
+    if new_ip {
+        tracing::warn!(user_id = %user_id, ip = %source.ip, "Sign-in from a new IP address");
+        monitoring::record_login_anomaly("new_ip");
+    }
+    record_login_attempt(pool, Some(user_id), source, LoginOutcome::Success, new_ip).await;
+
+    let session_id = Uuid::new_v4();
+    monitoring::timed(
+        "insert_session",
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions (id, user_id, device_label, user_agent, ip, expires_at)
+            VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6))
+            "#,
+            session_id,
+            user_id,
+            device_label,
+            source.user_agent,
+            source.ip,
+            refresh_token_ttl() as f64
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert session error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    issue_tokens(pool, user_id, username, session_id).await
+}
+
+/// Sign in a user who authenticated with an external identity provider (see `oidc`).
+/// The provider vouches for the password only: a locked account stays locked, and an
+/// account with TOTP enabled gets the same MFA challenge as a password login.
+pub async fn external_login(
+    pool: &PgPool,
+    user_id: Uuid,
+    username: &str,
+    ip: &str,
+    user_agent: Option<String>,
+) -> AppResult<ExternalLogin> {
+    let source = AttemptSource {
+        username: username.to_string(),
+        ip: ip.to_string(),
+        user_agent,
+    };
+    let user = monitoring::timed(
+        "get_user_login_state",
+        sqlx::query!(
+            "SELECT locked_until, totp_enabled_at FROM users WHERE id = $1",
+            user_id
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("DB query error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::InvalidCredentials)?;
+
+    if user.locked_until.is_some_and(|until| until > Utc::now()) {
+        record_login_attempt(pool, Some(user_id), &source, LoginOutcome::Locked, false).await;
+        return Err(AppError::InvalidCredentials);
+    }
+
+    let device_label = Some("Single sign-on".to_string());
+    if user.totp_enabled_at.is_some() {
+        let mfa_token = mfa::create_challenge(pool, user_id, device_label).await?;
+        record_login_attempt(
+            pool,
+            Some(user_id),
+            &source,
+            LoginOutcome::MfaRequired,
+            false,
+        )
+        .await;
+        return Ok(ExternalLogin::MfaRequired(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+            expires_in: mfa::challenge_ttl(),
+        }));
+    }
+
+    let tokens = complete_login(pool, user_id, username, &source, device_label).await?;
+    Ok(ExternalLogin::Tokens(tokens))
+}
+
+/// POST /api/token/refresh: exchange a refresh token for a new access token and a new
+/// refresh token (rotation). A refresh token that was already used revokes its whole
+/// family, i.e. its session.
+#[tracing::instrument(name = "auth.refresh", skip_all)]
+pub async fn refresh(
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<RefreshRequest>,
+) -> AppResult<impl IntoResponse> {
+    let token_hash = utils::hash_token(&payload.refresh_token);
+
+    // Claim the token atomically so concurrent presentations can't both rotate it
+    let claimed = monitoring::timed(
+        "claim_refresh_token",
+        sqlx::query!(
+            r#"
+            UPDATE refresh_tokens rt SET used_at = NOW()
+            FROM users u
+            WHERE rt.token_hash = $1
+              AND rt.used_at IS NULL
+              AND rt.revoked_at IS NULL
+              AND rt.expires_at > NOW()
+              AND u.id = rt.user_id
+            RETURNING rt.user_id, rt.family_id, u.username
+            "#,
+            token_hash
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Claim refresh token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    if let Some(row) = claimed {
+        let tokens = issue_tokens(&pool, row.user_id, &row.username, row.family_id).await?;
+        monitoring::timed(
+            "touch_session",
+            sqlx::query!(
+                r#"
+                UPDATE sessions
+                SET last_seen_at = NOW(), ip = $2, expires_at = NOW() + make_interval(secs => $3)
+                WHERE id = $1
+                "#,
+                row.family_id,
+                ip.to_string(),
+                refresh_token_ttl() as f64
+            )
+            .execute(&pool),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Touch session error: {:?}", e);
+            AppError::InternalServerError
+        })?;
+        monitoring::record_auth("refresh", "success");
+        return Ok((StatusCode::OK, AxumJson(tokens)));
+    }
+
+    // Not claimable: unknown, expired, revoked, or already rotated (reuse)
+    let existing = monitoring::timed(
+        "get_refresh_token",
+        sqlx::query!(
+            "SELECT user_id, family_id, used_at FROM refresh_tokens WHERE token_hash = $1",
+            token_hash
         )
-            .into_response();
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get refresh token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    if let Some(token) = existing.filter(|t| t.used_at.is_some()) {
+        // A rotated token came back: a copy leaked, so end every session descended from it
+        tracing::warn!(
+            user_id = %token.user_id,
+            family_id = %token.family_id,
+            "Refresh token reuse detected; revoking token family"
+        );
+        monitoring::record_login_anomaly("refresh_token_reuse");
+        revoke_session(&pool, &denylist, token.user_id, token.family_id).await?;
     }
 
+    monitoring::record_auth("refresh", "failure");
+    Err(AppError::Unauthorized)
+}
+
+/// POST /api/logout: end the caller's session, revoking its access and refresh tokens.
+#[tracing::instrument(name = "auth.logout", skip_all, fields(user_id = %user.id))]
+pub async fn logout(
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    denylist.revoke_token(user.jti, user.expires_at).await;
+    revoke_session(&pool, &denylist, user.id, user.session_id).await?;
+
+    monitoring::record_auth("logout", "success");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/logout/all: revoke every access and refresh token of the caller, ending all
+/// of their sessions on every device.
+#[tracing::instrument(name = "auth.logout_all", skip_all, fields(user_id = %user.id))]
+pub async fn logout_all(
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    revoke_all_sessions(&pool, &denylist, user.id).await?;
+
+    monitoring::record_auth("logout_all", "success");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/users/me/sessions: the caller's active sessions, most recently used first.
+#[tracing::instrument(name = "auth.list_sessions", skip_all, fields(user_id = %user.id))]
+pub async fn list_sessions(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    let sessions = monitoring::timed(
+        "list_sessions",
+        sqlx::query_as!(
+            Session,
+            r#"
+            SELECT id, device_label, user_agent, ip, created_at, last_seen_at, id = $2 AS "current!"
+            FROM sessions
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            ORDER BY last_seen_at DESC
+            "#,
+            user.id,
+            user.session_id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List sessions error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(sessions)))
+}
+
+/// DELETE /api/users/me/sessions/{session_id}: sign out one of the caller's devices.
+#[tracing::instrument(name = "auth.delete_session", skip_all, fields(user_id = %user.id, %session_id))]
+pub async fn delete_session(
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    user: SessionUser,
+    Path(session_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    if !revoke_session(&pool, &denylist, user.id, session_id).await? {
+        return Err(AppError::NotFound);
+    }
+    monitoring::record_auth("revoke_session", "success");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Revoke every session of `user_id`: all refresh tokens stop working and all access
+/// tokens issued so far are denylisted.
+pub async fn revoke_all_sessions(
+    pool: &PgPool,
+    denylist: &TokenDenylist,
+    user_id: Uuid,
+) -> AppResult<()> {
+    monitoring::timed(
+        "revoke_user_sessions",
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke user sessions error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    monitoring::timed(
+        "revoke_user_refresh_tokens",
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke user refresh tokens error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    denylist
+        .revoke_user_tokens(user_id, access_token_ttl())
+        .await;
+    Ok(())
+}
+
+/// Revoke every session of `user_id` except `keep`, e.g. the one that changed the password.
+pub async fn revoke_other_sessions(
+    pool: &PgPool,
+    denylist: &TokenDenylist,
+    user_id: Uuid,
+    keep: Uuid,
+) -> AppResult<()> {
+    let session_ids = monitoring::timed(
+        "list_other_sessions",
+        sqlx::query_scalar!(
+            "SELECT id FROM sessions WHERE user_id = $1 AND id <> $2 AND revoked_at IS NULL",
+            user_id,
+            keep
+        )
+        .fetch_all(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List other sessions error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    for session_id in session_ids {
+        revoke_session(pool, denylist, user_id, session_id).await?;
+    }
+    Ok(())
+}
+
+/// Revoke one of `user_id`'s sessions: its refresh tokens stop working and access tokens
+/// referencing it are denylisted. Returns false when no active session matched.
+async fn revoke_session(
+    pool: &PgPool,
+    denylist: &TokenDenylist,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> AppResult<bool> {
+    let revoked = monitoring::timed(
+        "revoke_session",
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+            session_id,
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke session error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    monitoring::timed(
+        "revoke_refresh_family",
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND user_id = $2 AND revoked_at IS NULL",
+            session_id,
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke refresh family error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    if revoked {
+        denylist
+            .revoke_session(session_id, access_token_ttl())
+            .await;
+    }
+    Ok(revoked)
+}
+
+/// Access token lifetime in seconds (`ACCESS_TOKEN_TTL_SECS`, default 15 minutes).
+fn access_token_ttl() -> i64 {
+    utils::env_or("ACCESS_TOKEN_TTL_SECS", 15 * 60)
+}
+
+/// Refresh token and idle session lifetime in seconds (`REFRESH_TOKEN_TTL_SECS`, default 30 days).
+fn refresh_token_ttl() -> i64 {
+    utils::env_or("REFRESH_TOKEN_TTL_SECS", 30 * 24 * 60 * 60)
+}
+
+/// Issue a short-lived access JWT (see [`access_token_ttl`]) for `session_id` and a
+/// refresh token in that session's family (see [`refresh_token_ttl`]).
+async fn issue_tokens(
+    pool: &PgPool,
+    user_id: Uuid,
+    username: &str,
+    session_id: Uuid,
+) -> AppResult<TokenResponse> {
+    let access_ttl = access_token_ttl();
+    let refresh_ttl = refresh_token_ttl();
+    let now = Utc::now();
+
     // Create JWT token
-    let expiration = Utc::now().timestamp() + 60 * 60 * 24; // 1 day
     let claims = Claims {
-        sub: user.id,
-        username: user.username.clone(),
-        exp: expiration,
+        sub: user_id,
+        username: username.to_string(),
+        exp: now.timestamp() + access_ttl,
+        iat: now.timestamp(),
+        jti: Uuid::new_v4(),
+        sid: session_id,
     };
+    let token = utils::encode_jwt(&claims).map_err(|err| {
+        tracing::error!("JWT encode error: {:?}", err);
+        AppError::InternalServerError
+    })?;
 
-    let token = match utils::encode_jwt(&claims) {
-        Ok(t) => t,
-        Err(err) => {
-            tracing::error!("JWT encode error: {:?}", err);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(serde_json::json!({ "error": "Failed to generate token" })),
-            )
-                .into_response();
+    let refresh_token = utils::generate_token();
+    monitoring::timed(
+        "insert_refresh_token",
+        sqlx::query!(
+            r#"
+            INSERT INTO refresh_tokens (id, user_id, family_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            session_id,
+            utils::hash_token(&refresh_token),
+            now + chrono::Duration::seconds(refresh_ttl)
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert refresh token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok(TokenResponse {
+        token,
+        refresh_token,
+        expires_in: access_ttl,
+    })
+}
+
+/// GET /api/users/me/logins: the caller's most recent sign-in attempts (default 20, max 100).
+#[tracing::instrument(name = "auth.list_logins", skip_all, fields(user_id = %user.id))]
+pub async fn list_logins(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Query(query): Query<LoginHistoryQuery>,
+) -> AppResult<impl IntoResponse> {
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let attempts = monitoring::timed(
+        "list_login_attempts",
+        sqlx::query_as!(
+            LoginAttempt,
+            r#"
+            SELECT id, ip, user_agent, outcome, new_ip, created_at
+            FROM login_attempts
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2
+            "#,
+            user.id,
+            limit
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List login attempts error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(attempts)))
+}
+
+/// Count a failed password for the account. Reaching `LOGIN_MAX_FAILED_ATTEMPTS` (default 5)
+/// locks it for `LOGIN_LOCKOUT_BASE_SECS` (default 60), doubling with each consecutive
+/// lockout up to `LOGIN_LOCKOUT_MAX_SECS` (default 1 day).
+async fn register_failed_login(pool: &PgPool, user_id: Uuid) {
+    let max_attempts: i32 = utils::env_or("LOGIN_MAX_FAILED_ATTEMPTS", 5);
+    let base_secs: f64 = utils::env_or("LOGIN_LOCKOUT_BASE_SECS", 60.0);
+    let max_secs: f64 = utils::env_or("LOGIN_LOCKOUT_MAX_SECS", 86_400.0);
+
+    let res = monitoring::timed(
+        "register_failed_login",
+        sqlx::query_scalar!(
+            r#"
+            UPDATE users SET
+                failed_login_count = CASE WHEN failed_login_count + 1 >= $2 THEN 0
+                                          ELSE failed_login_count + 1 END,
+                lockout_count = CASE WHEN failed_login_count + 1 >= $2 THEN lockout_count + 1
+                                     ELSE lockout_count END,
+                locked_until = CASE WHEN failed_login_count + 1 >= $2
+                                    THEN NOW() + make_interval(secs => LEAST($3 * power(2, lockout_count), $4))
+                                    ELSE locked_until END
+            WHERE id = $1
+            RETURNING locked_until
+            "#,
+            user_id,
+            max_attempts,
+            base_secs,
+            max_secs
+        )
+        .fetch_one(pool),
+    )
+    .await;
+
+    match res {
+        Ok(Some(until)) if until > Utc::now() => {
+            tracing::warn!(%user_id, locked_until = %until, "Account locked after repeated failed logins");
+            monitoring::record_login_anomaly("lockout");
         }
-    };
+        Ok(_) => {}
+        Err(e) => tracing::error!("Register failed login error: {:?}", e),
+    }
+}
+
+/// A successful login is anomalous when the account has signed in before, but never from this IP.
+async fn is_new_ip(pool: &PgPool, user_id: Uuid, ip: &str) -> bool {
+    let res = monitoring::timed(
+        "check_known_ip",
+        sqlx::query!(
+            r#"
+            SELECT
+                EXISTS(SELECT 1 FROM login_attempts WHERE user_id = $1 AND outcome = 'success') AS "has_history!",
+                EXISTS(SELECT 1 FROM login_attempts WHERE user_id = $1 AND outcome = 'success' AND ip = $2) AS "seen_ip!"
+            "#,
+            user_id,
+            ip
+        )
+        .fetch_one(pool),
+    )
+    .await;
 
-    (StatusCode::OK, AxumJson(TokenResponse { token })).into_response()
+    match res {
+        Ok(row) => row.has_history && !row.seen_ip,
+        Err(e) => {
+            tracing::error!("Known IP check error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Persist a login attempt. Failures are logged but never fail the login itself.
+async fn record_login_attempt(
+    pool: &PgPool,
+    user_id: Option<Uuid>,
+    source: &AttemptSource,
+    outcome: LoginOutcome,
+    new_ip: bool,
+) {
+    let res = monitoring::timed(
+        "insert_login_attempt",
+        sqlx::query!(
+            r#"
+            INSERT INTO login_attempts (id, user_id, username, ip, user_agent, outcome, new_ip)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            source.username,
+            source.ip,
+            source.user_agent,
+            outcome.as_str(),
+            new_ip
+        )
+        .execute(pool),
+    )
+    .await;
+
+    if let Err(e) = res {
+        tracing::error!("Record login attempt error: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::net::IpAddr;
+
+    async fn lockout(pool: &PgPool, user_id: Uuid) -> (i32, Option<i64>) {
+        let row = sqlx::query!(
+            r#"
+            SELECT lockout_count, EXTRACT(EPOCH FROM locked_until - NOW())::BIGINT AS secs_left
+            FROM users WHERE id = $1
+            "#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        (row.lockout_count, row.secs_left)
+    }
+
+    #[sqlx::test]
+    async fn repeated_failed_logins_lock_for_a_doubling_time(pool: PgPool) {
+        let user_id = test_support::create_user(&pool, "ann").await;
+
+        for _ in 0..4 {
+            register_failed_login(&pool, user_id).await;
+        }
+        assert_eq!(lockout(&pool, user_id).await, (0, None));
+
+        register_failed_login(&pool, user_id).await;
+        let (count, secs_left) = lockout(&pool, user_id).await;
+        assert_eq!(count, 1);
+        assert!((58..=60).contains(&secs_left.unwrap()));
+
+        for _ in 0..5 {
+            register_failed_login(&pool, user_id).await;
+        }
+        let (count, secs_left) = lockout(&pool, user_id).await;
+        assert_eq!(count, 2);
+        assert!((118..=120).contains(&secs_left.unwrap()));
+    }
+
+    #[sqlx::test]
+    async fn lockout_backoff_is_capped(pool: PgPool) {
+        let user_id = test_support::create_user(&pool, "ann").await;
+        sqlx::query!("UPDATE users SET lockout_count = 20 WHERE id = $1", user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        for _ in 0..5 {
+            register_failed_login(&pool, user_id).await;
+        }
+        let (_, secs_left) = lockout(&pool, user_id).await;
+        assert!((86_398..=86_400).contains(&secs_left.unwrap()));
+    }
+
+    async fn refresh_with(
+        pool: &PgPool,
+        denylist: &TokenDenylist,
+        refresh_token: &str,
+    ) -> AppResult<TokenResponse> {
+        let response = refresh(
+            Extension(pool.clone()),
+            Extension(denylist.clone()),
+            ClientIp(IpAddr::from([127, 0, 0, 1])),
+            Json(RefreshRequest {
+                refresh_token: refresh_token.to_string(),
+            }),
+        )
+        .await?
+        .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        Ok(TokenResponse {
+            token: body["token"].as_str().unwrap().to_string(),
+            refresh_token: body["refresh_token"].as_str().unwrap().to_string(),
+            expires_in: body["expires_in"].as_i64().unwrap(),
+        })
+    }
+
+    #[sqlx::test]
+    async fn refresh_rotates_the_token(pool: PgPool) {
+        test_support::init_jwt_keys();
+        let denylist = TokenDenylist::new(None);
+        let user_id = test_support::create_user(&pool, "ann").await;
+        let session_id = test_support::create_session(&pool, user_id).await;
+        let first = issue_tokens(&pool, user_id, "ann", session_id)
+            .await
+            .unwrap();
+
+        let second = refresh_with(&pool, &denylist, &first.refresh_token)
+            .await
+            .unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        let claims = utils::decode_jwt(&second.token).unwrap();
+        assert_eq!((claims.sub, claims.sid), (user_id, session_id));
+
+        let third = refresh_with(&pool, &denylist, &second.refresh_token).await;
+        assert!(third.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn reusing_a_refresh_token_revokes_its_family(pool: PgPool) {
+        test_support::init_jwt_keys();
+        let denylist = TokenDenylist::new(None);
+        let user_id = test_support::create_user(&pool, "ann").await;
+        let session_id = test_support::create_session(&pool, user_id).await;
+        let other_session = test_support::create_session(&pool, user_id).await;
+        let first = issue_tokens(&pool, user_id, "ann", session_id)
+            .await
+            .unwrap();
+        let other = issue_tokens(&pool, user_id, "ann", other_session)
+            .await
+            .unwrap();
+        let second = refresh_with(&pool, &denylist, &first.refresh_token)
+            .await
+            .unwrap();
+
+        // The rotated token comes back: the whole session ends, the newer token included
+        let reuse = refresh_with(&pool, &denylist, &first.refresh_token).await;
+        assert!(matches!(reuse, Err(AppError::Unauthorized)));
+        let newer = refresh_with(&pool, &denylist, &second.refresh_token).await;
+        assert!(matches!(newer, Err(AppError::Unauthorized)));
+        let claims = utils::decode_jwt(&second.token).unwrap();
+        assert!(denylist.is_revoked(&claims).await);
+
+        let revoked_at =
+            sqlx::query_scalar!("SELECT revoked_at FROM sessions WHERE id = $1", session_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(revoked_at.is_some());
+
+        // Other sessions are left alone
+        assert!(refresh_with(&pool, &denylist, &other.refresh_token)
+            .await
+            .is_ok());
+    }
+
+    #[sqlx::test]
+    async fn unknown_refresh_tokens_are_refused(pool: PgPool) {
+        let denylist = TokenDenylist::new(None);
+        let result = refresh_with(&pool, &denylist, "not-a-token").await;
+        assert!(matches!(result, Err(AppError::Unauthorized)));
+    }
 }
@@ -1,13 +1,24 @@
-use crate::models::Note;
+use crate::{
+    access_tokens::Scope,
+    auth::AuthUser,
+    errors::{AppError, AppResult},
+    folders,
+    models::{Note, NoteState, Revision},
+    monitoring,
+    permissions::{self, Role},
+    utils,
+    workspaces::{self, WorkspaceRole},
+};
 use axum::{
-    extract::{Extension, Json, Path},
+    extract::{Extension, Json, Path, Query},
     http::StatusCode,
     response::IntoResponse,
     Json as AxumJson,
 };
-use chrono::Utc;
-use serde::Deserialize;
-use serde_json::json;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -17,6 +28,10 @@ pub struct CreateNoteRequest {
     pub title: String,
     pub body: String,
     pub tags: Vec<String>, // maps to TEXT[]; column has DEFAULT '{}' (non-null), but we keep Option in model for sqlx compatibility
+    /// Workspace to create the note in; the caller's personal workspace when omitted
+    pub workspace_id: Option<Uuid>,
+    /// Folder to create the note in; the workspace root when omitted
+    pub folder_id: Option<Uuid>,
 }
 
 #[derive(Deserialize)]
@@ -26,139 +41,537 @@ pub struct UpdateNoteRequest {
     pub tags: Option<Vec<String>>,
 }
 
+/// Which archived notes a listing includes.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchivedFilter {
+    #[default]
+    Exclude,
+    Include,
+    Only,
+}
+
+/// Notes per page of a listing, unless `limit` says otherwise.
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+/// Longest excerpt in note summaries, in characters.
+const EXCERPT_CHARS: usize = 200;
+/// Body characters read to make an excerpt, leaving room for Markdown around its text.
+const EXCERPT_SOURCE_CHARS: i32 = 2000;
+/// What `fields` may select from.
+const LISTED_FIELDS: &[&str] = &[
+    "id",
+    "user_id",
+    "workspace_id",
+    "folder_id",
+    "title",
+    "body",
+    "excerpt",
+    "tags",
+    "revision",
+    "created_at",
+    "updated_at",
+    "pinned",
+    "favorite",
+    "archived",
+];
+
+/// Order of a listing, after pinned notes: newest first for dates, A to Z for titles.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteSort {
+    #[default]
+    Updated,
+    Created,
+    Title,
+}
+
+impl NoteSort {
+    fn as_str(self) -> &'static str {
+        match self {
+            NoteSort::Updated => "updated",
+            NoteSort::Created => "created",
+            NoteSort::Title => "title",
+        }
+    }
+}
+
+/// Shape of listed notes: summaries with an excerpt, or whole notes with their body.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteView {
+    #[default]
+    Summary,
+    Full,
+}
+
+#[derive(Deserialize)]
+pub struct ListNotesQuery {
+    /// Only notes the caller pinned (`true`) or didn't pin (`false`)
+    pub pinned: Option<bool>,
+    /// Only notes the caller favorited (`true`) or didn't (`false`)
+    pub favorite: Option<bool>,
+    #[serde(default)]
+    pub archived: ArchivedFilter,
+    #[serde(default)]
+    pub sort: NoteSort,
+    #[serde(default)]
+    pub view: NoteView,
+    /// Comma-separated fields to return instead of the view's (`id` is always included)
+    pub fields: Option<String>,
+    pub limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+/// A note as listed for the caller, with their own flags on it.
+#[derive(Serialize)]
+struct ListedNote {
+    #[serde(flatten)]
+    note: Note,
+    #[serde(flatten)]
+    state: NoteState,
+}
+
+/// A note without its body, for listings.
+#[derive(Serialize)]
+struct NoteSummary {
+    id: Uuid,
+    user_id: Option<Uuid>,
+    workspace_id: Uuid,
+    folder_id: Option<Uuid>,
+    title: String,
+    /// The start of the body as plain text
+    excerpt: String,
+    tags: Vec<String>,
+    revision: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(flatten)]
+    state: NoteState,
+}
+
+/// Where a page of a listing ended. Sent to clients as an opaque token; only valid for the
+/// sort order it was made for.
+#[derive(Serialize, Deserialize)]
+struct NoteCursor {
+    sort: NoteSort,
+    pinned: bool,
+    /// `updated_at` or `created_at` of the last note, as sorted by
+    at: Option<DateTime<Utc>>,
+    title: Option<String>,
+    id: Uuid,
+}
+
+impl NoteCursor {
+    fn after(note: &Note, pinned: bool, sort: NoteSort) -> Self {
+        NoteCursor {
+            sort,
+            pinned,
+            at: match sort {
+                NoteSort::Updated => Some(note.updated_at),
+                NoteSort::Created => Some(note.created_at),
+                NoteSort::Title => None,
+            },
+            title: (sort == NoteSort::Title).then(|| note.title.clone()),
+            id: note.id,
+        }
+    }
+
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str, sort: NoteSort) -> AppResult<Self> {
+        let cursor: NoteCursor = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| AppError::BadRequest("Invalid cursor".into()))?;
+        if cursor.sort != sort {
+            return Err(AppError::BadRequest(
+                "The cursor was made for another sort order".into(),
+            ));
+        }
+        Ok(cursor)
+    }
+}
+
+/// Requested `fields`, checked against [`LISTED_FIELDS`].
+fn parse_fields(fields: &str) -> AppResult<Vec<String>> {
+    let mut selected = vec!["id".to_string()];
+    for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        if !LISTED_FIELDS.contains(&field) {
+            return Err(AppError::BadRequest(format!(
+                "Unknown field {}; expected some of {}",
+                field,
+                LISTED_FIELDS.join(", ")
+            )));
+        }
+        if !selected.iter().any(|f| f == field) {
+            selected.push(field.to_string());
+        }
+    }
+    Ok(selected)
+}
+
+/// A listed note in the shape asked for.
+fn listed_note(
+    note: Note,
+    state: NoteState,
+    view: NoteView,
+    fields: Option<&[String]>,
+) -> AppResult<Value> {
+    let value = match (fields, view) {
+        (Some(fields), _) => {
+            let excerpt = fields
+                .iter()
+                .any(|f| f == "excerpt")
+                .then(|| utils::excerpt(&note.body, EXCERPT_CHARS));
+            let mut value = serde_json::to_value(ListedNote { note, state });
+            if let (Ok(Value::Object(map)), Some(excerpt)) = (&mut value, excerpt) {
+                map.insert("excerpt".into(), Value::String(excerpt));
+            }
+            value.map(|value| match value {
+                Value::Object(map) => Value::Object(
+                    map.into_iter()
+                        .filter(|(key, _)| fields.contains(key))
+                        .collect::<Map<_, _>>(),
+                ),
+                value => value,
+            })
+        }
+        (None, NoteView::Full) => serde_json::to_value(ListedNote { note, state }),
+        (None, NoteView::Summary) => serde_json::to_value(NoteSummary {
+            excerpt: utils::excerpt(&note.body, EXCERPT_CHARS),
+            id: note.id,
+            user_id: note.user_id,
+            workspace_id: note.workspace_id,
+            folder_id: note.folder_id,
+            title: note.title,
+            tags: note.tags.unwrap_or_default(),
+            revision: note.revision,
+            created_at: note.created_at,
+            updated_at: note.updated_at,
+            state,
+        }),
+    };
+    value.map_err(|e| {
+        tracing::error!("Serialize note error: {:?}", e);
+        AppError::InternalServerError
+    })
+}
+
+#[tracing::instrument(name = "db.create_note", skip_all, fields(user_id = %payload.user_id, caller_id = %user.id))]
 pub async fn create_note(
     Extension(pool): Extension<PgPool>,
+    user: AuthUser,
     Json(payload): Json<CreateNoteRequest>,
-) -> impl IntoResponse {
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesWrite)?;
+    if payload.user_id != user.id {
+        return Err(AppError::Forbidden(
+            "Notes can only be created for yourself".into(),
+        ));
+    }
+    // Guests only see what is shared with them, so they can only add notes to folders
+    // shared with them as editors
+    let destination = match (payload.workspace_id, payload.folder_id) {
+        (None, None) => None,
+        (workspace_id, folder_id) => {
+            Some(folders::require_destination(&pool, user.id, workspace_id, folder_id).await?)
+        }
+    };
     let note_id = Uuid::new_v4();
     let now = Utc::now();
 
     // sqlx bind for TEXT[] expects Option<&[String]> for a nullable array column
     let tags_opt: Option<&[String]> = Some(payload.tags.as_slice());
 
-    let res = sqlx::query_as!(
-        Note,
-        r#"
-        INSERT INTO notes (id, user_id, title, body, revision, tags, created_at, updated_at)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-        RETURNING id, user_id, title, body, revision, tags, created_at, updated_at
-        "#,
-        note_id,
-        payload.user_id,
-        payload.title,
-        payload.body,
-        1_i64,
-        tags_opt,
-        now,
-        now
+    // A first note without a workspace also creates the personal workspace
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let workspace_id = match destination {
+        Some(workspace_id) => workspace_id,
+        None => workspaces::personal_workspace(&mut tx, user.id).await?,
+    };
+    let mut note = monitoring::timed(
+        "create_note",
+        sqlx::query_as!(
+            Note,
+            r#"
+            INSERT INTO notes (id, user_id, workspace_id, folder_id, title, body, revision, tags, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING *
+            "#,
+            note_id,
+            payload.user_id,
+            workspace_id,
+            payload.folder_id,
+            payload.title,
+            payload.body,
+            1_i64,
+            tags_opt,
+            now,
+            now
+        )
+        .fetch_one(&mut *tx),
     )
-    .fetch_one(&pool)
-    .await;
-
-    match res {
-        Ok(mut note) => {
-            // Model uses Option<Vec<String>>; normalize to Some(vec) for consistent API shape
-            note.tags = Some(note.tags.unwrap_or_default());
-            (StatusCode::CREATED, AxumJson(note)).into_response()
-        }
-        Err(e) => {
-            tracing::error!("Create note error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(json!({"error":"Failed to create note"})),
-            )
-                .into_response()
-        }
-    }
+    .await
+    .map_err(|e| {
+        tracing::error!("Create note error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    // Model uses Option<Vec<String>>; normalize to Some(vec) for consistent API shape
+    note.tags = Some(note.tags.unwrap_or_default());
+    Ok((StatusCode::CREATED, AxumJson(note)))
 }
 
+/// GET /api/users/{user_id}/notes: a page of the caller's notes, pinned ones first, then
+/// in `sort` order. Archived notes are left out unless `archived=include` or `only`.
+/// Pages are keyset-paginated: `next_cursor` fetches the page after this one.
+#[tracing::instrument(name = "db.list_notes", skip(pool, user, query), fields(caller_id = %user.id))]
 pub async fn list_notes(
     Extension(pool): Extension<PgPool>,
+    user: AuthUser,
     Path(user_id): Path<Uuid>,
-) -> impl IntoResponse {
-    let res = sqlx::query_as!(
-        Note,
-        "SELECT * FROM notes WHERE user_id = $1 ORDER BY updated_at DESC",
-        user_id
+    Query(query): Query<ListNotesQuery>,
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesRead)?;
+    if user_id != user.id {
+        return Err(AppError::Forbidden(
+            "You can only list your own notes".into(),
+        ));
+    }
+    let archived = match query.archived {
+        ArchivedFilter::Exclude => Some(false),
+        ArchivedFilter::Include => None,
+        ArchivedFilter::Only => Some(true),
+    };
+    let fields = query.fields.as_deref().map(parse_fields).transpose()?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|cursor| NoteCursor::decode(cursor, query.sort))
+        .transpose()?;
+    // Whole bodies only when they are returned; an excerpt only needs the start
+    let body_chars = match (&fields, query.view) {
+        (Some(fields), _) if fields.iter().any(|f| f == "body") => None,
+        (Some(fields), _) if fields.iter().any(|f| f == "excerpt") => Some(EXCERPT_SOURCE_CHARS),
+        (Some(_), _) => Some(0),
+        (None, NoteView::Full) => None,
+        (None, NoteView::Summary) => Some(EXCERPT_SOURCE_CHARS),
+    };
+
+    // Notes of the user's workspaces (unless a guest there), notes shared with them, and
+    // notes in folders shared with them, with the user's flags on each. One more than a
+    // page tells whether there's more.
+    let mut rows = monitoring::timed(
+        "list_notes",
+        sqlx::query!(
+            r#"
+            WITH RECURSIVE shared_folders AS (
+                SELECT folder_id AS id FROM folder_permissions
+                WHERE user_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+                UNION
+                SELECT f.id FROM folders f JOIN shared_folders s ON f.parent_id = s.id
+            )
+            SELECT n.id, n.user_id, n.workspace_id, n.folder_id, n.title,
+                   CASE WHEN $11::int IS NULL THEN n.body ELSE LEFT(n.body, $11) END AS "body!",
+                   n.revision, n.tags, n.created_at, n.updated_at, n.deleted_at, n.deleted_by,
+                   st.pinned_at IS NOT NULL AS "pinned!", st.favorited_at IS NOT NULL AS "favorite!",
+                   st.archived_at IS NOT NULL AS "archived!"
+            FROM notes n
+            LEFT JOIN note_user_states st ON st.note_id = n.id AND st.user_id = $1
+            WHERE n.deleted_at IS NULL AND (n.folder_id IN (SELECT id FROM shared_folders) OR EXISTS (
+                SELECT 1 FROM workspace_members m
+                WHERE m.workspace_id = n.workspace_id AND m.user_id = $1 AND m.role <> 'guest'
+            ) OR EXISTS (
+                SELECT 1 FROM note_permissions p
+                WHERE p.note_id = n.id AND p.user_id = $1
+                  AND (p.expires_at IS NULL OR p.expires_at > NOW())
+            ))
+              AND ($2::bool IS NULL OR (st.pinned_at IS NOT NULL) = $2)
+              AND ($3::bool IS NULL OR (st.favorited_at IS NOT NULL) = $3)
+              AND ($4::bool IS NULL OR (st.archived_at IS NOT NULL) = $4)
+              AND ($6::bool IS NULL OR (st.pinned_at IS NULL) > NOT $6
+                   OR ((st.pinned_at IS NULL) = NOT $6 AND CASE $5::text
+                       WHEN 'created' THEN (n.created_at, n.id) < ($7::timestamptz, $9::uuid)
+                       WHEN 'title' THEN (n.title, n.id) > ($8::text, $9::uuid)
+                       ELSE (n.updated_at, n.id) < ($7::timestamptz, $9::uuid)
+                   END))
+            ORDER BY st.pinned_at IS NULL,
+                     CASE WHEN $5 = 'updated' THEN n.updated_at END DESC,
+                     CASE WHEN $5 = 'created' THEN n.created_at END DESC,
+                     CASE WHEN $5 = 'title' THEN n.title END ASC,
+                     CASE WHEN $5 = 'title' THEN n.id END ASC,
+                     n.id DESC
+            LIMIT $10
+            "#,
+            user_id,
+            query.pinned,
+            query.favorite,
+            archived,
+            query.sort.as_str(),
+            cursor.as_ref().map(|c| c.pinned),
+            cursor.as_ref().and_then(|c| c.at),
+            cursor.as_ref().and_then(|c| c.title.clone()),
+            cursor.as_ref().map(|c| c.id),
+            limit + 1,
+            body_chars
+        )
+        .fetch_all(&pool),
     )
-    .fetch_all(&pool)
-    .await;
+    .await
+    .map_err(|e| {
+        tracing::error!("List notes error: {:?}", e);
+        AppError::InternalServerError
+    })?;
 
-    match res {
-        Ok(mut notes) => {
-            for note in &mut notes {
-                note.tags = Some(note.tags.clone().unwrap_or_default());
-            }
-            (StatusCode::OK, AxumJson(notes)).into_response()
-        }
-        Err(e) => {
-            tracing::error!("List notes error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(json!({"error":"Failed to list notes"})),
+    let more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    let notes: Vec<(Note, NoteState)> = rows
+        .into_iter()
+        .map(|row| {
+            let note = Note {
+                id: row.id,
+                user_id: row.user_id,
+                workspace_id: row.workspace_id,
+                folder_id: row.folder_id,
+                title: row.title,
+                body: row.body,
+                revision: row.revision,
+                tags: Some(row.tags.unwrap_or_default()),
+                created_at: row.created_at,
+                updated_at: row.updated_at,
+                deleted_at: row.deleted_at,
+                deleted_by: row.deleted_by,
+            };
+            let state = NoteState {
+                pinned: row.pinned,
+                favorite: row.favorite,
+                archived: row.archived,
+            };
+            (note, state)
+        })
+        .collect();
+    let next_cursor = notes
+        .last()
+        .filter(|_| more)
+        .map(|(note, state)| NoteCursor::after(note, state.pinned, query.sort).encode());
+    let notes = notes
+        .into_iter()
+        .map(|(note, state)| listed_note(note, state, query.view, fields.as_deref()))
+        .collect::<AppResult<Vec<_>>>()?;
+    Ok((
+        StatusCode::OK,
+        AxumJson(json!({ "notes": notes, "next_cursor": next_cursor })),
+    ))
+}
+
+/// GET /api/workspaces/{workspace_id}/notes: the workspace's notes the caller can see;
+/// for guests, only those shared with them directly or through a folder.
+#[tracing::instrument(name = "db.list_workspace_notes", skip(pool, user), fields(caller_id = %user.id))]
+pub async fn list_workspace_notes(
+    Extension(pool): Extension<PgPool>,
+    user: AuthUser,
+    Path(workspace_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesRead)?;
+    let role =
+        workspaces::require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Guest)
+            .await?;
+    let mut notes = monitoring::timed(
+        "list_workspace_notes",
+        sqlx::query_as!(
+            Note,
+            r#"
+            WITH RECURSIVE shared_folders AS (
+                SELECT folder_id AS id FROM folder_permissions
+                WHERE user_id = $2 AND (expires_at IS NULL OR expires_at > NOW())
+                UNION
+                SELECT f.id FROM folders f JOIN shared_folders s ON f.parent_id = s.id
             )
-                .into_response()
-        }
+            SELECT n.* FROM notes n
+            WHERE n.workspace_id = $1 AND n.deleted_at IS NULL AND ($3 OR n.folder_id IN (SELECT id FROM shared_folders) OR EXISTS (
+                SELECT 1 FROM note_permissions p
+                WHERE p.note_id = n.id AND p.user_id = $2
+                  AND (p.expires_at IS NULL OR p.expires_at > NOW())
+            ))
+            ORDER BY n.updated_at DESC
+            "#,
+            workspace_id,
+            user.id,
+            role.note_role().is_some()
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List workspace notes error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    for note in &mut notes {
+        note.tags = Some(note.tags.clone().unwrap_or_default());
     }
+    Ok((StatusCode::OK, AxumJson(notes)))
 }
 
+#[tracing::instrument(name = "db.get_note", skip_all, fields(%note_id, caller_id = %user.id))]
 pub async fn get_note(
     Extension(pool): Extension<PgPool>,
+    user: AuthUser,
     Path(note_id): Path<Uuid>,
-) -> impl IntoResponse {
-    let res = sqlx::query_as!(Note, "SELECT * FROM notes WHERE id = $1", note_id)
-        .fetch_optional(&pool)
-        .await;
-
-    match res {
-        Ok(Some(mut note)) => {
-            note.tags = Some(note.tags.unwrap_or_default());
-            (StatusCode::OK, AxumJson(note)).into_response()
-        }
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            AxumJson(json!({"error":"Note not found"})),
-        )
-            .into_response(),
-        Err(e) => {
-            tracing::error!("Get note error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(json!({"error":"Failed to get note"})),
-            )
-                .into_response()
-        }
-    }
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesRead)?;
+    permissions::require_role(&pool, note_id, user.id, Role::Viewer).await?;
+    let mut note = monitoring::timed(
+        "get_note",
+        sqlx::query_as!(Note, "SELECT * FROM notes WHERE id = $1", note_id).fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get note error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    note.tags = Some(note.tags.unwrap_or_default());
+    Ok((StatusCode::OK, AxumJson(note)))
 }
 
+#[tracing::instrument(name = "db.update_note", skip_all, fields(%note_id, caller_id = %user.id))]
 pub async fn update_note(
     Extension(pool): Extension<PgPool>,
+    user: AuthUser,
     Path(note_id): Path<Uuid>,
     Json(payload): Json<UpdateNoteRequest>,
-) -> impl IntoResponse {
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesWrite)?;
+    permissions::require_role(&pool, note_id, user.id, Role::Editor).await?;
     // Fetch existing
-    let existing = sqlx::query_as!(Note, "SELECT * FROM notes WHERE id = $1", note_id)
-        .fetch_optional(&pool)
-        .await;
-
-    let mut note = match existing {
-        Ok(Some(note)) => note,
-        Ok(None) => {
-            return (
-                StatusCode::NOT_FOUND,
-                AxumJson(json!({"error":"Note not found"})),
-            )
-                .into_response()
-        }
-        Err(e) => {
-            tracing::error!("Update note retrieval error: {:?}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(json!({"error":"Error retrieving note"})),
-            )
-                .into_response();
-        }
-    };
+    let mut note = monitoring::timed(
+        "get_note",
+        sqlx::query_as!(Note, "SELECT * FROM notes WHERE id = $1", note_id).fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update note retrieval error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
 
     // Apply updates
     if let Some(title) = payload.title {
@@ -175,58 +588,150 @@ pub async fn update_note(
     note.updated_at = Utc::now();
 
     // Bind tags as Option<&[String]> for TEXT[] update
-    let tags_bind: Option<&[String]> = note.tags.as_ref().map(|v| v.as_slice());
-
-    let res = sqlx::query!(
-        r#"
-        UPDATE notes
-        SET title = $1, body = $2, revision = $3, tags = $4, updated_at = $5
-        WHERE id = $6
-        "#,
-        note.title,
-        note.body,
-        note.revision,
-        tags_bind,
-        note.updated_at,
-        note.id
-    )
-    .execute(&pool)
-    .await;
-
-    match res {
-        Ok(_) => {
-            // Ensure API returns Some(vec) consistently
-            note.tags = Some(note.tags.unwrap_or_default());
-            (StatusCode::OK, AxumJson(note)).into_response()
-        }
-        Err(e) => {
-            tracing::error!("Update note DB error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(json!({"error":"Failed to update note"})),
+    let tags_bind: Option<&[String]> = note.tags.as_deref();
+
+    // Keep the body being replaced as a revision, in the same statement
+    monitoring::timed(
+        "update_note",
+        sqlx::query!(
+            r#"
+            WITH previous AS (
+                INSERT INTO revisions (id, note_id, revision_number, body)
+                SELECT $7, id, revision, body FROM notes WHERE id = $6 AND body <> $2
+                ON CONFLICT (note_id, revision_number) DO NOTHING
             )
-                .into_response()
-        }
-    }
+            UPDATE notes
+            SET title = $1, body = $2, revision = $3, tags = $4, updated_at = $5
+            WHERE id = $6
+            "#,
+            note.title,
+            note.body,
+            note.revision,
+            tags_bind,
+            note.updated_at,
+            note.id,
+            Uuid::new_v4()
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update note DB error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    // Ensure API returns Some(vec) consistently
+    note.tags = Some(note.tags.unwrap_or_default());
+    Ok((StatusCode::OK, AxumJson(note)))
 }
 
+/// GET /api/notes/{note_id}/revisions: earlier bodies of the note, newest first. Each is
+/// kept when an update replaces it.
+#[tracing::instrument(name = "db.list_revisions", skip_all, fields(%note_id, caller_id = %user.id))]
+pub async fn list_revisions(
+    Extension(pool): Extension<PgPool>,
+    user: AuthUser,
+    Path(note_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::RevisionsRead)?;
+    permissions::require_role(&pool, note_id, user.id, Role::Viewer).await?;
+    let revisions = monitoring::timed(
+        "list_revisions",
+        sqlx::query_as!(
+            Revision,
+            "SELECT * FROM revisions WHERE note_id = $1 ORDER BY revision_number DESC",
+            note_id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List revisions error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(revisions)))
+}
+
+/// DELETE /api/notes/{note_id}: move a note to the trash, from where its owners can restore
+/// it until it is purged (see [`crate::trash`]).
+#[tracing::instrument(name = "db.delete_note", skip_all, fields(%note_id, caller_id = %user.id))]
 pub async fn delete_note(
     Extension(pool): Extension<PgPool>,
+    user: AuthUser,
     Path(note_id): Path<Uuid>,
-) -> impl IntoResponse {
-    let res = sqlx::query!("DELETE FROM notes WHERE id = $1", note_id)
-        .execute(&pool)
-        .await;
-
-    match res {
-        Ok(_) => (StatusCode::NO_CONTENT, AxumJson(json!({}))).into_response(),
-        Err(e) => {
-            tracing::error!("Delete note error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                AxumJson(json!({"error":"Failed to delete note"})),
-            )
-                .into_response()
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesWrite)?;
+    permissions::require_role(&pool, note_id, user.id, Role::Owner).await?;
+    monitoring::timed(
+        "trash_note",
+        sqlx::query!(
+            "UPDATE notes SET deleted_at = NOW(), deleted_by = $2 WHERE id = $1 AND deleted_at IS NULL",
+            note_id,
+            user.id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete note error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::NO_CONTENT, AxumJson(json!({}))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn note(title: &str) -> Note {
+        Note {
+            id: Uuid::new_v4(),
+            user_id: None,
+            workspace_id: Uuid::new_v4(),
+            folder_id: None,
+            title: title.to_string(),
+            body: String::new(),
+            revision: 1,
+            tags: None,
+            created_at: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            updated_at: Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap(),
+            deleted_at: None,
+            deleted_by: None,
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip_with_the_sort_key() {
+        let note = note("Groceries");
+
+        let cursor = NoteCursor::after(&note, true, NoteSort::Updated).encode();
+        let decoded = NoteCursor::decode(&cursor, NoteSort::Updated).unwrap();
+        assert!(decoded.pinned);
+        assert_eq!(decoded.at, Some(note.updated_at));
+        assert_eq!(decoded.title, None);
+        assert_eq!(decoded.id, note.id);
+
+        let cursor = NoteCursor::after(&note, false, NoteSort::Title).encode();
+        let decoded = NoteCursor::decode(&cursor, NoteSort::Title).unwrap();
+        assert_eq!(decoded.at, None);
+        assert_eq!(decoded.title.as_deref(), Some("Groceries"));
+    }
+
+    #[test]
+    fn cursors_for_another_sort_or_garbage_are_rejected() {
+        let cursor = NoteCursor::after(&note("a"), false, NoteSort::Created).encode();
+        assert!(matches!(
+            NoteCursor::decode(&cursor, NoteSort::Updated),
+            Err(AppError::BadRequest(_))
+        ));
+        for garbage in ["", "not a cursor", &URL_SAFE_NO_PAD.encode(b"{\"id\":1}")] {
+            assert!(matches!(
+                NoteCursor::decode(garbage, NoteSort::Updated),
+                Err(AppError::BadRequest(_))
+            ));
         }
     }
 }
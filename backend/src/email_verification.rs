@@ -0,0 +1,198 @@
+use crate::{
+    auth::SessionUser,
+    errors::{AppError, AppResult},
+    mailer::{self, Email, SharedMailer},
+    monitoring, utils,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+/// Trim and sanity-check an email address. Deliverability is only proven by verification.
+pub fn normalize_email(raw: &str) -> AppResult<String> {
+    let email = raw.trim();
+    let valid = email.len() <= 254
+        && !email.chars().any(char::is_whitespace)
+        && email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        });
+    if !valid {
+        return Err(AppError::BadRequest("Invalid email address".into()));
+    }
+    Ok(email.to_string())
+}
+
+/// Email a one-time verification link for `email` (`EMAIL_VERIFICATION_TTL_SECS`,
+/// default 24 hours). Earlier links for the user stop working.
+pub async fn send_verification(
+    pool: &PgPool,
+    mailer: SharedMailer,
+    user_id: Uuid,
+    email: &str,
+) -> AppResult<()> {
+    monitoring::timed(
+        "expire_email_verification_tokens",
+        sqlx::query!(
+            "UPDATE email_verification_tokens SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Expire email verification tokens error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let ttl: i64 = utils::env_or("EMAIL_VERIFICATION_TTL_SECS", 24 * 60 * 60);
+    let token = utils::generate_token();
+    monitoring::timed(
+        "insert_email_verification_token",
+        sqlx::query!(
+            r#"
+            INSERT INTO email_verification_tokens (id, user_id, email, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            email,
+            utils::hash_token(&token),
+            Utc::now() + chrono::Duration::seconds(ttl)
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert email verification token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let link = format!("{}/verify-email?token={}", utils::app_base_url(), token);
+    mailer::send_in_background(
+        mailer,
+        Email {
+            to: email.to_string(),
+            subject: "Verify your Noteflow email address".to_string(),
+            body: format!(
+                "Confirm that this address belongs to your Noteflow account:\n\
+                 {}\n\n\
+                 The link expires in {} hours. If you didn't sign up, ignore this email.\n",
+                link,
+                ttl / 3600
+            ),
+        },
+    );
+    Ok(())
+}
+
+/// POST /api/email/verify: mark the address a verification token was sent to as verified.
+#[tracing::instrument(name = "email.verify", skip_all)]
+pub async fn verify_email(
+    Extension(pool): Extension<PgPool>,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> AppResult<impl IntoResponse> {
+    let claimed = monitoring::timed(
+        "claim_email_verification_token",
+        sqlx::query!(
+            r#"
+            UPDATE email_verification_tokens SET used_at = NOW()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id, email
+            "#,
+            utils::hash_token(&payload.token)
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Claim email verification token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let invalid = || AppError::BadRequest("Invalid or expired verification token".into());
+    let Some(claimed) = claimed else {
+        return Err(invalid());
+    };
+
+    // The address may have changed since the link was sent
+    let verified = monitoring::timed(
+        "mark_email_verified",
+        sqlx::query!(
+            r#"
+            UPDATE users SET email_verified_at = NOW()
+            WHERE id = $1 AND LOWER(email) = LOWER($2)
+            "#,
+            claimed.user_id,
+            claimed.email
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Mark email verified error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    if !verified {
+        return Err(invalid());
+    }
+    monitoring::record_auth("verify_email", "success");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/users/me/email/verification: send a new verification link to the caller's
+/// current address.
+#[tracing::instrument(name = "email.resend_verification", skip_all, fields(user_id = %user.id))]
+pub async fn resend_verification(
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<SharedMailer>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    let row = monitoring::timed(
+        "get_user_email",
+        sqlx::query!(
+            "SELECT email, email_verified_at FROM users WHERE id = $1",
+            user.id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get user email error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    let Some(email) = row.email else {
+        return Err(AppError::BadRequest(
+            "No email address on this account".into(),
+        ));
+    };
+    if row.email_verified_at.is_some() {
+        return Err(AppError::Conflict("Email address already verified".into()));
+    }
+
+    send_verification(&pool, mailer, user.id, &email).await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        AxumJson(serde_json::json!({ "status": "ok" })),
+    ))
+}
@@ -1,17 +1,25 @@
-use axum::http::StatusCode;
+use crate::telemetry;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use serde::Serialize;
-use serde_json::json;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
     #[error("Not Found")]
     NotFound,
     #[error("Bad Request: {0}")]
     BadRequest(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Too Many Requests")]
+    TooManyRequests(u64),
     #[error("Internal Server Error")]
     InternalServerError,
 }
@@ -19,6 +27,9 @@ pub enum AppError {
 #[derive(Serialize)]
 struct ErrorBody {
     error: String,
+    /// Correlation ID of the failing request, matching the `X-Request-Id` response header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 pub type AppResult<T> = Result<T, AppError>;
@@ -27,12 +38,25 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, message) = match &self {
             AppError::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()),
+            AppError::InvalidCredentials => (StatusCode::UNAUTHORIZED, self.to_string()),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg.clone()),
             AppError::NotFound => (StatusCode::NOT_FOUND, self.to_string()),
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
+            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg.clone()),
+            AppError::TooManyRequests(_) => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
             AppError::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
         };
 
-        let body = json!({ "error": message });
-        (status, axum::Json(body)).into_response()
+        let body = ErrorBody {
+            error: message,
+            request_id: telemetry::current_request_id(),
+        };
+        let mut response = (status, axum::Json(body)).into_response();
+        if let AppError::TooManyRequests(retry_after) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
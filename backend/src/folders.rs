@@ -0,0 +1,900 @@
+use crate::{
+    auth::SessionUser,
+    errors::{AppError, AppResult},
+    mailer::SharedMailer,
+    models::{Folder, Note, NotePermission},
+    monitoring,
+    permissions::{self, Role, ShareRequest, ShareResponse},
+    utils,
+    workspaces::{self, WorkspaceRole},
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+/// Longest folder name, in characters.
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(Deserialize)]
+pub struct CreateFolderRequest {
+    pub name: String,
+    /// Enclosing folder; top level when omitted
+    pub parent_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct RenameFolderRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct MoveFolderRequest {
+    /// New enclosing folder; `null` for the top level
+    pub parent_id: Option<Uuid>,
+    /// Order among the new siblings; after them when omitted
+    pub position: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct MoveNoteRequest {
+    /// Folder to move the note to; `null` for the root of its workspace
+    pub folder_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+struct FolderContents {
+    folder: Folder,
+    /// Direct subfolders, in order
+    folders: Vec<Folder>,
+    /// Notes directly in the folder, most recently updated first
+    notes: Vec<Note>,
+}
+
+fn validate_name(name: &str) -> AppResult<String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "Folder name must be 1 to {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// The user's role on a folder: the higher of what their workspace role gives and the
+/// best unexpired grant on the folder or any folder above it.
+pub async fn folder_role(pool: &PgPool, folder_id: Uuid, user_id: Uuid) -> AppResult<Option<Role>> {
+    let roles = monitoring::timed(
+        "get_folder_role",
+        sqlx::query!(
+            r#"
+            WITH RECURSIVE chain AS (
+                SELECT id, parent_id FROM folders WHERE id = $1 AND deleted_at IS NULL
+                UNION ALL
+                SELECT f.id, f.parent_id FROM folders f JOIN chain c ON f.id = c.parent_id
+            )
+            SELECT
+                (SELECT m.role FROM folders f
+                 JOIN workspace_members m ON m.workspace_id = f.workspace_id
+                 WHERE f.id = $1 AND f.deleted_at IS NULL AND m.user_id = $2) AS workspace_role,
+                (SELECT p.role FROM folder_permissions p JOIN chain c ON c.id = p.folder_id
+                 WHERE p.user_id = $2 AND (p.expires_at IS NULL OR p.expires_at > NOW())
+                 ORDER BY CASE p.role WHEN 'owner' THEN 0 WHEN 'editor' THEN 1 ELSE 2 END
+                 LIMIT 1) AS folder_role
+            "#,
+            folder_id,
+            user_id
+        )
+        .fetch_one(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get folder role error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let granted = roles.folder_role.as_deref().and_then(Role::parse);
+    let inherited = roles
+        .workspace_role
+        .as_deref()
+        .and_then(WorkspaceRole::parse)
+        .and_then(WorkspaceRole::note_role);
+    Ok(granted.max(inherited))
+}
+
+/// Require at least `min` on a folder; 404 without any access, like for notes.
+pub async fn require_folder_role(
+    pool: &PgPool,
+    folder_id: Uuid,
+    user_id: Uuid,
+    min: Role,
+) -> AppResult<Role> {
+    match folder_role(pool, folder_id, user_id).await? {
+        None => Err(AppError::NotFound),
+        Some(role) if role < min => Err(AppError::Forbidden(format!(
+            "This requires {} access to the folder",
+            min.as_str()
+        ))),
+        Some(role) => Ok(role),
+    }
+}
+
+async fn fetch_folder(pool: &PgPool, folder_id: Uuid) -> AppResult<Folder> {
+    monitoring::timed(
+        "get_folder",
+        sqlx::query_as!(
+            Folder,
+            "SELECT * FROM folders WHERE id = $1 AND deleted_at IS NULL",
+            folder_id
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get folder error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)
+}
+
+/// Check the user may add things to `folder_id` (editor access), or to the root of the
+/// workspace (member access) when it is `None`. Returns the destination's workspace.
+pub async fn require_destination(
+    pool: &PgPool,
+    user_id: Uuid,
+    workspace_id: Option<Uuid>,
+    folder_id: Option<Uuid>,
+) -> AppResult<Uuid> {
+    match (folder_id, workspace_id) {
+        (Some(folder_id), workspace_id) => {
+            require_folder_role(pool, folder_id, user_id, Role::Editor).await?;
+            let folder = fetch_folder(pool, folder_id).await?;
+            if workspace_id.is_some_and(|id| id != folder.workspace_id) {
+                return Err(AppError::BadRequest(
+                    "The folder belongs to another workspace".into(),
+                ));
+            }
+            Ok(folder.workspace_id)
+        }
+        (None, Some(workspace_id)) => {
+            workspaces::require_workspace_role(pool, workspace_id, user_id, WorkspaceRole::Member)
+                .await?;
+            Ok(workspace_id)
+        }
+        (None, None) => Err(AppError::BadRequest("No destination given".into())),
+    }
+}
+
+/// The best unexpired grant of each account on a folder or any folder above it.
+async fn chain_grants(pool: &PgPool, folder_id: Uuid) -> AppResult<Vec<(Uuid, Role)>> {
+    let rows = monitoring::timed(
+        "list_folder_chain_grants",
+        sqlx::query!(
+            r#"
+            WITH RECURSIVE chain AS (
+                SELECT id, parent_id FROM folders WHERE id = $1
+                UNION ALL
+                SELECT f.id, f.parent_id FROM folders f JOIN chain c ON f.id = c.parent_id
+            )
+            SELECT DISTINCT ON (p.user_id) p.user_id, p.role
+            FROM folder_permissions p JOIN chain c ON c.id = p.folder_id
+            WHERE p.expires_at IS NULL OR p.expires_at > NOW()
+            ORDER BY p.user_id, CASE p.role WHEN 'owner' THEN 0 WHEN 'editor' THEN 1 ELSE 2 END
+            "#,
+            folder_id
+        )
+        .fetch_all(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List folder chain grants error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Some((row.user_id, Role::parse(&row.role)?)))
+        .collect())
+}
+
+/// Moving something under `folder_id` passes on the grants there. Without owner access,
+/// refuse when that would give anyone, the caller included, more than `current` gives them.
+async fn require_no_escalation<F, Fut>(
+    pool: &PgPool,
+    caller_role: Role,
+    folder_id: Option<Uuid>,
+    current: F,
+) -> AppResult<()>
+where
+    F: Fn(Uuid) -> Fut,
+    Fut: std::future::Future<Output = AppResult<Option<Role>>>,
+{
+    let Some(folder_id) = folder_id else {
+        return Ok(());
+    };
+    if caller_role == Role::Owner {
+        return Ok(());
+    }
+    for (user_id, granted) in chain_grants(pool, folder_id).await? {
+        if current(user_id).await? < Some(granted) {
+            return Err(AppError::Forbidden(
+                "Moving it there would give others more access, which requires owner access".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Serialize changes to a workspace's tree, so two concurrent moves can't form a cycle.
+async fn lock_workspace(conn: &mut PgConnection, workspace_id: Uuid) -> AppResult<()> {
+    monitoring::timed(
+        "lock_workspace_tree",
+        sqlx::query_scalar!(
+            "SELECT id FROM workspaces WHERE id = $1 FOR UPDATE",
+            workspace_id
+        )
+        .fetch_optional(conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Lock workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    Ok(())
+}
+
+/// Position after the last folder under `parent_id`.
+async fn next_position(
+    conn: &mut PgConnection,
+    workspace_id: Uuid,
+    parent_id: Option<Uuid>,
+) -> AppResult<i32> {
+    monitoring::timed(
+        "next_folder_position",
+        sqlx::query_scalar!(
+            r#"
+            SELECT COALESCE(MAX(position) + 1, 0) AS "position!" FROM folders
+            WHERE workspace_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND deleted_at IS NULL
+            "#,
+            workspace_id,
+            parent_id
+        )
+        .fetch_one(conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Next folder position error: {:?}", e);
+        AppError::InternalServerError
+    })
+}
+
+async fn fetch_folder_permissions(
+    pool: &PgPool,
+    folder_id: Uuid,
+) -> AppResult<Vec<NotePermission>> {
+    monitoring::timed(
+        "list_folder_permissions",
+        sqlx::query_as!(
+            NotePermission,
+            r#"
+            SELECT p.user_id, u.username, u.display_name, p.role, p.expires_at, p.granted_by,
+                   p.created_at
+            FROM folder_permissions p JOIN users u ON u.id = p.user_id
+            WHERE p.folder_id = $1 AND (p.expires_at IS NULL OR p.expires_at > NOW())
+            ORDER BY CASE p.role WHEN 'owner' THEN 0 WHEN 'editor' THEN 1 ELSE 2 END, p.created_at
+            "#,
+            folder_id
+        )
+        .fetch_all(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List folder permissions error: {:?}", e);
+        AppError::InternalServerError
+    })
+}
+
+/// POST /api/workspaces/{workspace_id}/folders: create a folder at the top level or in
+/// `parent_id`, after its siblings. The creator's access follows their workspace role.
+#[tracing::instrument(name = "folders.create", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn create_folder(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+    Json(payload): Json<CreateFolderRequest>,
+) -> AppResult<impl IntoResponse> {
+    let name = validate_name(&payload.name)?;
+    require_destination(&pool, user.id, Some(workspace_id), payload.parent_id).await?;
+    let folder_id = Uuid::new_v4();
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    lock_workspace(&mut tx, workspace_id).await?;
+    let position = next_position(&mut tx, workspace_id, payload.parent_id).await?;
+    let folder = monitoring::timed(
+        "insert_folder",
+        sqlx::query_as!(
+            Folder,
+            r#"
+            INSERT INTO folders (id, workspace_id, parent_id, name, position, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#,
+            folder_id,
+            workspace_id,
+            payload.parent_id,
+            name,
+            position,
+            user.id
+        )
+        .fetch_one(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert folder error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%workspace_id, %folder_id, "Folder created");
+    Ok((StatusCode::CREATED, AxumJson(folder)))
+}
+
+/// GET /api/workspaces/{workspace_id}/folders: the workspace's folder tree as a flat list,
+/// parents before children. Guests only get the folders shared with them and what's below.
+#[tracing::instrument(name = "folders.list", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn list_folders(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let role =
+        workspaces::require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Guest)
+            .await?;
+    let folders = monitoring::timed(
+        "list_folders",
+        sqlx::query_as!(
+            Folder,
+            r#"
+            WITH RECURSIVE tree AS (
+                SELECT f.id, f.parent_id, 0 AS depth, $3 OR EXISTS (
+                    SELECT 1 FROM folder_permissions p
+                    WHERE p.folder_id = f.id AND p.user_id = $2
+                      AND (p.expires_at IS NULL OR p.expires_at > NOW())
+                ) AS visible
+                FROM folders f
+                WHERE f.workspace_id = $1 AND f.parent_id IS NULL AND f.deleted_at IS NULL
+                UNION ALL
+                SELECT f.id, f.parent_id, t.depth + 1, t.visible OR EXISTS (
+                    SELECT 1 FROM folder_permissions p
+                    WHERE p.folder_id = f.id AND p.user_id = $2
+                      AND (p.expires_at IS NULL OR p.expires_at > NOW())
+                )
+                FROM folders f JOIN tree t ON f.parent_id = t.id
+                WHERE f.deleted_at IS NULL
+            )
+            SELECT f.* FROM folders f JOIN tree t ON t.id = f.id
+            WHERE t.visible
+            ORDER BY t.depth, f.position, LOWER(f.name)
+            "#,
+            workspace_id,
+            user.id,
+            role.note_role().is_some()
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List folders error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(folders)))
+}
+
+/// GET /api/folders/{folder_id}: a folder with its subfolders and notes.
+#[tracing::instrument(name = "folders.get", skip_all, fields(%folder_id, user_id = %user.id))]
+pub async fn get_folder(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(folder_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    require_folder_role(&pool, folder_id, user.id, Role::Viewer).await?;
+    let folder = fetch_folder(&pool, folder_id).await?;
+    let folders = monitoring::timed(
+        "list_subfolders",
+        sqlx::query_as!(
+            Folder,
+            r#"
+            SELECT * FROM folders WHERE parent_id = $1 AND deleted_at IS NULL
+            ORDER BY position, LOWER(name)
+            "#,
+            folder_id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List subfolders error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let mut notes = monitoring::timed(
+        "list_folder_notes",
+        sqlx::query_as!(
+            Note,
+            "SELECT * FROM notes WHERE folder_id = $1 AND deleted_at IS NULL ORDER BY updated_at DESC",
+            folder_id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List folder notes error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    for note in &mut notes {
+        note.tags = Some(note.tags.clone().unwrap_or_default());
+    }
+    Ok((
+        StatusCode::OK,
+        AxumJson(FolderContents {
+            folder,
+            folders,
+            notes,
+        }),
+    ))
+}
+
+/// PUT /api/folders/{folder_id}: rename a folder. Needs editor access.
+#[tracing::instrument(name = "folders.rename", skip_all, fields(%folder_id, user_id = %user.id))]
+pub async fn rename_folder(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(folder_id): Path<Uuid>,
+    Json(payload): Json<RenameFolderRequest>,
+) -> AppResult<impl IntoResponse> {
+    require_folder_role(&pool, folder_id, user.id, Role::Editor).await?;
+    let name = validate_name(&payload.name)?;
+    let folder = monitoring::timed(
+        "rename_folder",
+        sqlx::query_as!(
+            Folder,
+            "UPDATE folders SET name = $2, updated_at = NOW() WHERE id = $1 RETURNING *",
+            folder_id,
+            name
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Rename folder error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    Ok((StatusCode::OK, AxumJson(folder)))
+}
+
+/// POST /api/folders/{folder_id}/move: move a folder, with everything in it, under another
+/// folder of the same workspace or to the top level, or reorder it among its siblings.
+/// Editors can only move it where nobody gains access to it.
+#[tracing::instrument(name = "folders.move", skip_all, fields(%folder_id, user_id = %user.id))]
+pub async fn move_folder(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(folder_id): Path<Uuid>,
+    Json(payload): Json<MoveFolderRequest>,
+) -> AppResult<impl IntoResponse> {
+    let role = require_folder_role(&pool, folder_id, user.id, Role::Editor).await?;
+    let folder = fetch_folder(&pool, folder_id).await?;
+    require_destination(&pool, user.id, Some(folder.workspace_id), payload.parent_id).await?;
+    require_no_escalation(&pool, role, payload.parent_id, |user_id| {
+        folder_role(&pool, folder_id, user_id)
+    })
+    .await?;
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    lock_workspace(&mut tx, folder.workspace_id).await?;
+    if let Some(parent_id) = payload.parent_id {
+        // Walk up from the new parent: meeting the folder itself means a cycle
+        let cycle = monitoring::timed(
+            "check_folder_cycle",
+            sqlx::query_scalar!(
+                r#"
+                WITH RECURSIVE up AS (
+                    SELECT id, parent_id FROM folders WHERE id = $2
+                    UNION ALL
+                    SELECT f.id, f.parent_id FROM folders f JOIN up ON f.id = up.parent_id
+                )
+                SELECT EXISTS(SELECT 1 FROM up WHERE id = $1) AS "exists!"
+                "#,
+                folder_id,
+                parent_id
+            )
+            .fetch_one(&mut *tx),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Check folder cycle error: {:?}", e);
+            AppError::InternalServerError
+        })?;
+        if cycle {
+            return Err(AppError::Conflict(
+                "A folder can't be moved into itself or one of its subfolders".into(),
+            ));
+        }
+    }
+    let position = match payload.position {
+        Some(position) => position,
+        None if payload.parent_id == folder.parent_id => folder.position,
+        None => next_position(&mut tx, folder.workspace_id, payload.parent_id).await?,
+    };
+    let folder = monitoring::timed(
+        "move_folder",
+        sqlx::query_as!(
+            Folder,
+            r#"
+            UPDATE folders SET parent_id = $2, position = $3, updated_at = NOW()
+            WHERE id = $1
+            RETURNING *
+            "#,
+            folder_id,
+            payload.parent_id,
+            position
+        )
+        .fetch_one(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Move folder error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%folder_id, parent_id = ?folder.parent_id, "Folder moved");
+    Ok((StatusCode::OK, AxumJson(folder)))
+}
+
+/// DELETE /api/folders/{folder_id}: delete a folder with its subfolders, moving every note
+/// in them to the trash. The folders are only marked deleted, so the notes keep their place
+/// and the folders' grants until restored or purged. Needs owner access.
+#[tracing::instrument(name = "folders.delete", skip_all, fields(%folder_id, user_id = %user.id))]
+pub async fn delete_folder(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(folder_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    require_folder_role(&pool, folder_id, user.id, Role::Owner).await?;
+    let folder = fetch_folder(&pool, folder_id).await?;
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    lock_workspace(&mut tx, folder.workspace_id).await?;
+    // Locking the folders holds off notes being added to them meanwhile. Subfolders deleted
+    // earlier keep their own deletion time, and so their own purge date
+    let folder_ids = monitoring::timed(
+        "lock_folder_tree",
+        sqlx::query_scalar!(
+            r#"
+            WITH RECURSIVE tree AS (
+                SELECT id FROM folders WHERE id = $1
+                UNION ALL
+                SELECT f.id FROM folders f JOIN tree t ON f.parent_id = t.id
+                WHERE f.deleted_at IS NULL
+            )
+            SELECT f.id FROM folders f JOIN tree t ON t.id = f.id
+            FOR UPDATE OF f
+            "#,
+            folder_id
+        )
+        .fetch_all(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Lock folder tree error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let trashed = monitoring::timed(
+        "trash_folder_notes",
+        sqlx::query!(
+            r#"
+            UPDATE notes
+            SET deleted_by = CASE WHEN deleted_at IS NULL THEN $2 ELSE deleted_by END,
+                deleted_at = COALESCE(deleted_at, NOW())
+            WHERE folder_id = ANY($1)
+            "#,
+            &folder_ids,
+            user.id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Trash folder notes error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected();
+    monitoring::timed(
+        "delete_folder",
+        sqlx::query!(
+            "UPDATE folders SET deleted_at = NOW() WHERE id = ANY($1)",
+            &folder_ids
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete folder error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%folder_id, trashed, "Folder deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// PUT /api/notes/{note_id}/folder: move a note into a folder of its workspace, or to the
+/// workspace root. Editors can only move it where nobody gains access to it.
+#[tracing::instrument(name = "folders.move_note", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn move_note(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+    Json(payload): Json<MoveNoteRequest>,
+) -> AppResult<impl IntoResponse> {
+    let role = permissions::require_role(&pool, note_id, user.id, Role::Editor).await?;
+    let workspace_id = monitoring::timed(
+        "get_note_workspace",
+        sqlx::query_scalar!("SELECT workspace_id FROM notes WHERE id = $1", note_id)
+            .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get note workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    require_destination(&pool, user.id, Some(workspace_id), payload.folder_id).await?;
+    require_no_escalation(&pool, role, payload.folder_id, |user_id| {
+        permissions::note_role(&pool, note_id, user_id)
+    })
+    .await?;
+
+    let mut note = monitoring::timed(
+        "move_note",
+        sqlx::query_as!(
+            Note,
+            "UPDATE notes SET folder_id = $2 WHERE id = $1 RETURNING *",
+            note_id,
+            payload.folder_id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Move note error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    note.tags = Some(note.tags.unwrap_or_default());
+    Ok((StatusCode::OK, AxumJson(note)))
+}
+
+/// POST /api/folders/{folder_id}/share: give these accounts access to the folder, its
+/// subfolders and every note in them. Works like sharing a note.
+#[tracing::instrument(name = "folders.share", skip_all, fields(%folder_id, user_id = %user.id))]
+pub async fn share_folder(
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<SharedMailer>,
+    user: SessionUser,
+    Path(folder_id): Path<Uuid>,
+    Json(payload): Json<ShareRequest>,
+) -> AppResult<impl IntoResponse> {
+    require_folder_role(&pool, folder_id, user.id, Role::Owner).await?;
+    let expires_at = permissions::grant_expiry(payload.role, payload.expiry_days)?;
+    let (recipients, not_found) =
+        permissions::resolve_recipients(&pool, &payload.emails, user.id).await?;
+
+    let ids: Vec<Uuid> = recipients.iter().map(|(id, _)| *id).collect();
+    let granted = monitoring::timed(
+        "grant_folder_permissions",
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO folder_permissions (id, folder_id, user_id, role, granted_by, expires_at)
+            SELECT gen_random_uuid(), $1, r.user_id, $3, $4, $5 FROM UNNEST($2::uuid[]) AS r(user_id)
+            ON CONFLICT (folder_id, user_id) DO UPDATE
+            SET role = EXCLUDED.role, granted_by = EXCLUDED.granted_by,
+                expires_at = EXCLUDED.expires_at, updated_at = NOW()
+            WHERE folder_permissions.role <> 'owner'
+            RETURNING user_id
+            "#,
+            folder_id,
+            &ids,
+            payload.role.as_str(),
+            user.id,
+            expires_at
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Grant folder permissions error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let folder = fetch_folder(&pool, folder_id).await?;
+    let link = format!("{}/folder/{}", utils::app_base_url(), folder_id);
+    permissions::notify_recipients(
+        &pool,
+        mailer,
+        user.id,
+        "folder",
+        &folder.name,
+        &link,
+        &recipients,
+        &granted,
+    )
+    .await?;
+
+    tracing::info!(%folder_id, granted = granted.len(), role = payload.role.as_str(), "Folder shared");
+    let permissions = fetch_folder_permissions(&pool, folder_id).await?;
+    Ok((
+        StatusCode::OK,
+        AxumJson(ShareResponse {
+            permissions,
+            not_found,
+        }),
+    ))
+}
+
+/// GET /api/folders/{folder_id}/permissions: who was given access to this folder itself.
+#[tracing::instrument(name = "folders.permissions", skip_all, fields(%folder_id, user_id = %user.id))]
+pub async fn list_folder_permissions(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(folder_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    require_folder_role(&pool, folder_id, user.id, Role::Viewer).await?;
+    let permissions = fetch_folder_permissions(&pool, folder_id).await?;
+    Ok((StatusCode::OK, AxumJson(permissions)))
+}
+
+/// DELETE /api/folders/{folder_id}/permissions/{user_id}: revoke someone's access to the
+/// folder (owners), or stop following a folder shared with the caller.
+#[tracing::instrument(name = "folders.revoke", skip_all, fields(%folder_id, %user_id))]
+pub async fn revoke_folder_permission(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path((folder_id, user_id)): Path<(Uuid, Uuid)>,
+) -> AppResult<impl IntoResponse> {
+    let min = if user_id == user.id {
+        Role::Viewer
+    } else {
+        Role::Owner
+    };
+    require_folder_role(&pool, folder_id, user.id, min).await?;
+    let revoked = monitoring::timed(
+        "delete_folder_permission",
+        sqlx::query!(
+            "DELETE FROM folder_permissions WHERE folder_id = $1 AND user_id = $2",
+            folder_id,
+            user_id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete folder permission error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    if !revoked {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(%folder_id, %user_id, "Folder permission revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use axum::response::Response;
+
+    async fn move_to(
+        pool: &PgPool,
+        user_id: Uuid,
+        folder_id: Uuid,
+        parent_id: Option<Uuid>,
+    ) -> AppResult<Response> {
+        let user = SessionUser {
+            id: user_id,
+            jti: Uuid::new_v4(),
+            expires_at: 0,
+            session_id: Uuid::new_v4(),
+        };
+        let payload = MoveFolderRequest {
+            parent_id,
+            position: None,
+        };
+        move_folder(
+            Extension(pool.clone()),
+            user,
+            Path(folder_id),
+            Json(payload),
+        )
+        .await
+        .map(IntoResponse::into_response)
+    }
+
+    async fn parent_of(pool: &PgPool, folder_id: Uuid) -> Option<Uuid> {
+        sqlx::query_scalar!("SELECT parent_id FROM folders WHERE id = $1", folder_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn folders_cant_move_into_themselves(pool: PgPool) {
+        let owner = test_support::create_user(&pool, "owner").await;
+        let workspace = test_support::create_workspace(&pool, owner).await;
+        let top = test_support::create_folder(&pool, workspace, None).await;
+        let child = test_support::create_folder(&pool, workspace, Some(top)).await;
+        let grandchild = test_support::create_folder(&pool, workspace, Some(child)).await;
+
+        for parent in [top, child, grandchild] {
+            assert!(matches!(
+                move_to(&pool, owner, top, Some(parent)).await,
+                Err(AppError::Conflict(_))
+            ));
+        }
+        assert_eq!(parent_of(&pool, top).await, None);
+    }
+
+    #[sqlx::test]
+    async fn folders_move_within_the_tree(pool: PgPool) {
+        let owner = test_support::create_user(&pool, "owner").await;
+        let workspace = test_support::create_workspace(&pool, owner).await;
+        let a = test_support::create_folder(&pool, workspace, None).await;
+        let b = test_support::create_folder(&pool, workspace, None).await;
+        let child = test_support::create_folder(&pool, workspace, Some(a)).await;
+
+        let response = move_to(&pool, owner, a, Some(b)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(parent_of(&pool, a).await, Some(b));
+        assert_eq!(parent_of(&pool, child).await, Some(a));
+
+        // b now holds child, through a
+        assert!(matches!(
+            move_to(&pool, owner, b, Some(child)).await,
+            Err(AppError::Conflict(_))
+        ));
+        move_to(&pool, owner, a, None).await.unwrap();
+        assert_eq!(parent_of(&pool, a).await, None);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::models::Claims;
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    http::{header, HeaderValue},
+    response::IntoResponse,
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::{
+    rsa::PublicKeyComponents,
+    signature::{Ed25519KeyPair, KeyPair, RsaKeyPair},
+};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, fs, sync::OnceLock};
+
+static KEYS: OnceLock<JwtKeys> = OnceLock::new();
+
+/// Load the keys from the environment (see [`JwtKeys::from_env`]). Call once at startup.
+pub fn init() -> Result<()> {
+    let keys = JwtKeys::from_env()?;
+    match &keys.kid {
+        Some(kid) => tracing::info!("Signing JWTs with {:?} key {}", keys.algorithm, kid),
+        None => tracing::info!("Signing JWTs with {:?}", keys.algorithm),
+    }
+    KEYS.set(keys)
+        .map_err(|_| anyhow!("JWT keys already initialised"))
+}
+
+/// The keys loaded by [`init`].
+pub fn keys() -> Result<&'static JwtKeys> {
+    KEYS.get().context("JWT keys are not initialised")
+}
+
+/// Key used to sign new access tokens, plus every key accepted when verifying them.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    /// `kid` header of newly signed tokens; `None` for HS256
+    kid: Option<String>,
+    encoding: EncodingKey,
+    /// Asymmetric verification keys by `kid`: the signing key and those from `JWT_VERIFY_JWKS_FILE`
+    verification: HashMap<String, (Algorithm, DecodingKey)>,
+    /// `JWT_SECRET`, accepted for HS256 tokens without a `kid`
+    hmac: Option<DecodingKey>,
+    /// Public keys published at `/.well-known/jwks.json`
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    /// Configuration:
+    /// - `JWT_ALGORITHM`: `HS256` (default, signs with `JWT_SECRET`), `RS256` or `EdDSA`
+    /// - `JWT_PRIVATE_KEY_FILE`: PEM private key for `RS256`/`EdDSA` (PKCS#8, or PKCS#1 for RSA)
+    /// - `JWT_KEY_ID`: `kid` of that key, by default its RFC 7638 thumbprint
+    /// - `JWT_VERIFY_JWKS_FILE`: JWKS with further public keys to accept and publish, e.g.
+    ///   the previous key during rotation
+    /// - `JWT_SECRET`: with an asymmetric algorithm, optional; HS256 tokens signed with it
+    ///   are still accepted, to migrate away from HS256 without signing everyone out
+    pub fn from_env() -> Result<Self> {
+        let algorithm = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+        let secret = env::var("JWT_SECRET").ok().filter(|s| !s.is_empty());
+        let hmac = secret
+            .as_ref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+
+        let mut keys = match algorithm.as_str() {
+            "HS256" => {
+                let secret = secret.context("JWT_SECRET environment variable is not set")?;
+                JwtKeys {
+                    algorithm: Algorithm::HS256,
+                    kid: None,
+                    encoding: EncodingKey::from_secret(secret.as_bytes()),
+                    verification: HashMap::new(),
+                    hmac,
+                    jwks: JwkSet { keys: Vec::new() },
+                }
+            }
+            "RS256" | "EdDSA" => {
+                let path = env::var("JWT_PRIVATE_KEY_FILE")
+                    .context("JWT_PRIVATE_KEY_FILE must be set for asymmetric JWT signing")?;
+                let pem = fs::read(&path).with_context(|| format!("Failed to read {}", path))?;
+                let (algorithm, encoding, params) = if algorithm == "RS256" {
+                    rsa_signing_key(&pem)?
+                } else {
+                    ed25519_signing_key(&pem)?
+                };
+                let kid = env::var("JWT_KEY_ID").unwrap_or_else(|_| thumbprint(&params));
+                let jwk = public_jwk(algorithm, &kid, params);
+                let decoding = DecodingKey::from_jwk(&jwk).context("Invalid JWT public key")?;
+                JwtKeys {
+                    algorithm,
+                    kid: Some(kid.clone()),
+                    encoding,
+                    verification: HashMap::from([(kid, (algorithm, decoding))]),
+                    hmac,
+                    jwks: JwkSet { keys: vec![jwk] },
+                }
+            }
+            other => bail!("Unsupported JWT_ALGORITHM: {}", other),
+        };
+
+        if let Ok(path) = env::var("JWT_VERIFY_JWKS_FILE") {
+            let raw = fs::read(&path).with_context(|| format!("Failed to read {}", path))?;
+            let extra: JwkSet = serde_json::from_slice(&raw)
+                .with_context(|| format!("Invalid JWKS in {}", path))?;
+            for jwk in extra.keys {
+                keys.add_verification_key(jwk)?;
+            }
+        }
+        Ok(keys)
+    }
+
+    fn add_verification_key(&mut self, jwk: Jwk) -> Result<()> {
+        let kid = jwk
+            .common
+            .key_id
+            .clone()
+            .context("Verification JWK without kid")?;
+        let algorithm = match (&jwk.algorithm, jwk.common.key_algorithm) {
+            (AlgorithmParameters::RSA(_), None | Some(KeyAlgorithm::RS256)) => Algorithm::RS256,
+            (AlgorithmParameters::OctetKeyPair(_), None | Some(KeyAlgorithm::EdDSA)) => {
+                Algorithm::EdDSA
+            }
+            _ => bail!(
+                "Verification JWK {} must be an RS256 or EdDSA public key",
+                kid
+            ),
+        };
+        if self.verification.contains_key(&kid) {
+            bail!("Duplicate JWT key id {}", kid);
+        }
+        let decoding =
+            DecodingKey::from_jwk(&jwk).with_context(|| format!("Invalid JWK {}", kid))?;
+        self.verification.insert(kid, (algorithm, decoding));
+        self.jwks.keys.push(jwk);
+        Ok(())
+    }
+
+    pub fn encode(&self, claims: &Claims) -> Result<String> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        encode(&header, claims, &self.encoding).context("Failed to encode JWT token")
+    }
+
+    /// Verify a token with the key named by its `kid` (or `JWT_SECRET` when it has none)
+    /// and return its claims. The token's `alg` must match that key's algorithm.
+    pub fn decode(&self, token: &str) -> Result<Claims> {
+        let header = decode_header(token).context("Failed to decode JWT header")?;
+        let (algorithm, key) = match &header.kid {
+            Some(kid) => self
+                .verification
+                .get(kid)
+                .map(|(algorithm, key)| (*algorithm, key))
+                .with_context(|| format!("Unknown JWT key id {}", kid))?,
+            None => (
+                Algorithm::HS256,
+                self.hmac
+                    .as_ref()
+                    .context("HS256 tokens are not accepted")?,
+            ),
+        };
+        let token_data = decode::<Claims>(token, key, &Validation::new(algorithm))
+            .context("Failed to decode JWT token")?;
+        Ok(token_data.claims)
+    }
+}
+
+fn rsa_signing_key(pem: &[u8]) -> Result<(Algorithm, EncodingKey, AlgorithmParameters)> {
+    let parsed = pem::parse(pem).context("Invalid PEM private key")?;
+    let key_pair = match parsed.tag() {
+        "RSA PRIVATE KEY" => RsaKeyPair::from_der(parsed.contents()),
+        _ => RsaKeyPair::from_pkcs8(parsed.contents()),
+    }
+    .map_err(|err| anyhow!("Invalid RSA private key: {}", err))?;
+    let public = PublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+    let params = AlgorithmParameters::RSA(RSAKeyParameters {
+        key_type: RSAKeyType::RSA,
+        n: URL_SAFE_NO_PAD.encode(&public.n),
+        e: URL_SAFE_NO_PAD.encode(&public.e),
+    });
+    let encoding = EncodingKey::from_rsa_pem(pem).context("Invalid RSA private key")?;
+    Ok((Algorithm::RS256, encoding, params))
+}
+
+fn ed25519_signing_key(pem: &[u8]) -> Result<(Algorithm, EncodingKey, AlgorithmParameters)> {
+    let parsed = pem::parse(pem).context("Invalid PEM private key")?;
+    let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(parsed.contents())
+        .map_err(|err| anyhow!("Invalid Ed25519 private key: {}", err))?;
+    let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+        key_type: OctetKeyPairType::OctetKeyPair,
+        curve: EllipticCurve::Ed25519,
+        x: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+    });
+    let encoding = EncodingKey::from_ed_pem(pem).context("Invalid Ed25519 private key")?;
+    Ok((Algorithm::EdDSA, encoding, params))
+}
+
+fn public_jwk(algorithm: Algorithm, kid: &str, params: AlgorithmParameters) -> Jwk {
+    let key_algorithm = match algorithm {
+        Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+        _ => KeyAlgorithm::RS256,
+    };
+    Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: params,
+    }
+}
+
+/// RFC 7638 JWK thumbprint: SHA-256 over the required members in lexicographic order.
+fn thumbprint(params: &AlgorithmParameters) -> String {
+    let canonical = match params {
+        AlgorithmParameters::RSA(rsa) => {
+            format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n)
+        }
+        AlgorithmParameters::OctetKeyPair(okp) => {
+            format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, okp.x)
+        }
+        _ => String::new(),
+    };
+    URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+}
+
+/// GET /.well-known/jwks.json: public keys other services use to verify access tokens.
+/// Empty with HS256, whose secret is never published.
+pub async fn jwks_handler() -> impl IntoResponse {
+    let jwks = keys()
+        .map(|keys| keys.jwks.clone())
+        .unwrap_or(JwkSet { keys: Vec::new() });
+    (
+        [(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=300"),
+        )],
+        Json(jwks),
+    )
+}
@@ -1,12 +1,38 @@
+mod access_tokens;
 mod auth;
 mod db;
+mod email_verification;
 mod errors;
+mod folders;
+mod jwt_keys;
+mod mailer;
+mod mfa;
 mod models;
+mod monitoring;
+mod note_states;
+mod oidc;
+mod password_reset;
+mod permissions;
+mod preferences;
+mod publishing;
+mod rate_limit;
+mod redis_store;
+mod revocation;
 mod routes;
+mod share_links;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod trash;
+mod users;
 mod utils;
+mod workspaces;
 mod ws;
 
-use axum::{Extension, Router};
+use axum::{
+    http::{header, HeaderName},
+    Extension, Router,
+};
 use dotenv::dotenv;
 use redis::Client as RedisClient;
 use sqlx::PgPool;
@@ -20,8 +46,20 @@ async fn main() -> anyhow::Result<()> {
     // Load .env
     dotenv().ok();
 
-    // Init logging
-    tracing_subscriber::fmt::init();
+    // Init logging (RUST_LOG filter, LOG_FORMAT=json for structured output)
+    let tracer_provider = telemetry::init_tracing()?;
+
+    // JWT signing/verification keys (fails fast on bad key configuration)
+    jwt_keys::init()?;
+
+    // Outgoing mail (MAIL_TRANSPORT=log|file|smtp)
+    let mailer = mailer::from_env()?;
+
+    // Single sign-on provider (enabled by OIDC_ISSUER_URL)
+    let oidc = oidc::from_env()?;
+
+    // Prometheus recorder (rendered at /metrics on METRICS_ADDR)
+    let prometheus = monitoring::install_recorder()?;
 
     // Read env
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -31,34 +69,92 @@ async fn main() -> anyhow::Result<()> {
     let pg_pool = PgPool::connect(&database_url).await?;
     tracing::info!("Connected to PostgreSQL");
 
+    // Delete accounts whose deletion grace period has passed
+    tokio::spawn(users::purge_deleted_accounts(pg_pool.clone()));
+    // Delete notes that have been in the trash past the retention period
+    tokio::spawn(trash::purge_trash(pg_pool.clone()));
+
     // Redis client
     let redis_client = RedisClient::open(redis_url.as_str())?;
     tracing::info!("Connected to Redis");
 
     // WebSocket broadcast channel (buffer 100)
-    let (tx, _rx): (broadcast::Sender<ws::WsMessage>, broadcast::Receiver<ws::WsMessage>) =
-        broadcast::channel(100);
+    let (tx, _rx): (
+        broadcast::Sender<ws::WsMessage>,
+        broadcast::Receiver<ws::WsMessage>,
+    ) = broadcast::channel(100);
 
     // CORS (development-wide allow; restrict for prod)
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([
+            HeaderName::from_static(telemetry::REQUEST_ID_HEADER),
+            HeaderName::from_static("x-ratelimit-limit"),
+            HeaderName::from_static("x-ratelimit-remaining"),
+            HeaderName::from_static("x-ratelimit-reset"),
+            header::RETRY_AFTER,
+        ]);
+
+    // Metrics listener, local only unless METRICS_ADDR says otherwise
+    let metrics_app = routes::metrics_routes()
+        .layer(Extension(pg_pool.clone()))
+        .layer(Extension(prometheus));
+    let metrics_addr = utils::env_or("METRICS_ADDR", SocketAddr::from(([127, 0, 0, 1], 9090)));
+    let metrics_listener = TcpListener::bind(metrics_addr).await?;
+    tracing::info!("Metrics listening on {}", metrics_addr);
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(metrics_listener, metrics_app).await {
+            tracing::error!("Metrics server error: {:?}", err);
+        }
+    });
 
     // Build router
     let app = Router::new()
         .merge(routes::create_routes())
         .layer(cors)
         .layer(Extension(pg_pool))
+        .layer(Extension(rate_limit::RateLimiter::new(Some(
+            redis_client.clone(),
+        ))))
+        .layer(Extension(revocation::TokenDenylist::new(Some(
+            redis_client.clone(),
+        ))))
         .layer(Extension(redis_client))
-        .layer(Extension(tx));
+        .layer(Extension(tx))
+        .layer(Extension(ws::Rooms::default()))
+        .layer(Extension(mailer))
+        .layer(Extension(oidc));
 
     // Bind and serve using axum::serve (hyper 1-compatible)
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
     tracing::info!("Server listening on {}", addr);
 
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    // Peer addresses are needed for per-IP rate limiting
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
+
+    // Flush any spans still buffered for export
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            tracing::warn!("Tracer provider shutdown error: {:?}", err);
+        }
+    }
 
     Ok(())
 }
+
+/// Resolves on Ctrl+C so the server can drain and telemetry can be flushed.
+async fn shutdown_signal() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        tracing::error!("Failed to listen for shutdown signal: {:?}", err);
+        std::future::pending::<()>().await;
+    }
+    tracing::info!("Shutdown signal received");
+}
@@ -0,0 +1,546 @@
+use crate::{
+    auth::SessionUser,
+    errors::{AppError, AppResult},
+    monitoring, utils,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::Utc;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+/// Recovery codes handed out per enrollment or regeneration.
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// TOTP time step in seconds (RFC 6238 default, what authenticator apps expect).
+const TOTP_STEP_SECS: i64 = 30;
+
+#[derive(Deserialize)]
+pub struct MfaCodeRequest {
+    /// 6-digit TOTP code, or a recovery code where accepted
+    pub code: String,
+}
+
+#[derive(Serialize)]
+struct TotpEnrollment {
+    /// Base32 secret, for entering into an authenticator app by hand
+    secret: String,
+    /// `otpauth://` URI, usually rendered as a QR code
+    otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    /// Shown once; only their hashes are stored
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MfaStatus {
+    totp_enabled: bool,
+    recovery_codes_remaining: i64,
+}
+
+/// Build the TOTP generator for a stored base32 secret. Issuer is `MFA_ISSUER`
+/// (default `Noteflow`); `:` separates issuer and account in the URI, so it is replaced.
+fn totp_for(secret: &str, account: &str) -> AppResult<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|err| {
+            tracing::error!("TOTP secret decode error: {:?}", err);
+            AppError::InternalServerError
+        })?;
+    let issuer = std::env::var("MFA_ISSUER").unwrap_or_else(|_| "Noteflow".to_string());
+    // Skew 0: adjacent steps are checked explicitly so the matched step is known
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        TOTP_STEP_SECS as u64,
+        bytes,
+        Some(issuer.replace(':', "-")),
+        account.replace(':', "-"),
+    )
+    .map_err(|err| {
+        tracing::error!("TOTP setup error: {:?}", err);
+        AppError::InternalServerError
+    })
+}
+
+/// Time step `code` is valid for, allowing one step of clock drift either way.
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let now = Utc::now().timestamp();
+    [0, -1, 1].into_iter().find_map(|offset| {
+        let time = now + offset * TOTP_STEP_SECS;
+        totp.check(code, time as u64)
+            .then_some(time / TOTP_STEP_SECS)
+    })
+}
+
+/// Strip the spaces and dashes users type into codes.
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Accept a TOTP code once: its step must be later than the last accepted one, which is
+/// recorded atomically so concurrent requests can't both use it.
+async fn accept_totp(
+    pool: &PgPool,
+    user_id: Uuid,
+    secret: &str,
+    account: &str,
+    code: &str,
+) -> AppResult<bool> {
+    let Some(step) = matching_step(&totp_for(secret, account)?, code) else {
+        return Ok(false);
+    };
+    let accepted = monitoring::timed(
+        "record_totp_step",
+        sqlx::query!(
+            r#"
+            UPDATE users SET totp_last_step = $2
+            WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+            "#,
+            user_id,
+            step
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Record TOTP step error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    Ok(accepted)
+}
+
+/// Check a second factor for a user with TOTP enabled: a current TOTP code, or an unused
+/// recovery code, which is used up.
+pub async fn verify_code(pool: &PgPool, user_id: Uuid, code: &str) -> AppResult<bool> {
+    let user = monitoring::timed(
+        "get_user_totp",
+        sqlx::query!(
+            r#"
+            SELECT username, totp_secret AS "totp_secret!"
+            FROM users WHERE id = $1 AND totp_enabled_at IS NOT NULL
+            "#,
+            user_id
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get user TOTP error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let Some(user) = user else {
+        return Ok(false);
+    };
+
+    let code = normalize_code(code);
+    if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) {
+        return accept_totp(pool, user_id, &user.totp_secret, &user.username, &code).await;
+    }
+
+    let used = monitoring::timed(
+        "use_recovery_code",
+        sqlx::query!(
+            r#"
+            UPDATE mfa_recovery_codes SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            utils::hash_token(&code)
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Use recovery code error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    if used {
+        tracing::info!(%user_id, "Recovery code used");
+        monitoring::record_auth("mfa_recovery_code", "success");
+    }
+    Ok(used)
+}
+
+/// Replace the user's recovery codes with fresh ones and return them in plain text.
+/// Each is 64 random bits, formatted as `xxxx-xxxx-xxxx-xxxx`.
+async fn replace_recovery_codes(pool: &PgPool, user_id: Uuid) -> AppResult<Vec<String>> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 8];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let hex = hex::encode(bytes);
+            format!(
+                "{}-{}-{}-{}",
+                &hex[..4],
+                &hex[4..8],
+                &hex[8..12],
+                &hex[12..]
+            )
+        })
+        .collect();
+    let ids: Vec<Uuid> = codes.iter().map(|_| Uuid::new_v4()).collect();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| utils::hash_token(&normalize_code(code)))
+        .collect();
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "delete_recovery_codes",
+        sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete recovery codes error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "insert_recovery_codes",
+        sqlx::query!(
+            r#"
+            INSERT INTO mfa_recovery_codes (id, user_id, code_hash)
+            SELECT id, $2, code_hash FROM UNNEST($1::uuid[], $3::text[]) AS c(id, code_hash)
+            "#,
+            &ids,
+            user_id,
+            &hashes
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert recovery codes error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    Ok(codes)
+}
+
+/// Lifetime of the MFA challenge issued by a password login (`MFA_CHALLENGE_TTL_SECS`,
+/// default 5 minutes).
+pub fn challenge_ttl() -> i64 {
+    utils::env_or("MFA_CHALLENGE_TTL_SECS", 5 * 60)
+}
+
+/// Record a pending second login step and return its opaque token.
+pub async fn create_challenge(
+    pool: &PgPool,
+    user_id: Uuid,
+    device_label: Option<String>,
+) -> AppResult<String> {
+    let token = utils::generate_token();
+    monitoring::timed(
+        "insert_mfa_challenge",
+        sqlx::query!(
+            r#"
+            INSERT INTO mfa_challenges (id, user_id, token_hash, device_label, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            utils::hash_token(&token),
+            device_label,
+            Utc::now() + chrono::Duration::seconds(challenge_ttl())
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert MFA challenge error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    Ok(token)
+}
+
+/// GET /api/users/me/mfa: whether TOTP is enabled and how many recovery codes are left.
+#[tracing::instrument(name = "mfa.status", skip_all, fields(user_id = %user.id))]
+pub async fn status(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    let row = monitoring::timed(
+        "get_mfa_status",
+        sqlx::query!(
+            r#"
+            SELECT totp_enabled_at IS NOT NULL AS "totp_enabled!",
+                   (SELECT COUNT(*) FROM mfa_recovery_codes
+                    WHERE user_id = users.id AND used_at IS NULL) AS "recovery_codes_remaining!"
+            FROM users WHERE id = $1
+            "#,
+            user.id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get MFA status error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    Ok((
+        StatusCode::OK,
+        AxumJson(MfaStatus {
+            totp_enabled: row.totp_enabled,
+            recovery_codes_remaining: row.recovery_codes_remaining,
+        }),
+    ))
+}
+
+/// POST /api/users/me/mfa/totp: start TOTP enrollment with a new secret. Nothing is
+/// enforced until a code from it is confirmed; starting again replaces the secret.
+#[tracing::instrument(name = "mfa.enroll_totp", skip_all, fields(user_id = %user.id))]
+pub async fn enroll_totp(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+
+    let username = monitoring::timed(
+        "store_totp_secret",
+        sqlx::query_scalar!(
+            r#"
+            UPDATE users SET totp_secret = $2, totp_last_step = NULL
+            WHERE id = $1 AND totp_enabled_at IS NULL
+            RETURNING username
+            "#,
+            user.id,
+            secret
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Store TOTP secret error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or_else(|| AppError::Conflict("Two-factor authentication is already enabled".into()))?;
+
+    let otpauth_uri = totp_for(&secret, &username)?.get_url();
+    Ok((
+        StatusCode::OK,
+        AxumJson(TotpEnrollment {
+            secret,
+            otpauth_uri,
+        }),
+    ))
+}
+
+/// POST /api/users/me/mfa/totp/confirm: enable TOTP with a first code from the
+/// authenticator and return a set of recovery codes.
+#[tracing::instrument(name = "mfa.confirm_totp", skip_all, fields(user_id = %user.id))]
+pub async fn confirm_totp(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Json(payload): Json<MfaCodeRequest>,
+) -> AppResult<impl IntoResponse> {
+    let row = monitoring::timed(
+        "get_user_totp",
+        sqlx::query!(
+            "SELECT username, totp_secret, totp_enabled_at FROM users WHERE id = $1",
+            user.id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get user TOTP error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    if row.totp_enabled_at.is_some() {
+        return Err(AppError::Conflict(
+            "Two-factor authentication is already enabled".into(),
+        ));
+    }
+    let Some(secret) = row.totp_secret else {
+        return Err(AppError::BadRequest(
+            "Start two-factor enrollment first".into(),
+        ));
+    };
+    let code = normalize_code(&payload.code);
+    if !accept_totp(&pool, user.id, &secret, &row.username, &code).await? {
+        monitoring::record_auth("mfa_enroll", "failure");
+        return Err(AppError::BadRequest("Invalid code".into()));
+    }
+
+    monitoring::timed(
+        "enable_totp",
+        sqlx::query!(
+            "UPDATE users SET totp_enabled_at = NOW() WHERE id = $1 AND totp_secret = $2",
+            user.id,
+            secret
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Enable TOTP error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let recovery_codes = replace_recovery_codes(&pool, user.id).await?;
+
+    tracing::info!(user_id = %user.id, "Two-factor authentication enabled");
+    monitoring::record_auth("mfa_enroll", "success");
+    Ok((StatusCode::OK, AxumJson(RecoveryCodes { recovery_codes })))
+}
+
+/// DELETE /api/users/me/mfa/totp: turn two-factor authentication off, confirmed with a
+/// TOTP or recovery code. Recovery codes and pending login challenges are discarded.
+#[tracing::instrument(name = "mfa.disable_totp", skip_all, fields(user_id = %user.id))]
+pub async fn disable_totp(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Json(payload): Json<MfaCodeRequest>,
+) -> AppResult<impl IntoResponse> {
+    if !verify_code(&pool, user.id, &payload.code).await? {
+        monitoring::record_auth("mfa_disable", "failure");
+        return Err(AppError::BadRequest("Invalid code".into()));
+    }
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "disable_totp",
+        sqlx::query!(
+            r#"
+            UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+            WHERE id = $1
+            "#,
+            user.id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Disable TOTP error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "delete_recovery_codes",
+        sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = $1", user.id)
+            .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete recovery codes error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "expire_mfa_challenges",
+        sqlx::query!(
+            "UPDATE mfa_challenges SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+            user.id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Expire MFA challenges error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(user_id = %user.id, "Two-factor authentication disabled");
+    monitoring::record_auth("mfa_disable", "success");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/users/me/mfa/recovery-codes: replace all recovery codes, confirmed with a
+/// TOTP or recovery code.
+#[tracing::instrument(name = "mfa.regenerate_recovery_codes", skip_all, fields(user_id = %user.id))]
+pub async fn regenerate_recovery_codes(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Json(payload): Json<MfaCodeRequest>,
+) -> AppResult<impl IntoResponse> {
+    if !verify_code(&pool, user.id, &payload.code).await? {
+        return Err(AppError::BadRequest("Invalid code".into()));
+    }
+    let recovery_codes = replace_recovery_codes(&pool, user.id).await?;
+    Ok((StatusCode::OK, AxumJson(RecoveryCodes { recovery_codes })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    fn code_at(totp: &TOTP, time: i64) -> String {
+        totp.generate(time as u64)
+    }
+
+    #[test]
+    fn codes_are_normalized() {
+        assert_eq!(normalize_code(" 123 456 "), "123456");
+        assert_eq!(normalize_code("ABCD-efgh-1234"), "abcdefgh1234");
+    }
+
+    #[test]
+    fn codes_match_within_one_step_of_drift() {
+        let totp = totp_for(SECRET, "ann").unwrap();
+        let now = Utc::now().timestamp();
+        for offset in [0, -TOTP_STEP_SECS, TOTP_STEP_SECS] {
+            let time = now + offset;
+            assert_eq!(
+                matching_step(&totp, &code_at(&totp, time)),
+                Some(time / TOTP_STEP_SECS)
+            );
+        }
+        assert_eq!(
+            matching_step(&totp, &code_at(&totp, now + 3 * TOTP_STEP_SECS)),
+            None
+        );
+        assert_eq!(matching_step(&totp, "000000x"), None);
+    }
+
+    #[sqlx::test]
+    async fn a_code_is_accepted_only_once(pool: PgPool) {
+        let user_id = test_support::create_user(&pool, "ann").await;
+        let totp = totp_for(SECRET, "ann").unwrap();
+        let code = code_at(&totp, Utc::now().timestamp());
+
+        assert!(accept_totp(&pool, user_id, SECRET, "ann", &code)
+            .await
+            .unwrap());
+        assert!(!accept_totp(&pool, user_id, SECRET, "ann", &code)
+            .await
+            .unwrap());
+    }
+}
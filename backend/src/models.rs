@@ -9,6 +9,18 @@ pub struct User {
     pub id: Uuid,
     /// Unique username
     pub username: String,
+    /// Name shown instead of the username, if set
+    pub display_name: Option<String>,
+    /// URL of the profile picture, if set
+    pub avatar_url: Option<String>,
+    /// Email address, if any
+    pub email: Option<String>,
+    /// Whether the current email address has been verified
+    pub email_verified: bool,
+    /// Timestamp when the account was created
+    pub created_at: DateTime<Utc>,
+    /// When the account will be deleted, if deletion has been requested
+    pub deletion_scheduled_at: Option<DateTime<Utc>>,
 }
 
 /// Represents a note created by a user.
@@ -16,8 +28,12 @@ pub struct User {
 pub struct Note {
     /// Unique identifier of the note
     pub id: Uuid,
-    /// Reference to the user who owns the note
-    pub user_id: Uuid,
+    /// User who created the note; `None` if their account was deleted
+    pub user_id: Option<Uuid>,
+    /// Workspace the note belongs to
+    pub workspace_id: Uuid,
+    /// Folder the note is in; `None` at the root of the workspace
+    pub folder_id: Option<Uuid>,
     /// Title or headline of the note
     pub title: String,
     /// Main content body of the note
@@ -31,6 +47,20 @@ pub struct Note {
     pub created_at: DateTime<Utc>,
     /// Timestamp of last update to the note
     pub updated_at: DateTime<Utc>,
+    /// When the note was moved to the trash; `None` unless trashed
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Who moved the note to the trash
+    pub deleted_by: Option<Uuid>,
+}
+
+/// The caller's own flags on a note.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct NoteState {
+    /// Listed before other notes
+    pub pinned: bool,
+    pub favorite: bool,
+    /// Left out of listings unless asked for
+    pub archived: bool,
 }
 
 /// Represents a single revision/version of a note (body snapshot).
@@ -57,4 +87,202 @@ pub struct Claims {
     pub username: String,
     /// Expiration timestamp for token validity (Unix seconds)
     pub exp: i64,
+    /// Issued-at timestamp (Unix seconds), compared against logout-everywhere cutoffs
+    pub iat: i64,
+    /// Unique token ID, the key under which a logged-out token is denylisted
+    pub jti: Uuid,
+    /// Session (login) the token was issued for
+    pub sid: Uuid,
+}
+
+/// A recorded login attempt, shown to users as their recent sign-in activity.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoginAttempt {
+    /// Unique identifier of the attempt
+    pub id: Uuid,
+    /// Client IP address the attempt came from
+    pub ip: String,
+    /// User-Agent header sent with the attempt, if any
+    pub user_agent: Option<String>,
+    /// Outcome: success, invalid_password or locked
+    pub outcome: String,
+    /// Successful sign-in from an IP not seen before for this account
+    pub new_ip: bool,
+    /// Timestamp of the attempt
+    pub created_at: DateTime<Utc>,
+}
+
+/// A signed-in device, shown to users so they can revoke individual sessions.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    /// Unique identifier of the session
+    pub id: Uuid,
+    /// Device name supplied by the client at login, if any
+    pub device_label: Option<String>,
+    /// User-Agent header sent at login
+    pub user_agent: Option<String>,
+    /// Client IP address of the most recent login or token refresh
+    pub ip: String,
+    /// Timestamp of the login that started the session
+    pub created_at: DateTime<Utc>,
+    /// Timestamp of the most recent login or token refresh
+    pub last_seen_at: DateTime<Utc>,
+    /// Whether this is the session of the requesting access token
+    pub current: bool,
+}
+
+/// A personal access token as listed to its owner. The token itself is only shown once.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PersonalAccessToken {
+    /// Unique identifier of the token
+    pub id: Uuid,
+    /// Name given by the owner, e.g. "Backup script"
+    pub name: String,
+    /// First characters of the token, to tell tokens apart
+    pub token_prefix: String,
+    /// Granted scopes, e.g. `notes:read`
+    pub scopes: Vec<String>,
+    /// Expiry, if any
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Timestamp of the most recent request made with the token
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Timestamp of creation
+    pub created_at: DateTime<Utc>,
+}
+
+/// A user's access to a note or folder, as listed to its collaborators.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NotePermission {
+    /// User the access is granted to
+    pub user_id: Uuid,
+    /// Username of that user
+    pub username: String,
+    /// Display name of that user, if set
+    pub display_name: Option<String>,
+    /// owner, editor or viewer
+    pub role: String,
+    /// When the access ends, if ever
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Owner who granted the access; `None` for the creator or a deleted account
+    pub granted_by: Option<Uuid>,
+    /// Timestamp of the first grant
+    pub created_at: DateTime<Utc>,
+}
+
+/// An anonymous link to a note, as listed to its owners. The token itself is only shown once.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShareLink {
+    /// Unique identifier of the link
+    pub id: Uuid,
+    /// First characters of the token, to tell links apart
+    pub token_prefix: String,
+    /// view, comment or edit
+    pub role: String,
+    /// Whether opening the link needs a password
+    pub has_password: bool,
+    /// Expiry, if any
+    pub expires_at: Option<DateTime<Utc>>,
+    /// How many times the link may be opened, if limited
+    pub max_uses: Option<i32>,
+    /// How many times the link has been opened
+    pub use_count: i32,
+    /// Timestamp of the most recent open
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Owner who created the link; `None` if their account was deleted
+    pub created_by: Option<Uuid>,
+    /// Timestamp of creation
+    pub created_at: DateTime<Utc>,
+}
+
+/// Where and at which revision a note is published, as shown to its owners.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Publication {
+    /// Note that is published
+    pub note_id: Uuid,
+    /// Path segment of the public page, `/p/{slug}`
+    pub slug: String,
+    /// Revision of the note the page shows
+    pub revision: i64,
+    /// `updated_at` of the note at that revision
+    pub note_updated_at: DateTime<Utc>,
+    /// Owner who last published the note; `None` if their account was deleted
+    pub published_by: Option<Uuid>,
+    /// Timestamp of the last publish
+    pub published_at: DateTime<Utc>,
+    /// Timestamp of unpublishing; `None` while the page is public
+    pub unpublished_at: Option<DateTime<Utc>>,
+}
+
+/// A workspace as seen by one of its members.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Workspace {
+    /// Unique identifier of the workspace
+    pub id: Uuid,
+    /// Name shown to members
+    pub name: String,
+    /// Whether this is the caller's personal workspace
+    pub personal: bool,
+    /// The caller's role: owner, admin, member or guest
+    pub role: String,
+    /// Timestamp of creation
+    pub created_at: DateTime<Utc>,
+    /// Timestamp of the last rename
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A member of a workspace.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkspaceMember {
+    /// The member's user ID
+    pub user_id: Uuid,
+    /// Username of the member
+    pub username: String,
+    /// Display name of the member, if set
+    pub display_name: Option<String>,
+    /// owner, admin, member or guest
+    pub role: String,
+    /// Who invited the member, if anyone
+    pub invited_by: Option<Uuid>,
+    /// Timestamp of joining
+    pub joined_at: DateTime<Utc>,
+}
+
+/// A pending invitation to a workspace. The token is only sent by email.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkspaceInvitation {
+    /// Unique identifier of the invitation
+    pub id: Uuid,
+    /// Address the invitation was sent to
+    pub email: String,
+    /// Role given on acceptance: admin, member or guest
+    pub role: String,
+    /// Who sent the invitation; `None` if their account was deleted
+    pub invited_by: Option<Uuid>,
+    /// Timestamp after which the invitation can't be accepted
+    pub expires_at: DateTime<Utc>,
+    /// Timestamp of sending
+    pub created_at: DateTime<Utc>,
+}
+
+/// A folder in a workspace's tree.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Folder {
+    /// Unique identifier of the folder
+    pub id: Uuid,
+    /// Workspace the folder belongs to
+    pub workspace_id: Uuid,
+    /// Enclosing folder; `None` at the root of the workspace
+    pub parent_id: Option<Uuid>,
+    /// Name shown in the tree
+    pub name: String,
+    /// Order among sibling folders, lowest first
+    pub position: i32,
+    /// Who created the folder; `None` if their account was deleted
+    pub created_by: Option<Uuid>,
+    /// Timestamp of creation
+    pub created_at: DateTime<Utc>,
+    /// Timestamp of the last rename or move
+    pub updated_at: DateTime<Utc>,
+    /// When the folder was deleted; `None` unless deleted
+    pub deleted_at: Option<DateTime<Utc>>,
 }
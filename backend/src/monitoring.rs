@@ -0,0 +1,114 @@
+use axum::{
+    extract::{Extension, MatchedPath, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::PgPool;
+use std::future::Future;
+use std::time::Instant;
+use tracing::Instrument;
+
+/// Latency buckets (seconds) shared by the HTTP and SQL histograms.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Install the global Prometheus recorder and return the handle used to render `/metrics`.
+pub fn install_recorder() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_string()),
+            LATENCY_BUCKETS,
+        )?
+        .install_recorder()?;
+    Ok(handle)
+}
+
+/// Middleware recording request count and latency per matched route, method and status.
+/// Unmatched requests are grouped under a single label to keep cardinality bounded.
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+
+    let response = next.run(req).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [("method", method), ("route", route), ("status", status)];
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!("http_request_duration_seconds", &labels).record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Await a database future inside a `db.query` span, recording its duration under the given query name.
+pub async fn timed<F, T>(query: &'static str, fut: F) -> T
+where
+    F: Future<Output = T>,
+{
+    let span = tracing::info_span!(
+        "db.query",
+        otel.kind = "client",
+        db.system = "postgresql",
+        db.operation = query,
+    );
+    let start = Instant::now();
+    let out = fut.instrument(span).await;
+    histogram!("db_query_duration_seconds", "query" => query).record(start.elapsed().as_secs_f64());
+    out
+}
+
+/// Count an authentication attempt by action (login/signup) and outcome.
+pub fn record_auth(action: &'static str, outcome: &'static str) {
+    counter!("auth_attempts_total", "action" => action, "outcome" => outcome).increment(1);
+}
+
+/// Count a suspicious sign-in pattern (e.g. `new_ip`, `lockout`).
+pub fn record_login_anomaly(kind: &'static str) {
+    counter!("auth_login_anomalies_total", "kind" => kind).increment(1);
+}
+
+/// Track WebSocket connection open/close.
+pub fn ws_connection_opened() {
+    gauge!("ws_active_connections").increment(1.0);
+}
+
+pub fn ws_connection_closed() {
+    gauge!("ws_active_connections").decrement(1.0);
+}
+
+/// Set the number of rooms (notes) that currently have at least one connection.
+pub fn set_ws_rooms(rooms: usize) {
+    gauge!("ws_active_rooms").set(rooms as f64);
+}
+
+/// Count broadcast messages a slow receiver dropped because it fell behind.
+pub fn record_ws_lag(skipped: u64) {
+    counter!("ws_broadcast_lag_events_total").increment(1);
+    counter!("ws_broadcast_lagged_messages_total").increment(skipped);
+}
+
+/// GET /metrics: Prometheus text exposition. Pool gauges are sampled at scrape time.
+pub async fn metrics_handler(
+    Extension(handle): Extension<PrometheusHandle>,
+    Extension(pool): Extension<PgPool>,
+) -> impl IntoResponse {
+    let size = pool.size() as f64;
+    let idle = pool.num_idle() as f64;
+    gauge!("db_pool_connections", "state" => "idle").set(idle);
+    gauge!("db_pool_connections", "state" => "in_use").set(size - idle);
+    gauge!("db_pool_max_connections").set(pool.options().get_max_connections() as f64);
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render(),
+    )
+}
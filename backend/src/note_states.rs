@@ -0,0 +1,76 @@
+use crate::{
+    access_tokens::Scope,
+    auth::AuthUser,
+    errors::{AppError, AppResult},
+    models::NoteState,
+    monitoring,
+    permissions::{self, Role},
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct UpdateNoteStateRequest {
+    pub pinned: Option<bool>,
+    pub favorite: Option<bool>,
+    pub archived: Option<bool>,
+}
+
+/// PATCH /api/notes/{note_id}/state: pin, favorite or archive a note for the caller only,
+/// or undo it. Fields left out keep their value. Needs any access to the note.
+#[tracing::instrument(name = "note_states.update", skip_all, fields(%note_id, caller_id = %user.id))]
+pub async fn update_note_state(
+    Extension(pool): Extension<PgPool>,
+    user: AuthUser,
+    Path(note_id): Path<Uuid>,
+    Json(payload): Json<UpdateNoteStateRequest>,
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesWrite)?;
+    permissions::require_role(&pool, note_id, user.id, Role::Viewer).await?;
+    // Setting a flag again keeps when it was first set
+    let row = monitoring::timed(
+        "update_note_state",
+        sqlx::query!(
+            r#"
+            INSERT INTO note_user_states (note_id, user_id, pinned_at, favorited_at, archived_at)
+            VALUES ($1, $2, CASE WHEN $3 THEN NOW() END, CASE WHEN $4 THEN NOW() END,
+                    CASE WHEN $5 THEN NOW() END)
+            ON CONFLICT (note_id, user_id) DO UPDATE SET
+                pinned_at = CASE WHEN $3 IS NULL THEN note_user_states.pinned_at
+                                 WHEN $3 THEN COALESCE(note_user_states.pinned_at, NOW()) END,
+                favorited_at = CASE WHEN $4 IS NULL THEN note_user_states.favorited_at
+                                    WHEN $4 THEN COALESCE(note_user_states.favorited_at, NOW()) END,
+                archived_at = CASE WHEN $5 IS NULL THEN note_user_states.archived_at
+                                   WHEN $5 THEN COALESCE(note_user_states.archived_at, NOW()) END,
+                updated_at = NOW()
+            RETURNING pinned_at IS NOT NULL AS "pinned!", favorited_at IS NOT NULL AS "favorite!",
+                      archived_at IS NOT NULL AS "archived!"
+            "#,
+            note_id,
+            user.id,
+            payload.pinned,
+            payload.favorite,
+            payload.archived
+        )
+        .fetch_one(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update note state error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let state = NoteState {
+        pinned: row.pinned,
+        favorite: row.favorite,
+        archived: row.archived,
+    };
+    Ok((StatusCode::OK, AxumJson(state)))
+}
@@ -0,0 +1,828 @@
+use crate::{
+    auth,
+    errors::{AppError, AppResult},
+    monitoring,
+    utils::{self, ClientIp},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    extract::{Extension, Query},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Redirect, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::{
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// Cookie binding an authorization request to the browser that started it.
+const STATE_COOKIE: &str = "oidc_state";
+
+/// Lifetime of a pending authorization request.
+const STATE_TTL_SECS: i64 = 600;
+
+/// Minimum time between JWKS refetches triggered by an unknown key id.
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Parts of the provider's discovery document that the flow needs.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Discovered {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+    jwks_fetched_at: Instant,
+}
+
+/// Configured OpenID Connect provider, shared with handlers as an `Extension<Oidc>`.
+/// Discovery and the provider's keys are fetched on first use and cached.
+#[derive(Clone)]
+pub struct Oidc(Option<Arc<Provider>>);
+
+pub struct Provider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    scopes: String,
+    auto_create_users: bool,
+    http: reqwest::Client,
+    discovered: RwLock<Option<Discovered>>,
+}
+
+/// Single sign-on is enabled by setting `OIDC_ISSUER_URL`:
+/// - `OIDC_CLIENT_ID`: client registered with the provider (required)
+/// - `OIDC_CLIENT_SECRET`: for confidential clients; public clients rely on PKCE alone
+/// - `OIDC_REDIRECT_URL`: callback registered with the provider, by default
+///   `{API_BASE_URL}/api/auth/oidc/callback` (`API_BASE_URL` defaults to `http://localhost:8080`)
+/// - `OIDC_SCOPES`: default `openid email profile`
+/// - `OIDC_AUTO_CREATE_USERS`: create an account on first sign-in when none can be
+///   linked (default `true`)
+pub fn from_env() -> Result<Oidc> {
+    let Some(issuer) = env::var("OIDC_ISSUER_URL").ok().filter(|s| !s.is_empty()) else {
+        return Ok(Oidc(None));
+    };
+    let client_id = env::var("OIDC_CLIENT_ID").context("OIDC_CLIENT_ID must be set for OIDC")?;
+    let redirect_url = env::var("OIDC_REDIRECT_URL").unwrap_or_else(|_| {
+        let api = env::var("API_BASE_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+        format!("{}/api/auth/oidc/callback", api.trim_end_matches('/'))
+    });
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .context("Failed to build OIDC HTTP client")?;
+    tracing::info!("Single sign-on with OIDC provider {}", issuer);
+    Ok(Oidc(Some(Arc::new(Provider {
+        issuer: issuer.trim_end_matches('/').to_string(),
+        client_id,
+        client_secret: env::var("OIDC_CLIENT_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty()),
+        redirect_url,
+        scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+        auto_create_users: utils::env_or("OIDC_AUTO_CREATE_USERS", true),
+        http,
+        discovered: RwLock::new(None),
+    }))))
+}
+
+impl Oidc {
+    fn provider(&self) -> AppResult<&Provider> {
+        self.0.as_deref().ok_or(AppError::NotFound)
+    }
+}
+
+/// Claims of the ID token that are used.
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    azp: Option<String>,
+    email: Option<String>,
+    /// Some providers send `"true"` as a string
+    email_verified: Option<serde_json::Value>,
+    preferred_username: Option<String>,
+}
+
+impl IdTokenClaims {
+    fn verified_email(&self) -> Option<&str> {
+        let verified = match &self.email_verified {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        };
+        self.email.as_deref().filter(|_| verified)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+impl Provider {
+    /// Fetch discovery and keys on first use. The discovered issuer must match the
+    /// configured one exactly.
+    async fn ensure_discovered(&self) -> Result<()> {
+        if self.discovered.read().await.is_some() {
+            return Ok(());
+        }
+        let mut discovered = self.discovered.write().await;
+        if discovered.is_some() {
+            return Ok(());
+        }
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let metadata: ProviderMetadata = self.get_json(&url).await?;
+        if metadata.issuer.trim_end_matches('/') != self.issuer {
+            bail!(
+                "Discovered issuer {} does not match OIDC_ISSUER_URL {}",
+                metadata.issuer,
+                self.issuer
+            );
+        }
+        let jwks: JwkSet = self.get_json(&metadata.jwks_uri).await?;
+        *discovered = Some(Discovered {
+            metadata,
+            jwks,
+            jwks_fetched_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .with_context(|| format!("Request to {} failed", url))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid JSON from {}", url))
+    }
+
+    async fn authorization_url(&self, state: &str, nonce: &str, challenge: &str) -> Result<String> {
+        self.ensure_discovered().await?;
+        let discovered = self.discovered.read().await;
+        let endpoint = &discovered
+            .as_ref()
+            .context("OIDC provider not discovered")?
+            .metadata
+            .authorization_endpoint;
+        let mut url = reqwest::Url::parse(endpoint).context("Invalid authorization_endpoint")?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", challenge)
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.into())
+    }
+
+    /// Redeem the authorization code and return the validated ID token claims.
+    async fn exchange_code(
+        &self,
+        code: &str,
+        verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims> {
+        self.ensure_discovered().await?;
+        let token_endpoint = self
+            .discovered
+            .read()
+            .await
+            .as_ref()
+            .context("OIDC provider not discovered")?
+            .metadata
+            .token_endpoint
+            .clone();
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_url.as_str()),
+            ("code_verifier", verifier),
+        ];
+        let mut request = self.http.post(&token_endpoint);
+        match &self.client_secret {
+            Some(secret) => request = request.basic_auth(&self.client_id, Some(secret)),
+            None => form.push(("client_id", self.client_id.as_str())),
+        }
+        let response: TokenEndpointResponse = request
+            .form(&form)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .context("Token request failed")?
+            .json()
+            .await
+            .context("Invalid token response")?;
+
+        let claims = self.validate_id_token(&response.id_token).await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            bail!("ID token nonce mismatch");
+        }
+        if claims
+            .azp
+            .as_deref()
+            .is_some_and(|azp| azp != self.client_id)
+        {
+            bail!("ID token issued to another client");
+        }
+        Ok(claims)
+    }
+
+    /// Verify signature, issuer, audience and expiry. Only asymmetric algorithms are
+    /// accepted. An unknown `kid` triggers one JWKS refetch, for provider key rotation.
+    async fn validate_id_token(&self, id_token: &str) -> Result<IdTokenClaims> {
+        let header = decode_header(id_token).context("Invalid ID token header")?;
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            bail!("Unsupported ID token algorithm {:?}", header.alg);
+        }
+
+        let mut jwk = self.find_key(header.kid.as_deref()).await;
+        if jwk.is_none() {
+            self.refresh_jwks().await?;
+            jwk = self.find_key(header.kid.as_deref()).await;
+        }
+        let jwk = jwk.ok_or_else(|| anyhow!("No provider key for kid {:?}", header.kid))?;
+        let key = DecodingKey::from_jwk(&jwk).context("Invalid provider key")?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer, &format!("{}/", self.issuer)]);
+        validation.set_audience(&[&self.client_id]);
+        let data = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .context("ID token validation failed")?;
+        Ok(data.claims)
+    }
+
+    async fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let discovered = self.discovered.read().await;
+        let jwks = &discovered.as_ref()?.jwks;
+        match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn refresh_jwks(&self) -> Result<()> {
+        let mut discovered = self.discovered.write().await;
+        let discovered = discovered
+            .as_mut()
+            .context("OIDC provider not discovered")?;
+        if discovered.jwks_fetched_at.elapsed() < JWKS_REFRESH_INTERVAL {
+            return Ok(());
+        }
+        discovered.jwks = self.get_json(&discovered.metadata.jwks_uri).await?;
+        discovered.jwks_fetched_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// GET /api/auth/oidc/login: start single sign-on. Redirects the browser to the provider
+/// with a fresh state, nonce and PKCE challenge.
+#[tracing::instrument(name = "oidc.login", skip_all)]
+pub async fn login(
+    Extension(pool): Extension<PgPool>,
+    Extension(oidc): Extension<Oidc>,
+) -> AppResult<Response> {
+    let provider = oidc.provider()?;
+    let state = utils::generate_token();
+    let nonce = utils::generate_token();
+    let verifier = utils::generate_token();
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+    let url = provider
+        .authorization_url(&state, &nonce, &challenge)
+        .await
+        .map_err(|err| {
+            tracing::error!("OIDC discovery error: {:?}", err);
+            AppError::InternalServerError
+        })?;
+
+    // Clean up abandoned requests while we're here
+    monitoring::timed(
+        "delete_expired_oidc_states",
+        sqlx::query!("DELETE FROM oidc_login_states WHERE expires_at < NOW()").execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete expired OIDC states error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "insert_oidc_state",
+        sqlx::query!(
+            r#"
+            INSERT INTO oidc_login_states (id, state_hash, nonce, code_verifier, expires_at)
+            VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))
+            "#,
+            Uuid::new_v4(),
+            utils::hash_token(&state),
+            nonce,
+            verifier,
+            STATE_TTL_SECS as f64
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert OIDC state error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let secure = if provider.redirect_url.starts_with("https://") {
+        "; Secure"
+    } else {
+        ""
+    };
+    let cookie = format!(
+        "{}={}; Path=/api/auth/oidc; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE, state, STATE_TTL_SECS, secure
+    );
+    let mut response = Redirect::to(&url).into_response();
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&cookie).map_err(|_| AppError::InternalServerError)?,
+    );
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// GET /api/auth/oidc/callback: the provider redirects here after sign-in. On success the
+/// browser is sent to `{APP_BASE_URL}/auth/callback#token=...&refresh_token=...&expires_in=...`,
+/// otherwise to `{APP_BASE_URL}/auth/callback#error=...`. The fragment keeps tokens out of
+/// server logs.
+#[tracing::instrument(name = "oidc.callback", skip_all)]
+pub async fn callback(
+    Extension(pool): Extension<PgPool>,
+    Extension(oidc): Extension<Oidc>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> AppResult<Response> {
+    let provider = oidc.provider()?;
+    let fragment = match sign_in(&pool, provider, &ip.to_string(), &headers, query).await {
+        Ok(auth::ExternalLogin::Tokens(tokens)) => {
+            monitoring::record_auth("oidc_login", "success");
+            format!(
+                "token={}&refresh_token={}&expires_in={}",
+                tokens.token, tokens.refresh_token, tokens.expires_in
+            )
+        }
+        Ok(auth::ExternalLogin::MfaRequired(challenge)) => {
+            monitoring::record_auth("oidc_login", "mfa_required");
+            format!(
+                "mfa_required=true&mfa_token={}&expires_in={}",
+                challenge.mfa_token, challenge.expires_in
+            )
+        }
+        Err(error) => {
+            monitoring::record_auth("oidc_login", error);
+            format!("error={}", error)
+        }
+    };
+
+    let mut response = Redirect::to(&format!(
+        "{}/auth/callback#{}",
+        utils::app_base_url(),
+        fragment
+    ))
+    .into_response();
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        HeaderValue::from_static(
+            "oidc_state=; Path=/api/auth/oidc; Max-Age=0; HttpOnly; SameSite=Lax",
+        ),
+    );
+    Ok(response)
+}
+
+/// Run the callback; errors are short codes for the web app, details are logged.
+async fn sign_in(
+    pool: &PgPool,
+    provider: &Provider,
+    ip: &str,
+    headers: &HeaderMap,
+    query: CallbackQuery,
+) -> Result<auth::ExternalLogin, &'static str> {
+    if let Some(error) = query.error {
+        tracing::info!(%error, "OIDC provider returned an error");
+        return Err("access_denied");
+    }
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return Err("invalid_request");
+    };
+
+    // The state must come back to the browser that started the flow (login CSRF)
+    if state_cookie(headers) != Some(state.as_str()) {
+        tracing::warn!("OIDC state does not match the browser's cookie");
+        return Err("invalid_state");
+    }
+    let pending = monitoring::timed(
+        "claim_oidc_state",
+        sqlx::query!(
+            r#"
+            DELETE FROM oidc_login_states
+            WHERE state_hash = $1 AND expires_at > NOW()
+            RETURNING nonce, code_verifier
+            "#,
+            utils::hash_token(&state)
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Claim OIDC state error: {:?}", e);
+        "server_error"
+    })?
+    .ok_or("invalid_state")?;
+
+    let claims = provider
+        .exchange_code(&code, &pending.code_verifier, &pending.nonce)
+        .await
+        .map_err(|err| {
+            tracing::warn!("OIDC sign-in rejected: {:?}", err);
+            "invalid_token"
+        })?;
+
+    let (user_id, username) = resolve_user(pool, provider, &claims)
+        .await
+        .map_err(|err| {
+            tracing::error!("OIDC account linking error: {:?}", err);
+            "server_error"
+        })?
+        .ok_or("no_account")?;
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    auth::external_login(pool, user_id, &username, ip, user_agent)
+        .await
+        .map_err(|err| match err {
+            AppError::InvalidCredentials => "invalid_credentials",
+            _ => "server_error",
+        })
+}
+
+fn state_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(STATE_COOKIE)?.strip_prefix('='))
+}
+
+/// Find the account for a provider identity:
+/// 1. an identity linked earlier;
+/// 2. else a user with the same email address, verified both here and at the provider,
+///    which is then linked;
+/// 3. else, with `OIDC_AUTO_CREATE_USERS`, a new account.
+async fn resolve_user(
+    pool: &PgPool,
+    provider: &Provider,
+    claims: &IdTokenClaims,
+) -> Result<Option<(Uuid, String)>> {
+    let linked = monitoring::timed(
+        "get_user_identity",
+        sqlx::query!(
+            r#"
+            UPDATE user_identities SET last_login_at = NOW(), email = $3
+            FROM users u
+            WHERE issuer = $1 AND subject = $2 AND u.id = user_identities.user_id
+            RETURNING u.id, u.username
+            "#,
+            provider.issuer,
+            claims.sub,
+            claims.email
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .context("Identity lookup failed")?;
+    if let Some(user) = linked {
+        return Ok(Some((user.id, user.username)));
+    }
+
+    let verified_email = claims.verified_email();
+    let existing = match verified_email {
+        Some(email) => monitoring::timed(
+            "get_user_by_verified_email",
+            sqlx::query!(
+                r#"
+                SELECT id, username FROM users
+                WHERE LOWER(email) = LOWER($1) AND email_verified_at IS NOT NULL
+                "#,
+                email
+            )
+            .fetch_optional(pool),
+        )
+        .await
+        .context("User lookup by email failed")?
+        .map(|user| (user.id, user.username)),
+        None => None,
+    };
+
+    let (user_id, username) = match existing {
+        Some(user) => user,
+        None if provider.auto_create_users => create_user(pool, claims, verified_email).await?,
+        None => return Ok(None),
+    };
+
+    monitoring::timed(
+        "insert_user_identity",
+        sqlx::query!(
+            r#"
+            INSERT INTO user_identities (id, user_id, issuer, subject, email)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            provider.issuer,
+            claims.sub,
+            claims.email
+        )
+        .execute(pool),
+    )
+    .await
+    .context("Linking identity failed")?;
+    tracing::info!(%user_id, issuer = %provider.issuer, "Linked OIDC identity");
+    Ok(Some((user_id, username)))
+}
+
+/// Create an account for a first-time SSO user. The username comes from
+/// `preferred_username` or the email's local part, with a numeric suffix if taken. The
+/// password is random, so the account can only sign in through the provider until a
+/// password is reset. A verified email is kept unless another account already has it.
+async fn create_user(
+    pool: &PgPool,
+    claims: &IdTokenClaims,
+    verified_email: Option<&str>,
+) -> Result<(Uuid, String)> {
+    let base: String = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| verified_email.and_then(|email| email.split('@').next()))
+        .unwrap_or("user")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        .take(40)
+        .collect();
+    let base = if base.is_empty() {
+        "user".to_string()
+    } else {
+        base
+    };
+
+    let password = utils::generate_token();
+    let password_hash = utils::hash_password(&password).await?;
+    let email_taken = match verified_email {
+        Some(email) => monitoring::timed(
+            "check_email_taken",
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM users WHERE LOWER(email) = LOWER($1)) AS "taken!""#,
+                email
+            )
+            .fetch_one(pool),
+        )
+        .await
+        .context("Email lookup failed")?,
+        None => false,
+    };
+    let email = verified_email.filter(|_| !email_taken);
+
+    for attempt in 0..5 {
+        let username = if attempt == 0 {
+            base.clone()
+        } else {
+            format!("{}{}", base, rand::random::<u16>() % 10_000)
+        };
+        let user_id = Uuid::new_v4();
+        let created = monitoring::timed(
+            "insert_user",
+            sqlx::query!(
+                r#"
+                INSERT INTO users (id, username, password_hash, email, email_verified_at)
+                VALUES ($1, $2, $3, $4, CASE WHEN $4::text IS NULL THEN NULL ELSE NOW() END)
+                ON CONFLICT (username) DO NOTHING
+                "#,
+                user_id,
+                username,
+                password_hash,
+                email
+            )
+            .execute(pool),
+        )
+        .await
+        .context("Creating user failed")?
+        .rows_affected()
+            > 0;
+        if created {
+            tracing::info!(%user_id, %username, "Created account for OIDC sign-in");
+            return Ok((user_id, username));
+        }
+    }
+    bail!("No free username for {}", base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use axum::{routing::get, Json, Router};
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use serde_json::json;
+
+    const CLIENT_ID: &str = "noteflow";
+    const KID: &str = "mock-key";
+
+    /// Local identity provider serving discovery and one Ed25519 key. Returns the provider
+    /// configured against it and the key that signs its ID tokens.
+    async fn mock_idp(auto_create_users: bool) -> (Provider, EncodingKey) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwks = json!({ "keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+            "kid": KID,
+            "alg": "EdDSA",
+            "use": "sig",
+        }]});
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || async move { Json(discovery) }),
+            )
+            .route("/jwks", get(move || async move { Json(jwks) }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let provider = Provider {
+            issuer,
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_url: "http://127.0.0.1:8080/api/auth/oidc/callback".to_string(),
+            scopes: "openid email".to_string(),
+            auto_create_users,
+            http: reqwest::Client::new(),
+            discovered: RwLock::new(None),
+        };
+        (provider, EncodingKey::from_ed_der(pkcs8.as_ref()))
+    }
+
+    fn id_token(key: &EncodingKey, claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(KID.to_string());
+        encode(&header, &claims, key).unwrap()
+    }
+
+    fn claims_for(provider: &Provider, audience: &str) -> serde_json::Value {
+        json!({
+            "iss": provider.issuer,
+            "aud": audience,
+            "sub": "idp-user-1",
+            "exp": Utc::now().timestamp() + 300,
+            "email": "ann@example.com",
+            "email_verified": "true",
+        })
+    }
+
+    fn identity(sub: &str, email: Option<&str>, verified: bool) -> IdTokenClaims {
+        IdTokenClaims {
+            sub: sub.to_string(),
+            nonce: None,
+            azp: None,
+            email: email.map(str::to_string),
+            email_verified: Some(serde_json::Value::Bool(verified)),
+            preferred_username: Some("ann".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn id_tokens_from_the_provider_are_accepted() {
+        let (provider, key) = mock_idp(true).await;
+        provider.ensure_discovered().await.unwrap();
+
+        let token = id_token(&key, claims_for(&provider, CLIENT_ID));
+        let claims = provider.validate_id_token(&token).await.unwrap();
+        assert_eq!(claims.sub, "idp-user-1");
+        assert_eq!(claims.verified_email(), Some("ann@example.com"));
+    }
+
+    #[tokio::test]
+    async fn id_tokens_for_another_client_or_symmetric_are_rejected() {
+        let (provider, key) = mock_idp(true).await;
+        provider.ensure_discovered().await.unwrap();
+
+        let token = id_token(&key, claims_for(&provider, "other-client"));
+        assert!(provider.validate_id_token(&token).await.is_err());
+
+        let hs256 = encode(
+            &Header::new(Algorithm::HS256),
+            &claims_for(&provider, CLIENT_ID),
+            &EncodingKey::from_secret(b"guessable"),
+        )
+        .unwrap();
+        assert!(provider.validate_id_token(&hs256).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn identities_link_by_verified_email_only(pool: PgPool) {
+        let (provider, _) = mock_idp(true).await;
+        let ann = test_support::create_user(&pool, "ann").await;
+
+        let linked = resolve_user(
+            &pool,
+            &provider,
+            &identity("sub-1", Some("ANN@example.com"), true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(linked, Some((ann, "ann".to_string())));
+        // Later sign-ins find the identity, whatever the email
+        let again = resolve_user(&pool, &provider, &identity("sub-1", None, false))
+            .await
+            .unwrap();
+        assert_eq!(again.map(|(id, _)| id), Some(ann));
+
+        // An unverified address links nothing: a new account is made under a free username
+        let (created, username) = resolve_user(
+            &pool,
+            &provider,
+            &identity("sub-2", Some("ann@example.com"), false),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_ne!(created, ann);
+        assert!(username.starts_with("ann") && username != "ann");
+    }
+
+    #[sqlx::test]
+    async fn unknown_identities_are_refused_without_auto_create(pool: PgPool) {
+        let (provider, _) = mock_idp(false).await;
+        let resolved = resolve_user(
+            &pool,
+            &provider,
+            &identity("sub-1", Some("new@example.com"), true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved, None);
+    }
+}
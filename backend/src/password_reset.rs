@@ -0,0 +1,189 @@
+use crate::{
+    access_tokens, auth,
+    errors::{AppError, AppResult},
+    mailer::{self, Email, SharedMailer},
+    monitoring,
+    revocation::TokenDenylist,
+    utils,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetConfirmRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// POST /api/password-reset/request: email a one-time reset link to the account with this
+/// address. Always answers 202, so the response doesn't reveal whether the address exists.
+#[tracing::instrument(name = "password_reset.request", skip_all)]
+pub async fn request_reset(
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<SharedMailer>,
+    Json(payload): Json<ResetRequest>,
+) -> AppResult<impl IntoResponse> {
+    let accepted = (
+        StatusCode::ACCEPTED,
+        AxumJson(serde_json::json!({ "status": "ok" })),
+    );
+
+    let user = monitoring::timed(
+        "get_user_by_email",
+        sqlx::query!(
+            "SELECT id, email AS \"email!\" FROM users WHERE LOWER(email) = LOWER($1)",
+            payload.email.trim()
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("DB query error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let Some(user) = user else {
+        monitoring::record_auth("password_reset_request", "unknown_email");
+        return Ok(accepted);
+    };
+
+    // Only the most recently requested link stays valid
+    monitoring::timed(
+        "expire_password_reset_tokens",
+        sqlx::query!(
+            "UPDATE password_reset_tokens SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+            user.id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Expire password reset tokens error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let ttl: i64 = utils::env_or("PASSWORD_RESET_TTL_SECS", 60 * 60);
+    let token = utils::generate_token();
+    monitoring::timed(
+        "insert_password_reset_token",
+        sqlx::query!(
+            r#"
+            INSERT INTO password_reset_tokens (id, user_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            Uuid::new_v4(),
+            user.id,
+            utils::hash_token(&token),
+            Utc::now() + chrono::Duration::seconds(ttl)
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert password reset token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let link = format!("{}/reset-password?token={}", utils::app_base_url(), token);
+    mailer::send_in_background(
+        mailer,
+        Email {
+            to: user.email,
+            subject: "Reset your Noteflow password".to_string(),
+            body: format!(
+                "Someone asked to reset the password of your Noteflow account.\n\n\
+                 Choose a new password here (the link works once and expires in {} minutes):\n\
+                 {}\n\n\
+                 If this wasn't you, ignore this email; your password stays unchanged.\n",
+                ttl / 60,
+                link
+            ),
+        },
+    );
+
+    monitoring::record_auth("password_reset_request", "success");
+    Ok(accepted)
+}
+
+/// POST /api/password-reset/confirm: set a new password with a reset token. The token is
+/// used up, and every session of the account is signed out.
+#[tracing::instrument(name = "password_reset.confirm", skip_all)]
+pub async fn confirm_reset(
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    Json(payload): Json<ResetConfirmRequest>,
+) -> AppResult<impl IntoResponse> {
+    let hashed = utils::hash_password(&payload.new_password)
+        .await
+        .map_err(|err| {
+            tracing::error!("Password hashing error: {:?}", err);
+            AppError::InternalServerError
+        })?;
+
+    // Claim the token atomically so it can only ever be used once
+    let user_id = monitoring::timed(
+        "claim_password_reset_token",
+        sqlx::query_scalar!(
+            r#"
+            UPDATE password_reset_tokens SET used_at = NOW()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id
+            "#,
+            utils::hash_token(&payload.token)
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Claim password reset token error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let Some(user_id) = user_id else {
+        monitoring::record_auth("password_reset", "failure");
+        return Err(AppError::BadRequest(
+            "Invalid or expired reset token".into(),
+        ));
+    };
+
+    // Proving control of the mailbox also lifts a lockout
+    monitoring::timed(
+        "update_password",
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET password_hash = $2, failed_login_count = 0, lockout_count = 0, locked_until = NULL
+            WHERE id = $1
+            "#,
+            user_id,
+            hashed
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update password error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    auth::revoke_all_sessions(&pool, &denylist, user_id).await?;
+    // Tokens an intruder may have created stop working too
+    access_tokens::revoke_all(&pool, user_id).await?;
+
+    tracing::info!(%user_id, "Password reset");
+    monitoring::record_auth("password_reset", "success");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,593 @@
+use crate::{
+    auth::SessionUser,
+    email_verification,
+    errors::{AppError, AppResult},
+    mailer::{self, Email, SharedMailer},
+    models::NotePermission,
+    monitoring, preferences, utils,
+    workspaces::WorkspaceRole,
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Most addresses one share request may name.
+const MAX_SHARE_EMAILS: usize = 50;
+
+/// Access to a note, ordered from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Read the note
+    Viewer,
+    /// Read and change the note
+    Editor,
+    /// Also share and delete the note
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Role> {
+        match value {
+            "viewer" => Some(Role::Viewer),
+            "editor" => Some(Role::Editor),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShareRequest {
+    pub emails: Vec<String>,
+    pub role: Role,
+    /// Days until the access ends; it doesn't when omitted
+    #[serde(alias = "expiryDays")]
+    pub expiry_days: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePermissionRequest {
+    pub role: Role,
+    #[serde(alias = "expiryDays")]
+    pub expiry_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ShareResponse {
+    /// Everyone with access after the change
+    pub permissions: Vec<NotePermission>,
+    /// Addresses that don't belong to an account with a verified email
+    pub not_found: Vec<String>,
+}
+
+/// The user's role on a note, and whether the note is in the trash: the highest of an
+/// unexpired grant on the note itself, one on its folder or any folder above, and what
+/// their role in the note's workspace gives (see [`WorkspaceRole::note_role`]).
+async fn note_access(
+    pool: &PgPool,
+    note_id: Uuid,
+    user_id: Uuid,
+) -> AppResult<(Option<Role>, bool)> {
+    let roles = monitoring::timed(
+        "get_note_role",
+        sqlx::query!(
+            r#"
+            WITH RECURSIVE chain AS (
+                SELECT f.id, f.parent_id FROM folders f JOIN notes n ON n.folder_id = f.id
+                WHERE n.id = $1
+                UNION ALL
+                SELECT f.id, f.parent_id FROM folders f JOIN chain c ON f.id = c.parent_id
+            )
+            SELECT
+                (SELECT deleted_at IS NOT NULL FROM notes WHERE id = $1) AS trashed,
+                (SELECT role FROM note_permissions
+                 WHERE note_id = $1 AND user_id = $2
+                   AND (expires_at IS NULL OR expires_at > NOW())) AS note_role,
+                (SELECT p.role FROM folder_permissions p JOIN chain c ON c.id = p.folder_id
+                 WHERE p.user_id = $2 AND (p.expires_at IS NULL OR p.expires_at > NOW())
+                 ORDER BY CASE p.role WHEN 'owner' THEN 0 WHEN 'editor' THEN 1 ELSE 2 END
+                 LIMIT 1) AS folder_role,
+                (SELECT m.role FROM notes n
+                 JOIN workspace_members m ON m.workspace_id = n.workspace_id
+                 WHERE n.id = $1 AND m.user_id = $2) AS workspace_role
+            "#,
+            note_id,
+            user_id
+        )
+        .fetch_one(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get note role error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let granted = roles.note_role.as_deref().and_then(Role::parse);
+    let from_folder = roles.folder_role.as_deref().and_then(Role::parse);
+    let inherited = roles
+        .workspace_role
+        .as_deref()
+        .and_then(WorkspaceRole::parse)
+        .and_then(WorkspaceRole::note_role);
+    Ok((
+        granted.max(from_folder).max(inherited),
+        roles.trashed.unwrap_or(false),
+    ))
+}
+
+/// The user's role on a note, if they have access. Notes in the trash are only reachable
+/// through the trash endpoints, so they count as no access here.
+pub async fn note_role(pool: &PgPool, note_id: Uuid, user_id: Uuid) -> AppResult<Option<Role>> {
+    let (role, trashed) = note_access(pool, note_id, user_id).await?;
+    Ok(role.filter(|_| !trashed))
+}
+
+fn check_role(role: Option<Role>, min: Role) -> AppResult<Role> {
+    match role {
+        None => Err(AppError::NotFound),
+        Some(role) if role < min => Err(AppError::Forbidden(format!(
+            "This requires {} access to the note",
+            min.as_str()
+        ))),
+        Some(role) => Ok(role),
+    }
+}
+
+/// Require at least `min` on a note. Without any access the note is reported as not
+/// found, so its existence isn't revealed; with too little the request is forbidden.
+pub async fn require_role(
+    pool: &PgPool,
+    note_id: Uuid,
+    user_id: Uuid,
+    min: Role,
+) -> AppResult<Role> {
+    check_role(note_role(pool, note_id, user_id).await?, min)
+}
+
+/// Like [`require_role`], for a note in the trash; notes that aren't trashed are not found.
+pub async fn require_trashed_role(
+    pool: &PgPool,
+    note_id: Uuid,
+    user_id: Uuid,
+    min: Role,
+) -> AppResult<Role> {
+    let (role, trashed) = note_access(pool, note_id, user_id).await?;
+    check_role(role.filter(|_| trashed), min)
+}
+
+/// Expiry for a grant of `days`, checking the range. Owner access never expires.
+pub fn grant_expiry(role: Role, days: Option<i64>) -> AppResult<Option<DateTime<Utc>>> {
+    match days {
+        None => Ok(None),
+        Some(_) if role == Role::Owner => {
+            Err(AppError::BadRequest("Owner access can't expire".into()))
+        }
+        Some(days) if !(1..=3650).contains(&days) => Err(AppError::BadRequest(
+            "expiry_days must be between 1 and 3650".into(),
+        )),
+        Some(days) => Ok(Some(Utc::now() + chrono::Duration::days(days))),
+    }
+}
+
+async fn fetch_permissions(pool: &PgPool, note_id: Uuid) -> AppResult<Vec<NotePermission>> {
+    monitoring::timed(
+        "list_note_permissions",
+        sqlx::query_as!(
+            NotePermission,
+            r#"
+            SELECT p.user_id, u.username, u.display_name, p.role, p.expires_at, p.granted_by,
+                   p.created_at
+            FROM note_permissions p JOIN users u ON u.id = p.user_id
+            WHERE p.note_id = $1 AND (p.expires_at IS NULL OR p.expires_at > NOW())
+            ORDER BY CASE p.role WHEN 'owner' THEN 0 WHEN 'editor' THEN 1 ELSE 2 END, p.created_at
+            "#,
+            note_id
+        )
+        .fetch_all(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List note permissions error: {:?}", e);
+        AppError::InternalServerError
+    })
+}
+
+/// The accounts with these verified email addresses, other than the sharer's, as
+/// `(id, lowercased email)`, and the addresses that matched none.
+pub async fn resolve_recipients(
+    pool: &PgPool,
+    emails: &[String],
+    sharer_id: Uuid,
+) -> AppResult<(Vec<(Uuid, String)>, Vec<String>)> {
+    if emails.is_empty() || emails.len() > MAX_SHARE_EMAILS {
+        return Err(AppError::BadRequest(format!(
+            "Share with 1 to {} email addresses",
+            MAX_SHARE_EMAILS
+        )));
+    }
+    let mut emails = emails
+        .iter()
+        .map(|email| email_verification::normalize_email(email).map(|e| e.to_lowercase()))
+        .collect::<AppResult<Vec<_>>>()?;
+    emails.sort();
+    emails.dedup();
+
+    // Only verified addresses count: anyone can type an address in at signup
+    let recipients = monitoring::timed(
+        "resolve_share_emails",
+        sqlx::query!(
+            r#"
+            SELECT id, LOWER(email) AS "email!" FROM users
+            WHERE LOWER(email) = ANY($1) AND email_verified_at IS NOT NULL AND id <> $2
+            "#,
+            &emails,
+            sharer_id
+        )
+        .fetch_all(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Resolve share emails error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let recipients: Vec<(Uuid, String)> = recipients.into_iter().map(|r| (r.id, r.email)).collect();
+    let not_found = emails
+        .into_iter()
+        .filter(|email| !recipients.iter().any(|(_, found)| found == email))
+        .collect();
+    Ok((recipients, not_found))
+}
+
+/// POST /api/notes/{note_id}/share: give the accounts with these verified email addresses
+/// access to the caller's note, and email them about it. Re-sharing replaces an earlier
+/// grant, except that owners keep owning the note.
+#[tracing::instrument(name = "permissions.share", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn share_note(
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<SharedMailer>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+    Json(payload): Json<ShareRequest>,
+) -> AppResult<impl IntoResponse> {
+    require_role(&pool, note_id, user.id, Role::Owner).await?;
+    let expires_at = grant_expiry(payload.role, payload.expiry_days)?;
+    let (recipients, not_found) = resolve_recipients(&pool, &payload.emails, user.id).await?;
+
+    let ids: Vec<Uuid> = recipients.iter().map(|(id, _)| *id).collect();
+    let granted = monitoring::timed(
+        "grant_note_permissions",
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO note_permissions (id, note_id, user_id, role, granted_by, expires_at)
+            SELECT gen_random_uuid(), $1, r.user_id, $3, $4, $5 FROM UNNEST($2::uuid[]) AS r(user_id)
+            ON CONFLICT (note_id, user_id) DO UPDATE
+            SET role = EXCLUDED.role, granted_by = EXCLUDED.granted_by,
+                expires_at = EXCLUDED.expires_at, updated_at = NOW()
+            WHERE note_permissions.role <> 'owner'
+            RETURNING user_id
+            "#,
+            note_id,
+            &ids,
+            payload.role.as_str(),
+            user.id,
+            expires_at
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Grant note permissions error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let title = monitoring::timed(
+        "get_note_title",
+        sqlx::query_scalar!("SELECT title FROM notes WHERE id = $1", note_id).fetch_one(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get note title error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let link = format!("{}/note/{}", utils::app_base_url(), note_id);
+    notify_recipients(
+        &pool,
+        mailer,
+        user.id,
+        "note",
+        &title,
+        &link,
+        &recipients,
+        &granted,
+    )
+    .await?;
+
+    tracing::info!(%note_id, granted = granted.len(), role = payload.role.as_str(), "Note shared");
+    let permissions = fetch_permissions(&pool, note_id).await?;
+    Ok((
+        StatusCode::OK,
+        AxumJson(ShareResponse {
+            permissions,
+            not_found,
+        }),
+    ))
+}
+
+/// Email newly granted users about a share of `what` ("note" or "folder") titled `title`,
+/// unless they turned those emails off.
+#[allow(clippy::too_many_arguments)]
+pub async fn notify_recipients(
+    pool: &PgPool,
+    mailer: SharedMailer,
+    sharer_id: Uuid,
+    what: &str,
+    title: &str,
+    link: &str,
+    recipients: &[(Uuid, String)],
+    granted: &[Uuid],
+) -> AppResult<()> {
+    let opted_out = preferences::share_emails_disabled(pool, granted).await?;
+    let sharer = monitoring::timed(
+        "get_sharer_name",
+        sqlx::query_scalar!(
+            r#"SELECT COALESCE(display_name, username) AS "sharer!" FROM users WHERE id = $1"#,
+            sharer_id
+        )
+        .fetch_one(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get sharer name error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    for (id, email) in recipients {
+        if !granted.contains(id) || opted_out.contains(id) {
+            continue;
+        }
+        mailer::send_in_background(
+            mailer.clone(),
+            Email {
+                to: email.clone(),
+                subject: format!("{} shared a {} with you", sharer, what),
+                body: format!(
+                    "{} shared \"{}\" with you on Noteflow:\n{}\n",
+                    sharer, title, link
+                ),
+            },
+        );
+    }
+    Ok(())
+}
+
+/// GET /api/notes/{note_id}/permissions: who has access to a note the caller can see.
+#[tracing::instrument(name = "permissions.list", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn list_permissions(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    require_role(&pool, note_id, user.id, Role::Viewer).await?;
+    let permissions = fetch_permissions(&pool, note_id).await?;
+    Ok((StatusCode::OK, AxumJson(permissions)))
+}
+
+/// PUT /api/notes/{note_id}/permissions/{user_id}: change someone's role or expiry on the
+/// caller's note.
+#[tracing::instrument(name = "permissions.update", skip_all, fields(%note_id, %user_id))]
+pub async fn update_permission(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path((note_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<UpdatePermissionRequest>,
+) -> AppResult<impl IntoResponse> {
+    require_role(&pool, note_id, user.id, Role::Owner).await?;
+    let expires_at = grant_expiry(payload.role, payload.expiry_days)?;
+
+    let updated = monitoring::timed(
+        "update_note_permission",
+        sqlx::query!(
+            r#"
+            UPDATE note_permissions SET role = $3, expires_at = $4, granted_by = $5, updated_at = NOW()
+            WHERE note_id = $1 AND user_id = $2 AND (expires_at IS NULL OR expires_at > NOW())
+            "#,
+            note_id,
+            user_id,
+            payload.role.as_str(),
+            expires_at,
+            user.id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update note permission error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    if !updated {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(%note_id, %user_id, role = payload.role.as_str(), "Note permission changed");
+    let permissions = fetch_permissions(&pool, note_id).await?;
+    Ok((StatusCode::OK, AxumJson(permissions)))
+}
+
+/// DELETE /api/notes/{note_id}/permissions/{user_id}: revoke someone's access to the
+/// caller's note, or leave a note shared with the caller.
+#[tracing::instrument(name = "permissions.revoke", skip_all, fields(%note_id, %user_id))]
+pub async fn revoke_permission(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path((note_id, user_id)): Path<(Uuid, Uuid)>,
+) -> AppResult<impl IntoResponse> {
+    let min = if user_id == user.id {
+        Role::Viewer
+    } else {
+        Role::Owner
+    };
+    require_role(&pool, note_id, user.id, min).await?;
+
+    let revoked = monitoring::timed(
+        "delete_note_permission",
+        sqlx::query!(
+            "DELETE FROM note_permissions WHERE note_id = $1 AND user_id = $2",
+            note_id,
+            user_id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete note permission error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    if !revoked {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(%note_id, %user_id, "Note permission revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    async fn grant_note(pool: &PgPool, note_id: Uuid, user_id: Uuid, role: &str, days: i32) {
+        sqlx::query!(
+            r#"
+            INSERT INTO note_permissions (id, note_id, user_id, role, expires_at)
+            VALUES ($1, $2, $3, $4, NOW() + make_interval(days => $5))
+            "#,
+            Uuid::new_v4(),
+            note_id,
+            user_id,
+            role,
+            days
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn grant_folder(pool: &PgPool, folder_id: Uuid, user_id: Uuid, role: &str) {
+        sqlx::query!(
+            r#"
+            INSERT INTO folder_permissions (id, folder_id, user_id, role)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            Uuid::new_v4(),
+            folder_id,
+            user_id,
+            role
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn the_highest_of_note_folder_and_workspace_access_wins(pool: PgPool) {
+        let owner = test_support::create_user(&pool, "owner").await;
+        let guest = test_support::create_user(&pool, "guest").await;
+        let workspace = test_support::create_workspace(&pool, owner).await;
+        test_support::add_member(&pool, workspace, guest, "guest").await;
+        let top = test_support::create_folder(&pool, workspace, None).await;
+        let inner = test_support::create_folder(&pool, workspace, Some(top)).await;
+        let note = test_support::create_note(&pool, workspace, Some(inner), owner).await;
+
+        // Guests see nothing that isn't shared with them
+        assert_eq!(note_role(&pool, note, guest).await.unwrap(), None);
+        grant_note(&pool, note, guest, "viewer", 7).await;
+        assert_eq!(
+            note_role(&pool, note, guest).await.unwrap(),
+            Some(Role::Viewer)
+        );
+        // A grant on a folder above reaches the note
+        grant_folder(&pool, top, guest, "editor").await;
+        assert_eq!(
+            note_role(&pool, note, guest).await.unwrap(),
+            Some(Role::Editor)
+        );
+        // Workspace owners own every note
+        assert_eq!(
+            note_role(&pool, note, owner).await.unwrap(),
+            Some(Role::Owner)
+        );
+    }
+
+    #[sqlx::test]
+    async fn members_edit_every_note(pool: PgPool) {
+        let owner = test_support::create_user(&pool, "owner").await;
+        let member = test_support::create_user(&pool, "member").await;
+        let workspace = test_support::create_workspace(&pool, owner).await;
+        test_support::add_member(&pool, workspace, member, "member").await;
+        let note = test_support::create_note(&pool, workspace, None, owner).await;
+
+        assert_eq!(
+            note_role(&pool, note, member).await.unwrap(),
+            Some(Role::Editor)
+        );
+        assert!(matches!(
+            require_role(&pool, note, member, Role::Owner).await,
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn expired_grants_give_no_access(pool: PgPool) {
+        let owner = test_support::create_user(&pool, "owner").await;
+        let outsider = test_support::create_user(&pool, "outsider").await;
+        let workspace = test_support::create_workspace(&pool, owner).await;
+        let note = test_support::create_note(&pool, workspace, None, owner).await;
+
+        grant_note(&pool, note, outsider, "editor", -1).await;
+        assert_eq!(note_role(&pool, note, outsider).await.unwrap(), None);
+        assert!(matches!(
+            require_role(&pool, note, outsider, Role::Viewer).await,
+            Err(AppError::NotFound)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn trashed_notes_are_reached_only_through_the_trash(pool: PgPool) {
+        let owner = test_support::create_user(&pool, "owner").await;
+        let workspace = test_support::create_workspace(&pool, owner).await;
+        let note = test_support::create_note(&pool, workspace, None, owner).await;
+
+        assert!(matches!(
+            require_trashed_role(&pool, note, owner, Role::Owner).await,
+            Err(AppError::NotFound)
+        ));
+        sqlx::query!("UPDATE notes SET deleted_at = NOW() WHERE id = $1", note)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(note_role(&pool, note, owner).await.unwrap(), None);
+        assert_eq!(
+            require_trashed_role(&pool, note, owner, Role::Owner)
+                .await
+                .unwrap(),
+            Role::Owner
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use crate::{
+    auth::SessionUser,
+    errors::{AppError, AppResult},
+    monitoring,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A setting users may store, with its default and the values it accepts.
+struct Setting {
+    key: &'static str,
+    /// Accepted values, as told to clients that send something else
+    expected: &'static str,
+    default: fn() -> Value,
+    valid: fn(&Value) -> bool,
+}
+
+/// Every setting of the current schema version. Keys not listed here are rejected.
+const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "theme",
+        expected: "one of light, dark, system",
+        default: || json!("system"),
+        valid: |v| one_of(v, &["light", "dark", "system"]),
+    },
+    Setting {
+        key: "editor.font_family",
+        expected: "one of sans, serif, mono",
+        default: || json!("sans"),
+        valid: |v| one_of(v, &["sans", "serif", "mono"]),
+    },
+    Setting {
+        key: "editor.font_size",
+        expected: "an integer from 10 to 32",
+        default: || json!(16),
+        valid: |v| v.as_u64().is_some_and(|size| (10..=32).contains(&size)),
+    },
+    Setting {
+        key: "editor.spellcheck",
+        expected: "a boolean",
+        default: || json!(true),
+        valid: Value::is_boolean,
+    },
+    Setting {
+        key: "notes.default_tags",
+        expected: "up to 20 tags of 1 to 50 characters",
+        default: || json!([]),
+        valid: |v| {
+            v.as_array().is_some_and(|tags| {
+                tags.len() <= 20
+                    && tags.iter().all(|tag| {
+                        tag.as_str()
+                            .is_some_and(|tag| !tag.trim().is_empty() && tag.chars().count() <= 50)
+                    })
+            })
+        },
+    },
+    Setting {
+        key: "notifications.email_on_share",
+        expected: "a boolean",
+        default: || json!(true),
+        valid: Value::is_boolean,
+    },
+];
+
+/// Upgrades between schema versions: entry `i` turns a version `i + 1` document into
+/// version `i + 2`. Add one whenever a key is renamed, split or changes meaning.
+const UPGRADES: &[fn(&mut Map<String, Value>)] = &[];
+
+/// Version of the keys in [`SETTINGS`], stored with every document.
+const SCHEMA_VERSION: i32 = UPGRADES.len() as i32 + 1;
+
+fn one_of(value: &Value, allowed: &[&str]) -> bool {
+    value.as_str().is_some_and(|value| allowed.contains(&value))
+}
+
+fn setting(key: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.key == key)
+}
+
+/// Bring a stored document up to the current schema version. Keys that are no longer
+/// known or no longer valid are dropped, so they fall back to their defaults.
+fn upgrade(version: i32, settings: Value) -> Map<String, Value> {
+    let mut settings = match settings {
+        Value::Object(settings) => settings,
+        _ => Map::new(),
+    };
+    for step in UPGRADES.iter().skip((version - 1).max(0) as usize) {
+        step(&mut settings);
+    }
+    settings.retain(|key, value| {
+        let keep = setting(key).is_some_and(|setting| (setting.valid)(value));
+        if !keep {
+            tracing::warn!(%key, version, "Dropping stored preference");
+        }
+        keep
+    });
+    settings
+}
+
+#[derive(Serialize)]
+struct PreferencesResponse {
+    schema_version: i32,
+    /// Every setting, with its default unless the user changed it
+    preferences: Map<String, Value>,
+    /// Timestamp of the last change; `null` if the user never changed anything
+    updated_at: Option<DateTime<Utc>>,
+}
+
+impl PreferencesResponse {
+    fn new(settings: &Map<String, Value>, updated_at: Option<DateTime<Utc>>) -> Self {
+        let preferences = SETTINGS
+            .iter()
+            .map(|setting| {
+                let value = settings
+                    .get(setting.key)
+                    .cloned()
+                    .unwrap_or_else(setting.default);
+                (setting.key.to_string(), value)
+            })
+            .collect();
+        PreferencesResponse {
+            schema_version: SCHEMA_VERSION,
+            preferences,
+            updated_at,
+        }
+    }
+}
+
+/// GET /api/users/me/preferences: the caller's preferences, defaults filled in.
+#[tracing::instrument(name = "preferences.get", skip_all, fields(user_id = %user.id))]
+pub async fn get_preferences(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    let row = monitoring::timed(
+        "get_user_preferences",
+        sqlx::query!(
+            "SELECT schema_version, settings, updated_at FROM user_preferences WHERE user_id = $1",
+            user.id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get user preferences error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let response = match row {
+        Some(row) => PreferencesResponse::new(
+            &upgrade(row.schema_version, row.settings),
+            Some(row.updated_at),
+        ),
+        None => PreferencesResponse::new(&Map::new(), None),
+    };
+    Ok((StatusCode::OK, AxumJson(response)))
+}
+
+/// PATCH /api/users/me/preferences: change some settings, e.g. `{ "theme": "dark" }`.
+/// A `null` value resets a setting to its default. Nothing is saved if any key is unknown
+/// or any value is invalid.
+#[tracing::instrument(name = "preferences.update", skip_all, fields(user_id = %user.id))]
+pub async fn update_preferences(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Json(patch): Json<Map<String, Value>>,
+) -> AppResult<impl IntoResponse> {
+    for (key, value) in &patch {
+        let setting = setting(key)
+            .ok_or_else(|| AppError::BadRequest(format!("Unknown preference: {}", key)))?;
+        if !value.is_null() && !(setting.valid)(value) {
+            return Err(AppError::BadRequest(format!(
+                "{} must be {}",
+                key, setting.expected
+            )));
+        }
+    }
+
+    // Lock the document so concurrent changes to different keys don't overwrite each other
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "ensure_user_preferences",
+        sqlx::query!(
+            r#"
+            INSERT INTO user_preferences (user_id, schema_version) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO NOTHING
+            "#,
+            user.id,
+            SCHEMA_VERSION
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert user preferences error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let row = monitoring::timed(
+        "lock_user_preferences",
+        sqlx::query!(
+            "SELECT schema_version, settings FROM user_preferences WHERE user_id = $1 FOR UPDATE",
+            user.id
+        )
+        .fetch_one(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Lock user preferences error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let mut settings = upgrade(row.schema_version, row.settings);
+    for (key, value) in patch {
+        if value.is_null() {
+            settings.remove(&key);
+        } else {
+            settings.insert(key, value);
+        }
+    }
+
+    let updated_at = monitoring::timed(
+        "update_user_preferences",
+        sqlx::query_scalar!(
+            r#"
+            UPDATE user_preferences SET schema_version = $2, settings = $3, updated_at = NOW()
+            WHERE user_id = $1
+            RETURNING updated_at
+            "#,
+            user.id,
+            SCHEMA_VERSION,
+            Value::Object(settings.clone())
+        )
+        .fetch_one(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update user preferences error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        AxumJson(PreferencesResponse::new(&settings, Some(updated_at))),
+    ))
+}
+
+/// Those of `user_ids` who turned `notifications.email_on_share` off.
+pub async fn share_emails_disabled(pool: &PgPool, user_ids: &[Uuid]) -> AppResult<Vec<Uuid>> {
+    monitoring::timed(
+        "get_share_email_opt_outs",
+        sqlx::query_scalar!(
+            r#"
+            SELECT user_id FROM user_preferences
+            WHERE user_id = ANY($1) AND settings -> 'notifications.email_on_share' = 'false'
+            "#,
+            user_ids
+        )
+        .fetch_all(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get share email opt-outs error: {:?}", e);
+        AppError::InternalServerError
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrade_drops_unknown_and_invalid_keys() {
+        let stored = json!({
+            "theme": "dark",
+            "editor.font_size": 99,
+            "email_digest": "weekly",
+            "notes.default_tags": ["work"],
+        });
+        let settings = upgrade(SCHEMA_VERSION, stored);
+        assert_eq!(
+            Value::Object(settings),
+            json!({ "theme": "dark", "notes.default_tags": ["work"] })
+        );
+    }
+
+    #[test]
+    fn upgrade_starts_over_from_a_malformed_document() {
+        assert!(upgrade(SCHEMA_VERSION, json!(["theme", "dark"])).is_empty());
+        assert!(upgrade(0, Value::Null).is_empty());
+    }
+
+    #[test]
+    fn missing_settings_get_their_defaults() {
+        let settings = upgrade(SCHEMA_VERSION, json!({ "editor.spellcheck": false }));
+        let response = PreferencesResponse::new(&settings, None);
+        assert_eq!(response.preferences.len(), SETTINGS.len());
+        assert_eq!(response.preferences["editor.spellcheck"], json!(false));
+        assert_eq!(response.preferences["theme"], json!("system"));
+        assert_eq!(response.preferences["editor.font_size"], json!(16));
+    }
+}
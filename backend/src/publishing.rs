@@ -0,0 +1,480 @@
+use crate::{
+    auth::SessionUser,
+    errors::{AppError, AppResult},
+    models::Publication,
+    monitoring,
+    permissions::{self, Role},
+    utils,
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json as AxumJson,
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use pulldown_cmark::{Options, Parser};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::LazyLock;
+use uuid::Uuid;
+
+/// Bump when the rendering or the template changes, so cached pages are refetched.
+const RENDER_VERSION: u32 = 1;
+
+/// Longest slug accepted or generated.
+const MAX_SLUG_LEN: usize = 80;
+
+/// Content-Security-Policy of published pages: no scripts, no frames, inline styles for
+/// the template and images from anywhere secure.
+const PAGE_CSP: &str = "default-src 'none'; style-src 'unsafe-inline'; img-src https: data:; base-uri 'none'; form-action 'none'; frame-ancestors 'none'";
+
+/// `Cache-Control` of published pages (`PUBLISHED_PAGE_MAX_AGE_SECS`, default 60). Caches
+/// revalidate with the ETag afterwards, so unpublishing takes at most this long to show.
+static PAGE_CACHE_CONTROL: LazyLock<String> = LazyLock::new(|| {
+    format!(
+        "public, max-age={}",
+        utils::env_or("PUBLISHED_PAGE_MAX_AGE_SECS", 60u64)
+    )
+});
+
+/// Tags and attributes allowed in rendered pages: ammonia's defaults plus the disabled
+/// checkboxes of task lists.
+static SANITIZER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut builder = ammonia::Builder::default();
+    builder
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["checked"])
+        .set_tag_attribute_value("input", "type", "checkbox")
+        .set_tag_attribute_value("input", "disabled", "");
+    builder
+});
+
+#[derive(Deserialize, Default)]
+pub struct PublishRequest {
+    /// Slug to publish at instead of the current (or a generated) one
+    pub slug: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PublicationResponse {
+    #[serde(flatten)]
+    publication: Publication,
+    /// Address of the public page
+    url: String,
+}
+
+impl From<Publication> for PublicationResponse {
+    fn from(publication: Publication) -> Self {
+        let url = format!("{}/p/{}", utils::public_base_url(), publication.slug);
+        PublicationResponse { publication, url }
+    }
+}
+
+fn validate_slug(slug: &str) -> AppResult<()> {
+    let valid = (3..=MAX_SLUG_LEN).contains(&slug.len())
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-');
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(format!(
+            "Slug must be 3 to {} lowercase letters, digits or inner hyphens",
+            MAX_SLUG_LEN
+        )))
+    }
+}
+
+/// A readable slug from the note title with a random suffix, e.g. `deploy-runbook-3fa2c1`.
+fn generate_slug(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG_LEN - 7);
+    let slug = slug.trim_end_matches('-');
+    let suffix = rand::thread_rng().gen::<u32>() & 0xff_ffff;
+    if slug.is_empty() {
+        format!("note-{:06x}", suffix)
+    } else {
+        format!("{}-{:06x}", slug, suffix)
+    }
+}
+
+/// PUT /api/notes/{note_id}/publication: publish the note's current revision, or publish it
+/// again after edits. The page keeps its slug unless a new one is given.
+#[tracing::instrument(name = "publishing.publish", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn publish_note(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+    payload: Option<Json<PublishRequest>>,
+) -> AppResult<impl IntoResponse> {
+    permissions::require_role(&pool, note_id, user.id, Role::Owner).await?;
+    let Json(payload) = payload.unwrap_or_default();
+    if let Some(slug) = &payload.slug {
+        validate_slug(slug)?;
+    }
+
+    let current = monitoring::timed(
+        "get_note_publication_slug",
+        sqlx::query!(
+            r#"
+            SELECT n.title, p.slug AS "slug?"
+            FROM notes n LEFT JOIN published_notes p ON p.note_id = n.id
+            WHERE n.id = $1
+            "#,
+            note_id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get publication slug error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    let slug = payload
+        .slug
+        .or(current.slug)
+        .unwrap_or_else(|| generate_slug(&current.title));
+
+    // Snapshot the note in the same statement, so title, body and revision always match
+    let publication = monitoring::timed(
+        "publish_note",
+        sqlx::query_as!(
+            Publication,
+            r#"
+            INSERT INTO published_notes
+                (note_id, slug, title, body, revision, note_updated_at, published_by)
+            SELECT id, $2, title, body, revision, updated_at, $3 FROM notes WHERE id = $1
+            ON CONFLICT (note_id) DO UPDATE
+            SET slug = EXCLUDED.slug, title = EXCLUDED.title, body = EXCLUDED.body,
+                revision = EXCLUDED.revision, note_updated_at = EXCLUDED.note_updated_at,
+                published_by = EXCLUDED.published_by, published_at = NOW(), unpublished_at = NULL
+            RETURNING note_id, slug, revision, note_updated_at, published_by, published_at, unpublished_at
+            "#,
+            note_id,
+            slug,
+            user.id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Publish note error: {:?}", e);
+        match e.as_database_error() {
+            Some(db) if db.is_unique_violation() => {
+                AppError::Conflict("Slug already in use".into())
+            }
+            _ => AppError::InternalServerError,
+        }
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    tracing::info!(%note_id, slug = %publication.slug, revision = publication.revision, "Note published");
+    Ok((
+        StatusCode::OK,
+        AxumJson(PublicationResponse::from(publication)),
+    ))
+}
+
+/// GET /api/notes/{note_id}/publication: where and at which revision the note is (or was)
+/// published; 404 if it never was.
+#[tracing::instrument(name = "publishing.get", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn get_publication(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    permissions::require_role(&pool, note_id, user.id, Role::Viewer).await?;
+    let publication = monitoring::timed(
+        "get_publication",
+        sqlx::query_as!(
+            Publication,
+            r#"
+            SELECT note_id, slug, revision, note_updated_at, published_by, published_at, unpublished_at
+            FROM published_notes WHERE note_id = $1
+            "#,
+            note_id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get publication error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    Ok((
+        StatusCode::OK,
+        AxumJson(PublicationResponse::from(publication)),
+    ))
+}
+
+/// DELETE /api/notes/{note_id}/publication: take the public page down. The slug stays
+/// reserved for the note.
+#[tracing::instrument(name = "publishing.unpublish", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn unpublish_note(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    permissions::require_role(&pool, note_id, user.id, Role::Owner).await?;
+    let unpublished = monitoring::timed(
+        "unpublish_note",
+        sqlx::query!(
+            r#"
+            UPDATE published_notes SET unpublished_at = NOW()
+            WHERE note_id = $1 AND unpublished_at IS NULL
+            "#,
+            note_id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Unpublish note error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    if !unpublished {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(%note_id, "Note unpublished");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Markdown to sanitized HTML.
+fn render_markdown(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(markdown, options));
+    SANITIZER.clean(&html).to_string()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render_page(title: &str, body: &str, updated_at: DateTime<Utc>) -> String {
+    let title = escape_html(title);
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body {{ max-width: 46rem; margin: 2rem auto; padding: 0 1rem; font: 16px/1.6 system-ui, sans-serif; color: #1f2328; }}
+pre, code {{ font-family: ui-monospace, monospace; background: #f6f8fa; }}
+pre {{ padding: 1rem; overflow-x: auto; }}
+table {{ border-collapse: collapse; }}
+th, td {{ border: 1px solid #d0d7de; padding: .3rem .6rem; }}
+img {{ max-width: 100%; }}
+footer {{ margin-top: 3rem; color: #656d76; font-size: .875rem; }}
+</style>
+</head>
+<body>
+<article>
+<h1>{title}</h1>
+{body}
+</article>
+<footer>Last updated {updated}</footer>
+</body>
+</html>
+"#,
+        updated = updated_at.format("%B %-d, %Y"),
+    )
+}
+
+/// Whether the request's validators show the client already has this version of the page.
+fn is_fresh(headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since (RFC 9110, section 13.1.3)
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        });
+    }
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .is_some_and(|since| last_modified <= since)
+}
+
+/// GET /p/{slug}: a published note as an HTML page, with `ETag` and `Last-Modified` taken
+/// from the published revision's `updated_at`. Answers `304 Not Modified` to matching
+/// conditional requests.
+#[tracing::instrument(name = "publishing.page", skip_all, fields(%slug))]
+pub async fn published_page(
+    Extension(pool): Extension<PgPool>,
+    Path(slug): Path<String>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let page = monitoring::timed(
+        "get_published_note",
+        sqlx::query!(
+            r#"
+            SELECT p.title, p.body, p.note_updated_at
+            FROM published_notes p JOIN notes n ON n.id = p.note_id
+            WHERE p.slug = $1 AND p.unpublished_at IS NULL AND n.deleted_at IS NULL
+            "#,
+            slug
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get published note error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    // HTTP dates have whole seconds
+    let last_modified = page.note_updated_at.trunc_subsecs(0);
+    let etag = format!(
+        "\"{}-{:x}\"",
+        RENDER_VERSION,
+        page.note_updated_at.timestamp_micros()
+    );
+    let validators = [
+        (header::ETAG, etag.clone()),
+        (
+            header::LAST_MODIFIED,
+            last_modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        ),
+        (header::CACHE_CONTROL, PAGE_CACHE_CONTROL.clone()),
+    ];
+    if is_fresh(&headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+    }
+
+    let html = render_page(&page.title, &render_markdown(&page.body), last_modified);
+    let mut response = (StatusCode::OK, validators, html).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    response_headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(PAGE_CSP),
+    );
+    response_headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn slugs_are_lowercase_words_with_inner_hyphens() {
+        for slug in ["abc", "deploy-runbook", "v2-notes"] {
+            assert!(validate_slug(slug).is_ok(), "{}", slug);
+        }
+        for slug in [
+            "ab",
+            "Deploy",
+            "-abc",
+            "abc-",
+            "a b c",
+            "über",
+            &"a".repeat(81),
+        ] {
+            assert!(validate_slug(slug).is_err(), "{}", slug);
+        }
+    }
+
+    #[test]
+    fn generated_slugs_come_from_the_title() {
+        let slug = generate_slug("  Deploy Runbook: v2!  ");
+        assert!(slug.starts_with("deploy-runbook-v2-"), "{}", slug);
+        assert_eq!(slug.len(), "deploy-runbook-v2-".len() + 6);
+        assert!(validate_slug(&slug).is_ok());
+
+        assert!(generate_slug("¿¿??").starts_with("note-"));
+        let long = generate_slug(&"word ".repeat(40));
+        assert!(long.len() <= MAX_SLUG_LEN);
+        assert!(validate_slug(&long).is_ok(), "{}", long);
+    }
+
+    #[test]
+    fn conditional_requests_match_etag_first() {
+        let etag = "\"1-abc\"";
+        let modified = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let headers = |pairs: &[(header::HeaderName, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in pairs {
+                map.insert(name, HeaderValue::from_str(value).unwrap());
+            }
+            map
+        };
+
+        assert!(!is_fresh(&HeaderMap::new(), etag, modified));
+        assert!(is_fresh(
+            &headers(&[(header::IF_NONE_MATCH, "\"0-x\", W/\"1-abc\"")]),
+            etag,
+            modified
+        ));
+        assert!(is_fresh(
+            &headers(&[(header::IF_NONE_MATCH, "*")]),
+            etag,
+            modified
+        ));
+        // A mismatched ETag wins over a matching date
+        assert!(!is_fresh(
+            &headers(&[
+                (header::IF_NONE_MATCH, "\"0-x\""),
+                (header::IF_MODIFIED_SINCE, "Wed, 01 May 2024 12:00:00 GMT"),
+            ]),
+            etag,
+            modified
+        ));
+        assert!(is_fresh(
+            &headers(&[(header::IF_MODIFIED_SINCE, "Wed, 01 May 2024 12:00:00 GMT")]),
+            etag,
+            modified
+        ));
+        assert!(!is_fresh(
+            &headers(&[(header::IF_MODIFIED_SINCE, "Wed, 01 May 2024 11:59:59 GMT")]),
+            etag,
+            modified
+        ));
+    }
+}
@@ -0,0 +1,380 @@
+use crate::{access_tokens, errors::AppError, redis_store::RedisStore, utils};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use redis::{Client as RedisClient, Script};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// In-memory buckets are pruned once the map grows past this many keys.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// Atomic token bucket. KEYS[1] = bucket; ARGV = capacity, refill per ms, now (ms), ttl (ms).
+/// Returns {allowed (0/1), remaining tokens as string}.
+const TOKEN_BUCKET_LUA: &str = r#"
+local capacity = tonumber(ARGV[1])
+local refill_per_ms = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or capacity
+local ts = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * refill_per_ms)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+redis.call('PEXPIRE', KEYS[1], ARGV[4])
+return {allowed, tostring(tokens)}
+"#;
+
+/// What a bucket is keyed by.
+#[derive(Debug, Clone, Copy)]
+pub enum KeyBy {
+    /// Client IP address
+    Ip,
+    /// Authenticated user ID, falling back to the client IP for anonymous requests
+    UserOrIp,
+}
+
+/// Per-route token bucket policy: `capacity` requests of burst, refilled evenly over `period`.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub name: &'static str,
+    pub capacity: u32,
+    pub period: Duration,
+    pub key_by: KeyBy,
+}
+
+impl Policy {
+    /// Sign-ins and password reset confirmations: 5 per minute per IP
+    pub const LOGIN: Policy = Policy {
+        name: "login",
+        capacity: 5,
+        period: Duration::from_secs(60),
+        key_by: KeyBy::Ip,
+    };
+
+    /// Refresh token exchange: 30 per minute per IP
+    pub const TOKEN_REFRESH: Policy = Policy {
+        name: "token_refresh",
+        capacity: 30,
+        period: Duration::from_secs(60),
+        key_by: KeyBy::Ip,
+    };
+
+    /// Account creation: 10 per hour per IP
+    pub const SIGNUP: Policy = Policy {
+        name: "signup",
+        capacity: 10,
+        period: Duration::from_secs(3600),
+        key_by: KeyBy::Ip,
+    };
+
+    /// Password reset emails: 5 per hour per IP
+    pub const PASSWORD_RESET: Policy = Policy {
+        name: "password_reset",
+        capacity: 5,
+        period: Duration::from_secs(3600),
+        key_by: KeyBy::Ip,
+    };
+
+    /// Verification email re-sends: 5 per hour per user
+    pub const EMAIL_VERIFICATION: Policy = Policy {
+        name: "email_verification",
+        capacity: 5,
+        period: Duration::from_secs(3600),
+        key_by: KeyBy::UserOrIp,
+    };
+
+    /// Two-factor code checks on enrollment endpoints: 10 per 15 minutes per user
+    pub const MFA_VERIFY: Policy = Policy {
+        name: "mfa_verify",
+        capacity: 10,
+        period: Duration::from_secs(900),
+        key_by: KeyBy::UserOrIp,
+    };
+
+    /// Profile, password and access token changes: 10 per 15 minutes per user
+    pub const ACCOUNT_UPDATE: Policy = Policy {
+        name: "account_update",
+        capacity: 10,
+        period: Duration::from_secs(900),
+        key_by: KeyBy::UserOrIp,
+    };
+
+    /// Note sharing and grant changes: 20 per hour per user
+    pub const NOTE_SHARE: Policy = Policy {
+        name: "note_share",
+        capacity: 20,
+        period: Duration::from_secs(3600),
+        key_by: KeyBy::UserOrIp,
+    };
+
+    /// Workspace invitations: 20 per hour per user
+    pub const WORKSPACE_INVITE: Policy = Policy {
+        name: "workspace_invite",
+        capacity: 20,
+        period: Duration::from_secs(3600),
+        key_by: KeyBy::UserOrIp,
+    };
+
+    /// Anonymous share link opens: 30 per minute per IP, to slow down password guessing
+    pub const SHARE_LINK_OPEN: Policy = Policy {
+        name: "share_link_open",
+        capacity: 30,
+        period: Duration::from_secs(60),
+        key_by: KeyBy::Ip,
+    };
+
+    /// Note, folder and workspace writes: 60 per minute per user
+    pub const NOTE_WRITE: Policy = Policy {
+        name: "note_write",
+        capacity: 60,
+        period: Duration::from_secs(60),
+        key_by: KeyBy::UserOrIp,
+    };
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// Outcome of taking a token from a bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    /// Tokens left after this request (fractional while refilling)
+    pub remaining: f64,
+    refill_per_sec: f64,
+}
+
+impl Decision {
+    /// Seconds until one token is available again (0 when allowed).
+    pub fn retry_after_secs(&self) -> u64 {
+        if self.allowed {
+            return 0;
+        }
+        ((1.0 - self.remaining) / self.refill_per_sec)
+            .ceil()
+            .max(1.0) as u64
+    }
+
+    /// Seconds until the bucket is full again.
+    pub fn reset_secs(&self) -> u64 {
+        ((self.limit as f64 - self.remaining) / self.refill_per_sec)
+            .ceil()
+            .max(0.0) as u64
+    }
+
+    fn apply_headers(&self, headers: &mut HeaderMap) {
+        let values = [
+            ("x-ratelimit-limit", self.limit as u64),
+            (
+                "x-ratelimit-remaining",
+                self.remaining.floor().max(0.0) as u64,
+            ),
+            ("x-ratelimit-reset", self.reset_secs()),
+        ];
+        for (name, value) in values {
+            headers.insert(name, HeaderValue::from(value));
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// One period of its own policy after the last request, when it would be full again
+    expires: Instant,
+}
+
+/// Token bucket rate limiter backed by Redis, with a per-process in-memory fallback
+/// used whenever Redis is unavailable.
+#[derive(Clone)]
+pub struct RateLimiter {
+    redis: RedisStore,
+    script: Arc<Script>,
+    memory: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(redis: Option<RedisClient>) -> Self {
+        Self {
+            redis: RedisStore::new("Rate limiter", redis),
+            script: Arc::new(Script::new(TOKEN_BUCKET_LUA)),
+            memory: Arc::default(),
+        }
+    }
+
+    /// Take one token for `key` under `policy`.
+    pub async fn check(&self, policy: &Policy, key: &str) -> Decision {
+        let bucket_key = format!("ratelimit:{}:{}", policy.name, key);
+        let remaining = match self.check_redis(policy, &bucket_key).await {
+            Some(result) => result,
+            None => self.check_memory(policy, &bucket_key),
+        };
+        Decision {
+            allowed: remaining.0,
+            limit: policy.capacity,
+            remaining: remaining.1,
+            refill_per_sec: policy.refill_per_sec(),
+        }
+    }
+
+    async fn check_redis(&self, policy: &Policy, bucket_key: &str) -> Option<(bool, f64)> {
+        let mut conn = self.redis.connection().await?;
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let refill_per_ms = policy.refill_per_sec() / 1000.0;
+        // Keep idle buckets around for one full period, after which they would be full anyway
+        let ttl_ms = policy.period.as_millis() as u64;
+
+        let mut invocation = self.script.key(bucket_key);
+        invocation
+            .arg(policy.capacity)
+            .arg(refill_per_ms)
+            .arg(now_ms)
+            .arg(ttl_ms);
+        let (allowed, remaining) = self
+            .redis
+            .run(invocation.invoke_async::<(i64, String)>(&mut conn))
+            .await?;
+        Some((allowed == 1, remaining.parse().unwrap_or(0.0)))
+    }
+
+    fn check_memory(&self, policy: &Policy, bucket_key: &str) -> (bool, f64) {
+        let now = Instant::now();
+        let capacity = policy.capacity as f64;
+        let mut buckets = self.memory.lock().unwrap();
+
+        if buckets.len() >= MAX_MEMORY_BUCKETS {
+            buckets.retain(|_, b| b.expires > now);
+        }
+
+        let bucket = buckets.entry(bucket_key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            expires: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * policy.refill_per_sec()).min(capacity);
+        bucket.updated = now;
+        bucket.expires = now + policy.period;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        (allowed, bucket.tokens)
+    }
+}
+
+/// Route middleware enforcing `policy`. Attach with
+/// `middleware::from_fn_with_state(Policy::LOGIN, rate_limit::enforce)`; expects a
+/// `RateLimiter` extension. Sets `X-RateLimit-*` headers and answers 429 with `Retry-After`.
+pub async fn enforce(State(policy): State<Policy>, req: Request, next: Next) -> Response {
+    let Some(limiter) = req.extensions().get::<RateLimiter>().cloned() else {
+        tracing::error!(
+            "RateLimiter extension missing; not enforcing {}",
+            policy.name
+        );
+        return next.run(req).await;
+    };
+
+    let ip = utils::client_ip(req.headers(), req.extensions());
+    let key = match policy.key_by {
+        KeyBy::Ip => format!("ip:{}", ip),
+        KeyBy::UserOrIp => match utils::bearer_claims(req.headers()) {
+            Some(claims) => format!("user:{}", claims.sub),
+            // Personal access tokens get a bucket each, without a database lookup here
+            None => match utils::bearer_token(req.headers())
+                .filter(|t| access_tokens::is_access_token(t))
+            {
+                Some(token) => format!("pat:{}", &utils::hash_token(token)[..16]),
+                None => format!("ip:{}", ip),
+            },
+        },
+    };
+
+    let decision = limiter.check(&policy, &key).await;
+    let mut response = if decision.allowed {
+        next.run(req).await
+    } else {
+        tracing::warn!(policy = policy.name, %key, "Rate limit exceeded");
+        AppError::TooManyRequests(decision.retry_after_secs()).into_response()
+    };
+    decision.apply_headers(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST: Policy = Policy {
+        name: "test",
+        capacity: 2,
+        period: Duration::from_secs(60),
+        key_by: KeyBy::Ip,
+    };
+
+    #[tokio::test]
+    async fn bucket_allows_its_capacity_then_refuses() {
+        let limiter = RateLimiter::new(None);
+        let first = limiter.check(&TEST, "a").await;
+        assert!(first.allowed);
+        assert_eq!(first.limit, 2);
+        assert!((first.remaining - 1.0).abs() < 0.01);
+        assert!(limiter.check(&TEST, "a").await.allowed);
+
+        let refused = limiter.check(&TEST, "a").await;
+        assert!(!refused.allowed);
+        // One token per 30 seconds
+        assert_eq!(refused.retry_after_secs(), 30);
+        assert_eq!(refused.reset_secs(), 60);
+    }
+
+    #[tokio::test]
+    async fn buckets_are_kept_per_key_and_policy() {
+        let limiter = RateLimiter::new(None);
+        let other = Policy {
+            name: "other",
+            ..TEST
+        };
+        for _ in 0..2 {
+            limiter.check(&TEST, "a").await;
+        }
+        assert!(!limiter.check(&TEST, "a").await.allowed);
+        assert!(limiter.check(&TEST, "b").await.allowed);
+        assert!(limiter.check(&other, "a").await.allowed);
+    }
+
+    #[test]
+    fn allowed_decisions_have_no_retry_delay() {
+        let decision = Decision {
+            allowed: true,
+            limit: 60,
+            remaining: 59.0,
+            refill_per_sec: 1.0,
+        };
+        assert_eq!(decision.retry_after_secs(), 0);
+        assert_eq!(decision.reset_secs(), 1);
+
+        let mut headers = HeaderMap::new();
+        decision.apply_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-limit"], "60");
+        assert_eq!(headers["x-ratelimit-remaining"], "59");
+        assert_eq!(headers["x-ratelimit-reset"], "1");
+    }
+}
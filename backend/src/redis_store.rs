@@ -0,0 +1,92 @@
+use redis::{aio::MultiplexedConnection, Client as RedisClient};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How long to stop trying Redis after it failed, serving from memory meanwhile.
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+/// Upper bound for connecting to / querying Redis before falling back.
+const TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct State {
+    conn: Option<MultiplexedConnection>,
+    retry_at: Option<Instant>,
+}
+
+/// Lazily connected, shared Redis connection for features that degrade to per-process
+/// memory when Redis is unavailable. After a failure Redis is skipped for a while
+/// instead of adding a timeout to every request.
+#[derive(Clone)]
+pub struct RedisStore {
+    /// Feature name used in log messages
+    name: &'static str,
+    client: Option<RedisClient>,
+    state: Arc<tokio::sync::Mutex<State>>,
+}
+
+impl RedisStore {
+    pub fn new(name: &'static str, client: Option<RedisClient>) -> Self {
+        Self {
+            name,
+            client,
+            state: Arc::default(),
+        }
+    }
+
+    /// Current connection, or `None` while Redis is unconfigured or backing off.
+    pub async fn connection(&self) -> Option<MultiplexedConnection> {
+        let client = self.client.as_ref()?;
+        let mut state = self.state.lock().await;
+        if let Some(conn) = &state.conn {
+            return Some(conn.clone());
+        }
+        if state.retry_at.is_some_and(|at| Instant::now() < at) {
+            return None;
+        }
+        match tokio::time::timeout(TIMEOUT, client.get_multiplexed_async_connection()).await {
+            Ok(Ok(conn)) => {
+                state.conn = Some(conn.clone());
+                state.retry_at = None;
+                Some(conn)
+            }
+            Ok(Err(err)) => {
+                tracing::warn!("{} Redis connect error, using memory: {:?}", self.name, err);
+                state.retry_at = Some(Instant::now() + RETRY_AFTER);
+                None
+            }
+            Err(_) => {
+                tracing::warn!("{} Redis connect timed out, using memory", self.name);
+                state.retry_at = Some(Instant::now() + RETRY_AFTER);
+                None
+            }
+        }
+    }
+
+    /// Run a Redis command with [`TIMEOUT`]. Errors are logged and put Redis into backoff.
+    pub async fn run<T, F>(&self, fut: F) -> Option<T>
+    where
+        F: std::future::Future<Output = redis::RedisResult<T>>,
+    {
+        match tokio::time::timeout(TIMEOUT, fut).await {
+            Ok(Ok(value)) => Some(value),
+            Ok(Err(err)) => {
+                tracing::warn!("{} Redis error, using memory: {:?}", self.name, err);
+                self.mark_down().await;
+                None
+            }
+            Err(_) => {
+                tracing::warn!("{} Redis timed out, using memory", self.name);
+                self.mark_down().await;
+                None
+            }
+        }
+    }
+
+    async fn mark_down(&self) {
+        let mut state = self.state.lock().await;
+        state.conn = None;
+        state.retry_at = Some(Instant::now() + RETRY_AFTER);
+    }
+}
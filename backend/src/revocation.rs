@@ -0,0 +1,142 @@
+use crate::{models::Claims, redis_store::RedisStore};
+use chrono::Utc;
+use redis::{AsyncCommands, Client as RedisClient};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+/// In-memory entries are pruned once the map grows past this many keys.
+const MAX_MEMORY_ENTRIES: usize = 10_000;
+
+struct Entry {
+    value: i64,
+    /// Unix seconds after which the entry no longer matters
+    expires_at: i64,
+}
+
+/// Denylist of revoked access tokens, checked by the `SessionUser` extractor.
+///
+/// Three kinds of entries are kept, each expiring once the tokens they cover would have
+/// expired anyway:
+/// - `denylist:jti:{jti}`: a single token, revoked by logout
+/// - `denylist:session:{sid}`: all tokens of a revoked session
+/// - `denylist:user:{user_id}`: Unix time before which all of a user's tokens are revoked
+///   (logout everywhere)
+///
+/// Entries live in Redis so every instance sees them, and are mirrored in per-process
+/// memory so revocations made here still apply while Redis is unavailable.
+#[derive(Clone)]
+pub struct TokenDenylist {
+    redis: RedisStore,
+    memory: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl TokenDenylist {
+    pub fn new(redis: Option<RedisClient>) -> Self {
+        Self {
+            redis: RedisStore::new("Token denylist", redis),
+            memory: Arc::default(),
+        }
+    }
+
+    /// Revoke a single access token until its expiry.
+    pub async fn revoke_token(&self, jti: Uuid, expires_at: i64) {
+        let now = Utc::now().timestamp();
+        self.insert(
+            format!("denylist:jti:{}", jti),
+            expires_at,
+            expires_at - now,
+        )
+        .await;
+    }
+
+    /// Revoke every access token issued for a session. `max_token_ttl` is the longest
+    /// lifetime such a token can have.
+    pub async fn revoke_session(&self, session_id: Uuid, max_token_ttl: i64) {
+        let now = Utc::now().timestamp();
+        self.insert(
+            format!("denylist:session:{}", session_id),
+            now,
+            max_token_ttl,
+        )
+        .await;
+    }
+
+    /// Revoke every access token issued to `user_id` up to now. `max_token_ttl` is the
+    /// longest lifetime such a token can have.
+    pub async fn revoke_user_tokens(&self, user_id: Uuid, max_token_ttl: i64) {
+        let now = Utc::now().timestamp();
+        self.insert(format!("denylist:user:{}", user_id), now, max_token_ttl)
+            .await;
+    }
+
+    /// Whether the token described by `claims` has been revoked.
+    pub async fn is_revoked(&self, claims: &Claims) -> bool {
+        let keys = [
+            format!("denylist:jti:{}", claims.jti),
+            format!("denylist:session:{}", claims.sid),
+            format!("denylist:user:{}", claims.sub),
+        ];
+        // A cutoff in the same second as `iat` revokes the token too
+        let revoked = |(jti, session, cutoff): (Option<i64>, Option<i64>, Option<i64>)| {
+            jti.is_some() || session.is_some() || cutoff.is_some_and(|cutoff| claims.iat <= cutoff)
+        };
+
+        if revoked(self.get_memory(&keys)) {
+            return true;
+        }
+        let Some(mut conn) = self.redis.connection().await else {
+            return false;
+        };
+        self.redis
+            .run(conn.mget::<_, (Option<i64>, Option<i64>, Option<i64>)>(&keys))
+            .await
+            .is_some_and(revoked)
+    }
+
+    async fn insert(&self, key: String, value: i64, ttl_secs: i64) {
+        if ttl_secs <= 0 {
+            return;
+        }
+        let now = Utc::now().timestamp();
+        {
+            let mut memory = self.memory.lock().unwrap();
+            if memory.len() >= MAX_MEMORY_ENTRIES {
+                memory.retain(|_, entry| entry.expires_at > now);
+            }
+            memory.insert(
+                key.clone(),
+                Entry {
+                    value,
+                    expires_at: now + ttl_secs,
+                },
+            );
+        }
+
+        let stored = match self.redis.connection().await {
+            Some(mut conn) => self
+                .redis
+                .run(conn.set_ex::<_, _, ()>(&key, value, ttl_secs as u64))
+                .await
+                .is_some(),
+            None => false,
+        };
+        if !stored {
+            tracing::warn!(%key, "Revocation only stored in memory; other instances won't see it");
+        }
+    }
+
+    fn get_memory(&self, keys: &[String; 3]) -> (Option<i64>, Option<i64>, Option<i64>) {
+        let now = Utc::now().timestamp();
+        let memory = self.memory.lock().unwrap();
+        let get = |key: &str| {
+            memory
+                .get(key)
+                .filter(|entry| entry.expires_at > now)
+                .map(|entry| entry.value)
+        };
+        (get(&keys[0]), get(&keys[1]), get(&keys[2]))
+    }
+}
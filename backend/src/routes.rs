@@ -1,6 +1,12 @@
-use crate::{auth, db, ws};
+use crate::{
+    access_tokens, auth, db, email_verification, folders, jwt_keys, mfa, monitoring, note_states,
+    oidc, password_reset, permissions, preferences, publishing,
+    rate_limit::{self, Policy},
+    share_links, telemetry, trash, users, workspaces, ws,
+};
 use axum::{
-    routing::{get, post, put, delete},
+    middleware,
+    routing::{delete, get, patch, post, put},
     Extension, Router,
 };
 use tokio::sync::broadcast;
@@ -14,19 +20,338 @@ pub fn create_routes() -> Router {
 
     Router::new()
         // Auth endpoints
-        .route("/api/signup", post(auth::signup))
-        .route("/api/login", post(auth::login))
+        .route(
+            "/api/signup",
+            post(auth::signup).layer(middleware::from_fn_with_state(
+                Policy::SIGNUP,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/login",
+            post(auth::login).layer(middleware::from_fn_with_state(
+                Policy::LOGIN,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/login/mfa",
+            post(auth::login_mfa).layer(middleware::from_fn_with_state(
+                Policy::LOGIN,
+                rate_limit::enforce,
+            )),
+        )
+        // Single sign-on (404 unless OIDC_ISSUER_URL is set)
+        .route(
+            "/api/auth/oidc/login",
+            get(oidc::login).layer(middleware::from_fn_with_state(
+                Policy::LOGIN,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/auth/oidc/callback",
+            get(oidc::callback).layer(middleware::from_fn_with_state(
+                Policy::LOGIN,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/token/refresh",
+            post(auth::refresh).layer(middleware::from_fn_with_state(
+                Policy::TOKEN_REFRESH,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/password-reset/request",
+            post(password_reset::request_reset).layer(middleware::from_fn_with_state(
+                Policy::PASSWORD_RESET,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/password-reset/confirm",
+            post(password_reset::confirm_reset).layer(middleware::from_fn_with_state(
+                Policy::LOGIN,
+                rate_limit::enforce,
+            )),
+        )
+        .route("/api/email/verify", post(email_verification::verify_email))
+        .route(
+            "/api/users/me/email/verification",
+            post(email_verification::resend_verification).layer(middleware::from_fn_with_state(
+                Policy::EMAIL_VERIFICATION,
+                rate_limit::enforce,
+            )),
+        )
+        .route("/api/logout", post(auth::logout))
+        .route("/api/logout/all", post(auth::logout_all))
+        // Public keys for verifying access tokens in other services
+        .route("/.well-known/jwks.json", get(jwt_keys::jwks_handler))
+        .route("/api/users/me/logins", get(auth::list_logins))
+        .route("/api/users/me/sessions", get(auth::list_sessions))
+        .route(
+            "/api/users/me/sessions/{session_id}",
+            delete(auth::delete_session),
+        )
+        // Profile and account management
+        .route(
+            "/api/users/{user_id}",
+            get(users::get_profile).merge(
+                put(users::update_profile)
+                    .layer(middleware::from_fn_with_state(
+                        Policy::ACCOUNT_UPDATE,
+                        rate_limit::enforce,
+                    ))
+                    .delete(users::delete_account),
+            ),
+        )
+        .route(
+            "/api/users/{user_id}/password",
+            put(users::change_password).layer(middleware::from_fn_with_state(
+                Policy::ACCOUNT_UPDATE,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/users/me/preferences",
+            get(preferences::get_preferences).patch(preferences::update_preferences),
+        )
+        // Personal access tokens
+        .route(
+            "/api/users/me/tokens",
+            get(access_tokens::list_tokens).merge(post(access_tokens::create_token).layer(
+                middleware::from_fn_with_state(Policy::ACCOUNT_UPDATE, rate_limit::enforce),
+            )),
+        )
+        .route(
+            "/api/users/me/tokens/{token_id}",
+            delete(access_tokens::revoke_token),
+        )
+        // Two-factor authentication
+        .route("/api/users/me/mfa", get(mfa::status))
+        .route(
+            "/api/users/me/mfa/totp",
+            post(mfa::enroll_totp).merge(delete(mfa::disable_totp).layer(
+                middleware::from_fn_with_state(Policy::MFA_VERIFY, rate_limit::enforce),
+            )),
+        )
+        .route(
+            "/api/users/me/mfa/totp/confirm",
+            post(mfa::confirm_totp).layer(middleware::from_fn_with_state(
+                Policy::MFA_VERIFY,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/users/me/mfa/recovery-codes",
+            post(mfa::regenerate_recovery_codes).layer(middleware::from_fn_with_state(
+                Policy::MFA_VERIFY,
+                rate_limit::enforce,
+            )),
+        )
         // Notes CRUD
         .route("/api/users/{user_id}/notes", get(db::list_notes))
-        .route("/api/notes", post(db::create_note))
+        .route(
+            "/api/notes",
+            post(db::create_note).layer(middleware::from_fn_with_state(
+                Policy::NOTE_WRITE,
+                rate_limit::enforce,
+            )),
+        )
         .route(
             "/api/notes/{note_id}",
-            get(db::get_note)
-                .put(db::update_note)
-                .delete(db::delete_note),
+            get(db::get_note).merge(put(db::update_note).delete(db::delete_note).layer(
+                middleware::from_fn_with_state(Policy::NOTE_WRITE, rate_limit::enforce),
+            )),
+        )
+        .route("/api/notes/{note_id}/revisions", get(db::list_revisions))
+        .route(
+            "/api/notes/{note_id}/state",
+            patch(note_states::update_note_state).layer(middleware::from_fn_with_state(
+                Policy::NOTE_WRITE,
+                rate_limit::enforce,
+            )),
+        )
+        // Trash
+        .route("/api/trash", get(trash::list_trash))
+        .route(
+            "/api/trash/{note_id}",
+            delete(trash::delete_note_permanently).layer(middleware::from_fn_with_state(
+                Policy::NOTE_WRITE,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/trash/{note_id}/restore",
+            post(trash::restore_note).layer(middleware::from_fn_with_state(
+                Policy::NOTE_WRITE,
+                rate_limit::enforce,
+            )),
+        )
+        // Note sharing
+        .route(
+            "/api/notes/{note_id}/share",
+            post(permissions::share_note).layer(middleware::from_fn_with_state(
+                Policy::NOTE_SHARE,
+                rate_limit::enforce,
+            )),
         )
+        .route(
+            "/api/notes/{note_id}/permissions",
+            get(permissions::list_permissions),
+        )
+        .route(
+            "/api/notes/{note_id}/permissions/{user_id}",
+            put(permissions::update_permission)
+                .layer(middleware::from_fn_with_state(
+                    Policy::NOTE_SHARE,
+                    rate_limit::enforce,
+                ))
+                .delete(permissions::revoke_permission),
+        )
+        // Workspaces
+        .route(
+            "/api/workspaces",
+            get(workspaces::list_workspaces).merge(post(workspaces::create_workspace).layer(
+                middleware::from_fn_with_state(Policy::NOTE_WRITE, rate_limit::enforce),
+            )),
+        )
+        .route(
+            "/api/workspaces/invitations/accept",
+            post(workspaces::accept_invitation),
+        )
+        .route(
+            "/api/workspaces/{workspace_id}",
+            get(workspaces::get_workspace).merge(
+                put(workspaces::rename_workspace)
+                    .delete(workspaces::delete_workspace)
+                    .layer(middleware::from_fn_with_state(
+                        Policy::NOTE_WRITE,
+                        rate_limit::enforce,
+                    )),
+            ),
+        )
+        .route(
+            "/api/workspaces/{workspace_id}/restore",
+            post(workspaces::restore_workspace).layer(middleware::from_fn_with_state(
+                Policy::NOTE_WRITE,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/workspaces/{workspace_id}/notes",
+            get(db::list_workspace_notes),
+        )
+        // Folders
+        .route(
+            "/api/workspaces/{workspace_id}/folders",
+            get(folders::list_folders).merge(post(folders::create_folder).layer(
+                middleware::from_fn_with_state(Policy::NOTE_WRITE, rate_limit::enforce),
+            )),
+        )
+        .route(
+            "/api/folders/{folder_id}",
+            get(folders::get_folder).merge(
+                put(folders::rename_folder)
+                    .delete(folders::delete_folder)
+                    .layer(middleware::from_fn_with_state(
+                        Policy::NOTE_WRITE,
+                        rate_limit::enforce,
+                    )),
+            ),
+        )
+        .route(
+            "/api/folders/{folder_id}/move",
+            post(folders::move_folder).layer(middleware::from_fn_with_state(
+                Policy::NOTE_WRITE,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/folders/{folder_id}/share",
+            post(folders::share_folder).layer(middleware::from_fn_with_state(
+                Policy::NOTE_SHARE,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/folders/{folder_id}/permissions",
+            get(folders::list_folder_permissions),
+        )
+        .route(
+            "/api/folders/{folder_id}/permissions/{user_id}",
+            delete(folders::revoke_folder_permission),
+        )
+        .route(
+            "/api/notes/{note_id}/folder",
+            put(folders::move_note).layer(middleware::from_fn_with_state(
+                Policy::NOTE_WRITE,
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/api/workspaces/{workspace_id}/members",
+            get(workspaces::list_members),
+        )
+        .route(
+            "/api/workspaces/{workspace_id}/members/{user_id}",
+            put(workspaces::update_member).delete(workspaces::remove_member),
+        )
+        .route(
+            "/api/workspaces/{workspace_id}/invitations",
+            get(workspaces::list_invitations).merge(post(workspaces::create_invitation).layer(
+                middleware::from_fn_with_state(Policy::WORKSPACE_INVITE, rate_limit::enforce),
+            )),
+        )
+        .route(
+            "/api/workspaces/{workspace_id}/invitations/{invitation_id}",
+            delete(workspaces::revoke_invitation),
+        )
+        // Anonymous share links
+        .route(
+            "/api/notes/{note_id}/links",
+            get(share_links::list_links).merge(post(share_links::create_link).layer(
+                middleware::from_fn_with_state(Policy::NOTE_SHARE, rate_limit::enforce),
+            )),
+        )
+        .route(
+            "/api/notes/{note_id}/links/{link_id}",
+            delete(share_links::revoke_link),
+        )
+        .route(
+            "/api/shared/{token}",
+            get(share_links::open_link).layer(middleware::from_fn_with_state(
+                Policy::SHARE_LINK_OPEN,
+                rate_limit::enforce,
+            )),
+        )
+        // Published pages
+        .route(
+            "/api/notes/{note_id}/publication",
+            get(publishing::get_publication).merge(
+                put(publishing::publish_note)
+                    .delete(publishing::unpublish_note)
+                    .layer(middleware::from_fn_with_state(
+                        Policy::NOTE_WRITE,
+                        rate_limit::enforce,
+                    )),
+            ),
+        )
+        .route("/p/{slug}", get(publishing::published_page))
         // WebSocket for collaborative sync
         .route("/api/notes/{note_id}/ws", get(ws::note_ws))
+        // Per-route request count/latency; Router::layer runs after routing so MatchedPath is set
+        .layer(middleware::from_fn(monitoring::track_http))
+        // Outermost: assign X-Request-Id and open the per-request span
+        .layer(middleware::from_fn(telemetry::request_context))
         // Layer broadcast sender so ws handler can access it via Extension
         .layer(Extension(tx))
 }
+
+/// Prometheus scrape endpoint, served on its own listener (`METRICS_ADDR`) so it stays off
+/// the public port.
+pub fn metrics_routes() -> Router {
+    Router::new().route("/metrics", get(monitoring::metrics_handler))
+}
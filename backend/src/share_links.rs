@@ -0,0 +1,317 @@
+use crate::{
+    auth::SessionUser,
+    errors::{AppError, AppResult},
+    models::ShareLink,
+    monitoring,
+    permissions::{self, Role},
+    utils,
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Header carrying the password of a password-protected link.
+const PASSWORD_HEADER: &str = "x-share-password";
+
+/// Characters of a token kept in `token_prefix` for display.
+const DISPLAY_PREFIX_LEN: usize = 8;
+
+/// What an anonymous link grants. Links are opened read-only through
+/// `GET /api/shared/{token}`; the role tells the client what to offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkRole {
+    View,
+    Comment,
+    Edit,
+}
+
+impl LinkRole {
+    fn as_str(self) -> &'static str {
+        match self {
+            LinkRole::View => "view",
+            LinkRole::Comment => "comment",
+            LinkRole::Edit => "edit",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateLinkRequest {
+    pub role: LinkRole,
+    /// Required to open the link, if set
+    pub password: Option<String>,
+    /// Lifetime in days; the link doesn't expire when omitted
+    #[serde(alias = "expiryDays")]
+    pub expiry_days: Option<i64>,
+    /// How many times the link may be opened; unlimited when omitted
+    #[serde(alias = "maxUses")]
+    pub max_uses: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct CreatedLink {
+    #[serde(flatten)]
+    info: ShareLink,
+    /// The token itself, shown only in this response
+    token: String,
+    /// Page of the web app that opens the link
+    url: String,
+}
+
+/// A note as seen through an anonymous link.
+#[derive(Serialize)]
+struct SharedNote {
+    id: Uuid,
+    title: String,
+    body: String,
+    tags: Vec<String>,
+    revision: i64,
+    updated_at: DateTime<Utc>,
+    /// Role of the link that was opened
+    role: String,
+}
+
+/// POST /api/notes/{note_id}/links: create an anonymous link to the caller's note. The
+/// token is returned once; only its hash (and the password's) is stored.
+#[tracing::instrument(name = "share_links.create", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn create_link(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+    Json(payload): Json<CreateLinkRequest>,
+) -> AppResult<impl IntoResponse> {
+    permissions::require_role(&pool, note_id, user.id, Role::Owner).await?;
+    let expires_at: Option<DateTime<Utc>> = match payload.expiry_days {
+        Some(days) if !(1..=3650).contains(&days) => {
+            return Err(AppError::BadRequest(
+                "expiry_days must be between 1 and 3650".into(),
+            ))
+        }
+        Some(days) => Some(Utc::now() + chrono::Duration::days(days)),
+        None => None,
+    };
+    if payload.max_uses.is_some_and(|uses| uses < 1) {
+        return Err(AppError::BadRequest("max_uses must be at least 1".into()));
+    }
+    let password_hash = match payload.password.as_deref() {
+        Some("") => return Err(AppError::BadRequest("Password can't be empty".into())),
+        Some(password) => Some(utils::hash_password(password).await.map_err(|err| {
+            tracing::error!("Password hashing error: {:?}", err);
+            AppError::InternalServerError
+        })?),
+        None => None,
+    };
+
+    let token = utils::generate_token();
+    let info = monitoring::timed(
+        "insert_share_link",
+        sqlx::query_as!(
+            ShareLink,
+            r#"
+            INSERT INTO share_links
+                (id, note_id, created_by, token_hash, token_prefix, role, password_hash, expires_at, max_uses)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, token_prefix, role, password_hash IS NOT NULL AS "has_password!",
+                      expires_at, max_uses, use_count, last_used_at, created_by, created_at
+            "#,
+            Uuid::new_v4(),
+            note_id,
+            user.id,
+            utils::hash_token(&token),
+            &token[..DISPLAY_PREFIX_LEN],
+            payload.role.as_str(),
+            password_hash,
+            expires_at,
+            payload.max_uses
+        )
+        .fetch_one(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert share link error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%note_id, link_id = %info.id, role = payload.role.as_str(), "Share link created");
+    let url = format!("{}/s/{}", utils::app_base_url(), token);
+    Ok((
+        StatusCode::CREATED,
+        AxumJson(CreatedLink { info, token, url }),
+    ))
+}
+
+/// GET /api/notes/{note_id}/links: the note's links that are not revoked, including
+/// expired and used-up ones.
+#[tracing::instrument(name = "share_links.list", skip_all, fields(%note_id, user_id = %user.id))]
+pub async fn list_links(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(note_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    permissions::require_role(&pool, note_id, user.id, Role::Owner).await?;
+    let links = monitoring::timed(
+        "list_share_links",
+        sqlx::query_as!(
+            ShareLink,
+            r#"
+            SELECT id, token_prefix, role, password_hash IS NOT NULL AS "has_password!",
+                   expires_at, max_uses, use_count, last_used_at, created_by, created_at
+            FROM share_links
+            WHERE note_id = $1 AND revoked_at IS NULL
+            ORDER BY created_at DESC
+            "#,
+            note_id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List share links error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(links)))
+}
+
+/// DELETE /api/notes/{note_id}/links/{link_id}: revoke a link. Opening it fails from then on.
+#[tracing::instrument(name = "share_links.revoke", skip_all, fields(%note_id, %link_id))]
+pub async fn revoke_link(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path((note_id, link_id)): Path<(Uuid, Uuid)>,
+) -> AppResult<impl IntoResponse> {
+    permissions::require_role(&pool, note_id, user.id, Role::Owner).await?;
+    let revoked = monitoring::timed(
+        "revoke_share_link",
+        sqlx::query!(
+            r#"
+            UPDATE share_links SET revoked_at = NOW()
+            WHERE id = $1 AND note_id = $2 AND revoked_at IS NULL
+            "#,
+            link_id,
+            note_id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke share link error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    if !revoked {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(%note_id, %link_id, "Share link revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/shared/{token}: read a note through an anonymous link, sending the password of
+/// a protected link in `X-Share-Password`. Each successful open counts towards `max_uses`.
+/// Revoked, expired, used-up and unknown links all answer 404, as do links whose creator no
+/// longer owns the note.
+#[tracing::instrument(name = "share_links.open", skip_all)]
+pub async fn open_link(
+    Extension(pool): Extension<PgPool>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> AppResult<impl IntoResponse> {
+    let token_hash = utils::hash_token(&token);
+    let link = monitoring::timed(
+        "get_share_link",
+        sqlx::query!(
+            r#"
+            SELECT id, note_id, created_by, password_hash FROM share_links
+            WHERE token_hash = $1 AND revoked_at IS NULL
+              AND (expires_at IS NULL OR expires_at > NOW())
+              AND (max_uses IS NULL OR use_count < max_uses)
+            "#,
+            token_hash
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get share link error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    // A link is only as good as its creator's owner access
+    let Some(created_by) = link.created_by else {
+        return Err(AppError::NotFound);
+    };
+    if permissions::note_role(&pool, link.note_id, created_by).await? != Some(Role::Owner) {
+        return Err(AppError::NotFound);
+    }
+
+    // A wrong password doesn't use the link up
+    if let Some(hash) = &link.password_hash {
+        let password = headers
+            .get(PASSWORD_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let valid = !password.is_empty()
+            && utils::verify_password(password, hash)
+                .await
+                .map_err(|err| {
+                    tracing::error!("Password verification error: {:?}", err);
+                    AppError::InternalServerError
+                })?;
+        if !valid {
+            monitoring::record_auth("share_link", "failure");
+            return Err(AppError::Forbidden(
+                "This link needs the correct password".into(),
+            ));
+        }
+    }
+
+    // Count the open atomically, so max_uses holds under concurrent opens
+    let row = monitoring::timed(
+        "use_share_link",
+        sqlx::query!(
+            r#"
+            UPDATE share_links l SET use_count = l.use_count + 1, last_used_at = NOW()
+            FROM notes n
+            WHERE l.id = $1 AND n.id = l.note_id AND n.deleted_at IS NULL AND l.revoked_at IS NULL
+              AND (l.max_uses IS NULL OR l.use_count < l.max_uses)
+            RETURNING l.role, n.id, n.title, n.body, n.tags, n.revision, n.updated_at
+            "#,
+            link.id
+        )
+        .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Use share link error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    monitoring::record_auth("share_link", "success");
+    let note = SharedNote {
+        id: row.id,
+        title: row.title,
+        body: row.body,
+        tags: row.tags.unwrap_or_default(),
+        revision: row.revision,
+        updated_at: row.updated_at,
+        role: row.role,
+    };
+    // Every open is counted, so responses must not be served from a cache
+    Ok((
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, "no-store")],
+        AxumJson(note),
+    ))
+}
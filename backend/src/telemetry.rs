@@ -0,0 +1,279 @@
+use crate::utils;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, SpanData, SpanExporter},
+    Resource,
+};
+use std::{
+    env,
+    io::Write,
+    time::{Instant, SystemTime},
+};
+use tracing::{field, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Layer};
+use uuid::Uuid;
+
+/// Header used to propagate the correlation ID between clients, proxies and this service.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// Correlation ID of the request currently being handled on this task.
+    static REQUEST_ID: String;
+}
+
+/// Returns the request ID of the in-flight request, if called from within one.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Initialise the global subscriber. Filtering follows `RUST_LOG` (default `info`);
+/// `LOG_FORMAT=json` switches to one JSON object per line including the current span fields.
+///
+/// When `OTEL_TRACES_EXPORTER` selects an exporter, spans are also exported through
+/// OpenTelemetry. The returned provider must be shut down on exit to flush pending spans.
+pub fn init_tracing() -> anyhow::Result<Option<SdkTracerProvider>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let json = env::var("LOG_FORMAT")
+        .map(|v| v.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+    let fmt_layer = if json {
+        fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed()
+    } else {
+        fmt::layer().boxed()
+    };
+
+    // RUST_LOG applies to exported spans as well; per-message WS events are at DEBUG,
+    // e.g. `RUST_LOG=info,backend::ws=debug`. A single global filter is used on purpose:
+    // per-layer filters drop events after sqlx's bare `enabled!` checks.
+    let provider = tracer_provider_from_env()?;
+    let otel_layer = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
+
+    Ok(provider)
+}
+
+/// Select the span exporter from `OTEL_TRACES_EXPORTER`:
+/// `none` (default), `otlp` (HTTP/protobuf to `OTEL_EXPORTER_OTLP_ENDPOINT`, default
+/// `http://localhost:4318`) or `stdout`.
+fn tracer_provider_from_env() -> anyhow::Result<Option<SdkTracerProvider>> {
+    let exporter = env::var("OTEL_TRACES_EXPORTER").unwrap_or_else(|_| "none".to_string());
+    let provider = match exporter.to_ascii_lowercase().as_str() {
+        "" | "none" => return Ok(None),
+        "otlp" => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .build()?;
+            tracer_provider(exporter, true)
+        }
+        "stdout" | "console" => tracer_provider(StdoutSpanExporter, false),
+        other => anyhow::bail!("Unsupported OTEL_TRACES_EXPORTER: {}", other),
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    Ok(Some(provider))
+}
+
+/// Build a tracer provider around any span exporter. `batch = false` exports each span as
+/// soon as it ends, which is what tests want when handing in the SDK's in-memory exporter.
+pub fn tracer_provider<E: SpanExporter + 'static>(exporter: E, batch: bool) -> SdkTracerProvider {
+    let service_name =
+        env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "noteflow-backend".to_string());
+    let resource = Resource::builder().with_service_name(service_name).build();
+    let builder = SdkTracerProvider::builder().with_resource(resource);
+    let builder = if batch {
+        builder.with_batch_exporter(exporter)
+    } else {
+        builder.with_simple_exporter(exporter)
+    };
+    builder.build()
+}
+
+/// Writes each finished span to stdout as a JSON line. Useful locally and in tests
+/// when no collector is running.
+#[derive(Debug, Default)]
+pub struct StdoutSpanExporter;
+
+impl SpanExporter for StdoutSpanExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let attributes = |kvs: &[KeyValue]| -> serde_json::Map<String, serde_json::Value> {
+            kvs.iter()
+                .map(|kv| (kv.key.to_string(), kv.value.to_string().into()))
+                .collect()
+        };
+
+        let mut out = std::io::stdout().lock();
+        for span in batch {
+            let duration_us = span
+                .end_time
+                .duration_since(span.start_time)
+                .unwrap_or_default()
+                .as_micros() as u64;
+            let events: Vec<_> = span
+                .events
+                .iter()
+                .map(|event| {
+                    serde_json::json!({
+                        "name": event.name,
+                        "offset_us": event
+                            .timestamp
+                            .duration_since(span.start_time)
+                            .unwrap_or_default()
+                            .as_micros() as u64,
+                        "attributes": attributes(&event.attributes),
+                    })
+                })
+                .collect();
+            let line = serde_json::json!({
+                "trace_id": span.span_context.trace_id().to_string(),
+                "span_id": span.span_context.span_id().to_string(),
+                "parent_span_id": span.parent_span_id.to_string(),
+                "name": span.name,
+                "start_unix_us": span
+                    .start_time
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_micros() as u64,
+                "duration_us": duration_us,
+                "status": format!("{:?}", span.status),
+                "attributes": attributes(&span.attributes),
+                "events": events,
+            });
+            let _ = writeln!(out, "{}", line);
+        }
+        Ok(())
+    }
+}
+
+/// Accept client-supplied IDs only if they are short and made of safe characters.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Middleware that assigns each request a correlation ID (reusing a valid incoming
+/// `X-Request-Id`), runs it inside a `request` span and echoes the ID on the response.
+pub async fn request_context(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        request_id = %request_id,
+        method = %req.method(),
+        route = %route,
+        user_id = field::Empty,
+        status = field::Empty,
+        latency_ms = field::Empty,
+    );
+
+    // Continue an incoming W3C `traceparent`, if any (no-op when tracing export is off)
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let _ = span.set_parent(parent);
+
+    // Best-effort: tag the span with the caller when a valid bearer token is present
+    if let Some(claims) = utils::bearer_claims(req.headers()) {
+        span.record("user_id", field::display(claims.sub));
+    }
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(req).instrument(span.clone()))
+        .await;
+
+    span.record("status", response.status().as_u16());
+    span.record("latency_ms", start.elapsed().as_millis() as u64);
+    span.in_scope(|| tracing::info!("request completed"));
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+
+    #[test]
+    fn spans_are_exported_with_their_fields_and_events() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = tracer_provider(exporter.clone(), false);
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let session = tracing::info_span!("ws.session", note_id = "n1");
+            let _entered = session.enter();
+            tracing::info_span!("db.query").in_scope(|| {});
+            tracing::info!(bytes = 3, "ws message received");
+        });
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let [query, session] = spans.as_slice() else {
+            panic!("expected two spans, got {}", spans.len());
+        };
+        assert_eq!(query.name, "db.query");
+        assert_eq!(session.name, "ws.session");
+        assert_eq!(query.parent_span_id, session.span_context.span_id());
+        assert_eq!(
+            query.span_context.trace_id(),
+            session.span_context.trace_id()
+        );
+        assert!(session
+            .attributes
+            .iter()
+            .any(|kv| kv.key.as_str() == "note_id" && kv.value == Value::from("n1")));
+        assert_eq!(session.events.len(), 1);
+        assert_eq!(session.events[0].name, "ws message received");
+    }
+
+    #[test]
+    fn request_ids_must_be_short_and_safe() {
+        assert!(is_valid_request_id("3f2c1e9a-4b7d-4c1e-9a2b-0c1d2e3f4a5b"));
+        assert!(is_valid_request_id("lb.req_42"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("id with spaces"));
+        assert!(!is_valid_request_id("id\r\nx-injected: 1"));
+        assert!(!is_valid_request_id(&"a".repeat(129)));
+    }
+}
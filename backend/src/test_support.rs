@@ -0,0 +1,118 @@
+//! Fixtures for tests that run against a database from `#[sqlx::test]`.
+
+use crate::jwt_keys;
+use sqlx::PgPool;
+use std::{env, sync::Once};
+use uuid::Uuid;
+
+/// Insert an account with a verified `{username}@example.com` address. Its password hash
+/// matches no password.
+pub async fn create_user(pool: &PgPool, username: &str) -> Uuid {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO users (id, username, password_hash, email, email_verified_at)
+        VALUES ($1, $2, '!', $3, NOW())
+        RETURNING id
+        "#,
+        Uuid::new_v4(),
+        username,
+        format!("{}@example.com", username)
+    )
+    .fetch_one(pool)
+    .await
+    .expect("create user")
+}
+
+/// Load HS256 signing keys once per test binary, with a throwaway `JWT_SECRET`.
+pub fn init_jwt_keys() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        env::set_var("JWT_SECRET", "test-secret-not-for-production");
+        env::set_var("JWT_ALGORITHM", "HS256");
+        jwt_keys::init().expect("init JWT keys");
+    });
+}
+
+/// Start a session for `user_id`, as a login does, returning its ID.
+pub async fn create_session(pool: &PgPool, user_id: Uuid) -> Uuid {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO sessions (id, user_id, ip, expires_at)
+        VALUES ($1, $2, '127.0.0.1', NOW() + INTERVAL '1 day')
+        RETURNING id
+        "#,
+        Uuid::new_v4(),
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .expect("create session")
+}
+
+/// Create a team workspace with `owner_id` as its owner.
+pub async fn create_workspace(pool: &PgPool, owner_id: Uuid) -> Uuid {
+    let workspace_id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO workspaces (id, name, created_by) VALUES ($1, 'Team', $2)",
+        workspace_id,
+        owner_id
+    )
+    .execute(pool)
+    .await
+    .expect("create workspace");
+    add_member(pool, workspace_id, owner_id, "owner").await;
+    workspace_id
+}
+
+/// Make `user_id` a member of `workspace_id` with `role`.
+pub async fn add_member(pool: &PgPool, workspace_id: Uuid, user_id: Uuid, role: &str) {
+    sqlx::query!(
+        "INSERT INTO workspace_members (workspace_id, user_id, role) VALUES ($1, $2, $3)",
+        workspace_id,
+        user_id,
+        role
+    )
+    .execute(pool)
+    .await
+    .expect("add workspace member");
+}
+
+/// Create a folder in `workspace_id`, under `parent_id` or at the root.
+pub async fn create_folder(pool: &PgPool, workspace_id: Uuid, parent_id: Option<Uuid>) -> Uuid {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO folders (id, workspace_id, parent_id, name)
+        VALUES ($1, $2, $3, 'Folder')
+        RETURNING id
+        "#,
+        Uuid::new_v4(),
+        workspace_id,
+        parent_id
+    )
+    .fetch_one(pool)
+    .await
+    .expect("create folder")
+}
+
+/// Create a note by `user_id` in `workspace_id`, inside `folder_id` or at the root.
+pub async fn create_note(
+    pool: &PgPool,
+    workspace_id: Uuid,
+    folder_id: Option<Uuid>,
+    user_id: Uuid,
+) -> Uuid {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO notes (id, user_id, workspace_id, folder_id, title, body)
+        VALUES ($1, $2, $3, $4, 'Note', '')
+        RETURNING id
+        "#,
+        Uuid::new_v4(),
+        user_id,
+        workspace_id,
+        folder_id
+    )
+    .fetch_one(pool)
+    .await
+    .expect("create note")
+}
@@ -0,0 +1,284 @@
+use crate::{
+    access_tokens::Scope,
+    auth::AuthUser,
+    errors::{AppError, AppResult},
+    models::Note,
+    monitoring,
+    permissions::{self, Role},
+    utils,
+};
+use axum::{
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct TrashQuery {
+    /// Only list the trash of this workspace
+    pub workspace_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+struct TrashedNote {
+    #[serde(flatten)]
+    note: Note,
+    /// When the note will be deleted for good
+    purge_at: DateTime<Utc>,
+}
+
+/// Days a note stays in the trash (`TRASH_RETENTION_DAYS`, default 30).
+fn retention_days() -> i32 {
+    utils::env_or("TRASH_RETENTION_DAYS", 30)
+}
+
+/// GET /api/trash: trashed notes the caller owns, most recently deleted first.
+#[tracing::instrument(name = "trash.list", skip_all, fields(caller_id = %user.id))]
+pub async fn list_trash(
+    Extension(pool): Extension<PgPool>,
+    user: AuthUser,
+    Query(query): Query<TrashQuery>,
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesRead)?;
+    // Owner access, as in permissions::note_role: a grant on the note or a folder above it,
+    // or an admin role in the workspace
+    let notes = monitoring::timed(
+        "list_trash",
+        sqlx::query_as!(
+            Note,
+            r#"
+            WITH RECURSIVE owned_folders AS (
+                SELECT folder_id AS id FROM folder_permissions
+                WHERE user_id = $1 AND role = 'owner'
+                  AND (expires_at IS NULL OR expires_at > NOW())
+                UNION
+                SELECT f.id FROM folders f JOIN owned_folders o ON f.parent_id = o.id
+            )
+            SELECT n.* FROM notes n
+            WHERE n.deleted_at IS NOT NULL
+              AND ($2::uuid IS NULL OR n.workspace_id = $2)
+              AND (n.folder_id IN (SELECT id FROM owned_folders) OR EXISTS (
+                  SELECT 1 FROM workspace_members m
+                  WHERE m.workspace_id = n.workspace_id AND m.user_id = $1
+                    AND m.role IN ('owner', 'admin')
+              ) OR EXISTS (
+                  SELECT 1 FROM note_permissions p
+                  WHERE p.note_id = n.id AND p.user_id = $1 AND p.role = 'owner'
+              ))
+            ORDER BY n.deleted_at DESC
+            "#,
+            user.id,
+            query.workspace_id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List trash error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let retention = chrono::Duration::days(retention_days().into());
+    let trash: Vec<TrashedNote> = notes
+        .into_iter()
+        .filter_map(|mut note| {
+            note.tags = Some(note.tags.take().unwrap_or_default());
+            let purge_at = note.deleted_at? + retention;
+            Some(TrashedNote { note, purge_at })
+        })
+        .collect();
+    Ok((StatusCode::OK, AxumJson(trash)))
+}
+
+/// POST /api/trash/{note_id}/restore: take a note out of the trash, back in its folder. If
+/// that folder was deleted, the note goes to the closest folder above it that is left, or
+/// to the workspace root.
+/// Notes of a deleted workspace come back only with it.
+#[tracing::instrument(name = "trash.restore", skip_all, fields(%note_id, caller_id = %user.id))]
+pub async fn restore_note(
+    Extension(pool): Extension<PgPool>,
+    user: AuthUser,
+    Path(note_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesWrite)?;
+    permissions::require_trashed_role(&pool, note_id, user.id, Role::Owner).await?;
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    // Locking the workspace holds off its deletion meanwhile
+    let workspace_deleted = monitoring::timed(
+        "get_restore_workspace",
+        sqlx::query_scalar!(
+            r#"
+            SELECT w.deleted_at IS NOT NULL AS "deleted!"
+            FROM workspaces w JOIN notes n ON n.workspace_id = w.id
+            WHERE n.id = $1
+            FOR SHARE OF w
+            "#,
+            note_id
+        )
+        .fetch_optional(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get restore workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .unwrap_or(false);
+    if workspace_deleted {
+        return Err(AppError::Conflict(
+            "The note's workspace was deleted; restore the workspace instead".into(),
+        ));
+    }
+    // Locking the folder holds off its deletion meanwhile
+    let folder_id = monitoring::timed(
+        "get_restore_folder",
+        sqlx::query_scalar!(
+            r#"
+            WITH RECURSIVE chain AS (
+                SELECT f.id, f.parent_id, 0 AS depth
+                FROM folders f JOIN notes n ON n.folder_id = f.id
+                WHERE n.id = $1
+                UNION ALL
+                SELECT f.id, f.parent_id, c.depth + 1
+                FROM folders f JOIN chain c ON f.id = c.parent_id
+            )
+            SELECT f.id FROM folders f JOIN chain c ON c.id = f.id
+            WHERE f.deleted_at IS NULL
+            ORDER BY c.depth
+            LIMIT 1
+            FOR SHARE OF f
+            "#,
+            note_id
+        )
+        .fetch_optional(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get restore folder error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let mut note = monitoring::timed(
+        "restore_note",
+        sqlx::query_as!(
+            Note,
+            r#"
+            UPDATE notes SET deleted_at = NULL, deleted_by = NULL, folder_id = $2
+            WHERE id = $1 AND deleted_at IS NOT NULL
+            RETURNING *
+            "#,
+            note_id,
+            folder_id
+        )
+        .fetch_optional(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Restore note error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    note.tags = Some(note.tags.unwrap_or_default());
+    Ok((StatusCode::OK, AxumJson(note)))
+}
+
+/// DELETE /api/trash/{note_id}: delete a trashed note for good, with its revisions.
+#[tracing::instrument(name = "trash.delete", skip_all, fields(%note_id, caller_id = %user.id))]
+pub async fn delete_note_permanently(
+    Extension(pool): Extension<PgPool>,
+    user: AuthUser,
+    Path(note_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    user.require(Scope::NotesWrite)?;
+    permissions::require_trashed_role(&pool, note_id, user.id, Role::Owner).await?;
+    monitoring::timed(
+        "delete_note",
+        sqlx::query!(
+            "DELETE FROM notes WHERE id = $1 AND deleted_at IS NOT NULL",
+            note_id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete note error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%note_id, "Trashed note deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Background task: every `TRASH_PURGE_INTERVAL_SECS` (default 3600), delete notes that
+/// have been in the trash longer than `TRASH_RETENTION_DAYS`, then folders and workspaces
+/// deleted as long ago.
+pub async fn purge_trash(pool: PgPool) {
+    let period = Duration::from_secs(utils::env_or("TRASH_PURGE_INTERVAL_SECS", 3600));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let result = monitoring::timed(
+            "purge_trash",
+            sqlx::query!(
+                "DELETE FROM notes WHERE deleted_at <= NOW() - make_interval(days => $1)",
+                retention_days()
+            )
+            .execute(&pool),
+        )
+        .await;
+        match result {
+            Ok(done) if done.rows_affected() > 0 => {
+                tracing::info!(count = done.rows_affected(), "Trashed notes purged");
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Purge trash error: {:?}", e),
+        }
+        // Notes trashed with a folder were trashed no later than it, so they are gone by now
+        let result = monitoring::timed(
+            "purge_deleted_folders",
+            sqlx::query!(
+                "DELETE FROM folders WHERE deleted_at <= NOW() - make_interval(days => $1)",
+                retention_days()
+            )
+            .execute(&pool),
+        )
+        .await;
+        match result {
+            Ok(done) if done.rows_affected() > 0 => {
+                tracing::info!(count = done.rows_affected(), "Deleted folders purged");
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Purge deleted folders error: {:?}", e),
+        }
+        // Same for the notes and folders of a deleted workspace
+        let result = monitoring::timed(
+            "purge_deleted_workspaces",
+            sqlx::query!(
+                "DELETE FROM workspaces WHERE deleted_at <= NOW() - make_interval(days => $1)",
+                retention_days()
+            )
+            .execute(&pool),
+        )
+        .await;
+        match result {
+            Ok(done) if done.rows_affected() > 0 => {
+                tracing::info!(count = done.rows_affected(), "Deleted workspaces purged");
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Purge deleted workspaces error: {:?}", e),
+        }
+    }
+}
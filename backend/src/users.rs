@@ -0,0 +1,463 @@
+use crate::{
+    access_tokens,
+    auth::{self, SessionUser},
+    email_verification,
+    errors::{AppError, AppResult},
+    mailer::{self, Email, SharedMailer},
+    mfa,
+    models::User,
+    monitoring,
+    revocation::TokenDenylist,
+    utils, workspaces,
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Profile changes; omitted fields are left as they are. An empty `display_name` or
+/// `avatar_url` clears it.
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub email: Option<String>,
+    /// Confirms an email change, unless `code` does
+    pub current_password: Option<String>,
+    /// TOTP or recovery code, confirming an email change on accounts with TOTP enabled
+    pub code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Reject the request unless the account in the path is the caller's own.
+fn ensure_self(user: &SessionUser, user_id: Uuid) -> AppResult<()> {
+    if user.id != user_id {
+        return Err(AppError::Forbidden(
+            "You can only manage your own account".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Usernames are 3 to 40 letters, digits, `.`, `_` or `-`.
+fn validate_username(raw: &str) -> AppResult<String> {
+    let username = raw.trim();
+    let valid = (3..=40).contains(&username.chars().count())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        return Err(AppError::BadRequest(
+            "Username must be 3 to 40 letters, digits, '.', '_' or '-'".into(),
+        ));
+    }
+    Ok(username.to_string())
+}
+
+/// Trimmed display name, at most 100 characters; empty clears it.
+fn validate_display_name(raw: &str) -> AppResult<String> {
+    let name = raw.trim();
+    if name.chars().count() > 100 || name.chars().any(char::is_control) {
+        return Err(AppError::BadRequest(
+            "Display name must be at most 100 characters".into(),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// An http(s) URL of at most 2048 characters; empty clears it.
+fn validate_avatar_url(raw: &str) -> AppResult<String> {
+    let url = raw.trim();
+    let valid = url.is_empty()
+        || (url.len() <= 2048
+            && (url.starts_with("https://") || url.starts_with("http://"))
+            && !url.chars().any(|c| c.is_whitespace() || c.is_control()));
+    if !valid {
+        return Err(AppError::BadRequest(
+            "Avatar URL must be an http(s) URL".into(),
+        ));
+    }
+    Ok(url.to_string())
+}
+
+/// Check the current password, or a second factor when TOTP is enabled, before a change
+/// that could take the account over.
+async fn confirm_identity(
+    pool: &PgPool,
+    user_id: Uuid,
+    current_password: Option<&str>,
+    code: Option<&str>,
+) -> AppResult<bool> {
+    if let Some(code) = code {
+        if mfa::verify_code(pool, user_id, code).await? {
+            return Ok(true);
+        }
+    }
+    let Some(password) = current_password else {
+        return Ok(false);
+    };
+    let hash = monitoring::timed(
+        "get_password_hash",
+        sqlx::query_scalar!("SELECT password_hash FROM users WHERE id = $1", user_id)
+            .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get password hash error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    utils::verify_password(password, &hash)
+        .await
+        .map_err(|err| {
+            tracing::error!("Password verification error: {:?}", err);
+            AppError::InternalServerError
+        })
+}
+
+async fn fetch_profile(pool: &PgPool, user_id: Uuid) -> AppResult<User> {
+    monitoring::timed(
+        "get_user_profile",
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, username, display_name, avatar_url, email,
+                   email_verified_at IS NOT NULL AS "email_verified!",
+                   created_at, deletion_scheduled_at
+            FROM users WHERE id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get user profile error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)
+}
+
+/// GET /api/users/{user_id}: the caller's profile.
+#[tracing::instrument(name = "users.get_profile", skip_all, fields(%user_id))]
+pub async fn get_profile(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(user_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    ensure_self(&user, user_id)?;
+    let profile = fetch_profile(&pool, user_id).await?;
+    Ok((StatusCode::OK, AxumJson(profile)))
+}
+
+/// PUT /api/users/{user_id}: change the caller's username, display name, avatar or email.
+/// A new email address needs the current password (or a TOTP code), starts out unverified
+/// and is sent a verification link; the old address is told about the change.
+#[tracing::instrument(name = "users.update_profile", skip_all, fields(%user_id))]
+pub async fn update_profile(
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<SharedMailer>,
+    user: SessionUser,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> AppResult<impl IntoResponse> {
+    ensure_self(&user, user_id)?;
+    let username = payload
+        .username
+        .as_deref()
+        .map(validate_username)
+        .transpose()?;
+    let display_name = payload
+        .display_name
+        .as_deref()
+        .map(validate_display_name)
+        .transpose()?;
+    let avatar_url = payload
+        .avatar_url
+        .as_deref()
+        .map(validate_avatar_url)
+        .transpose()?;
+    let email = payload
+        .email
+        .as_deref()
+        .map(email_verification::normalize_email)
+        .transpose()?;
+
+    // Only a different address (ignoring case) needs verifying again
+    let current = fetch_profile(&pool, user_id).await?;
+    let new_email = email.filter(|email| {
+        current
+            .email
+            .as_deref()
+            .is_none_or(|current| !current.eq_ignore_ascii_case(email))
+    });
+    if new_email.is_some()
+        && !confirm_identity(
+            &pool,
+            user_id,
+            payload.current_password.as_deref(),
+            payload.code.as_deref(),
+        )
+        .await?
+    {
+        monitoring::record_auth("change_email", "failure");
+        return Err(AppError::Forbidden(
+            "Confirm the new email address with your current password or a two-factor code".into(),
+        ));
+    }
+
+    monitoring::timed(
+        "update_user_profile",
+        sqlx::query!(
+            r#"
+            UPDATE users SET
+                username = COALESCE($2, username),
+                display_name = CASE WHEN $3::TEXT IS NULL THEN display_name ELSE NULLIF($3, '') END,
+                avatar_url = CASE WHEN $4::TEXT IS NULL THEN avatar_url ELSE NULLIF($4, '') END,
+                email = COALESCE($5, email),
+                email_verified_at = CASE WHEN $5::TEXT IS NULL THEN email_verified_at END
+            WHERE id = $1
+            "#,
+            user_id,
+            username,
+            display_name,
+            avatar_url,
+            new_email
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update user profile error: {:?}", e);
+        match e.as_database_error() {
+            Some(db) if db.constraint() == Some("idx_users_email_lower") => {
+                AppError::Conflict("Email address already in use".into())
+            }
+            Some(db) if db.is_unique_violation() => {
+                AppError::Conflict("Username already exists".into())
+            }
+            _ => AppError::InternalServerError,
+        }
+    })?;
+
+    if let Some(email) = &new_email {
+        // Reset links already sent to the old address stop working
+        monitoring::timed(
+            "expire_password_reset_tokens",
+            sqlx::query!(
+                "UPDATE password_reset_tokens SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+                user_id
+            )
+            .execute(&pool),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Expire password reset tokens error: {:?}", e);
+            AppError::InternalServerError
+        })?;
+        let _ = email_verification::send_verification(&pool, mailer.clone(), user_id, email).await;
+        if let Some(old_email) = current.email {
+            mailer::send_in_background(
+                mailer,
+                Email {
+                    to: old_email,
+                    subject: "Your Noteflow email address was changed".to_string(),
+                    body: format!(
+                        "The email address of your Noteflow account {} was changed to {}.\n\n\
+                         If this wasn't you, reset your password and contact support.\n",
+                        current.username, email
+                    ),
+                },
+            );
+        }
+        monitoring::record_auth("change_email", "success");
+        tracing::info!(%user_id, "Email address changed");
+    }
+
+    let profile = fetch_profile(&pool, user_id).await?;
+    Ok((StatusCode::OK, AxumJson(profile)))
+}
+
+/// PUT /api/users/{user_id}/password: change the caller's password, confirmed with the
+/// current one. Every other session of the account is signed out.
+#[tracing::instrument(name = "users.change_password", skip_all, fields(%user_id))]
+pub async fn change_password(
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    user: SessionUser,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> AppResult<impl IntoResponse> {
+    ensure_self(&user, user_id)?;
+    if payload.new_password.is_empty() {
+        return Err(AppError::BadRequest("New password is required".into()));
+    }
+
+    let current_hash = monitoring::timed(
+        "get_password_hash",
+        sqlx::query_scalar!("SELECT password_hash FROM users WHERE id = $1", user_id)
+            .fetch_optional(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get password hash error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+
+    let valid = utils::verify_password(&payload.current_password, &current_hash)
+        .await
+        .map_err(|err| {
+            tracing::error!("Password verification error: {:?}", err);
+            AppError::InternalServerError
+        })?;
+    if !valid {
+        monitoring::record_auth("change_password", "failure");
+        return Err(AppError::Forbidden("Current password is incorrect".into()));
+    }
+
+    let hashed = utils::hash_password(&payload.new_password)
+        .await
+        .map_err(|err| {
+            tracing::error!("Password hashing error: {:?}", err);
+            AppError::InternalServerError
+        })?;
+    monitoring::timed(
+        "update_password",
+        sqlx::query!(
+            "UPDATE users SET password_hash = $2 WHERE id = $1",
+            user_id,
+            hashed
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update password error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    auth::revoke_other_sessions(&pool, &denylist, user_id, user.session_id).await?;
+
+    tracing::info!(%user_id, "Password changed");
+    monitoring::record_auth("change_password", "success");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE /api/users/{user_id}: schedule the caller's account for deletion after
+/// `ACCOUNT_DELETION_GRACE_DAYS` (default 30). Every session and personal access token is
+/// revoked now; signing in again before the deadline cancels the deletion. Refused while the
+/// caller is the only owner of a team workspace.
+#[tracing::instrument(name = "users.delete_account", skip_all, fields(%user_id))]
+pub async fn delete_account(
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    user: SessionUser,
+    Path(user_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    ensure_self(&user, user_id)?;
+    let grace_days: i64 = utils::env_or("ACCOUNT_DELETION_GRACE_DAYS", 30);
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    workspaces::ensure_not_sole_owner(&mut tx, user_id).await?;
+    // Asking again doesn't push the deadline back
+    let deletion_scheduled_at = monitoring::timed(
+        "schedule_account_deletion",
+        sqlx::query_scalar!(
+            r#"
+            UPDATE users SET deletion_scheduled_at = COALESCE(deletion_scheduled_at, $2)
+            WHERE id = $1
+            RETURNING deletion_scheduled_at AS "deletion_scheduled_at!"
+            "#,
+            user_id,
+            Utc::now() + chrono::Duration::days(grace_days)
+        )
+        .fetch_optional(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Schedule account deletion error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    auth::revoke_all_sessions(&pool, &denylist, user_id).await?;
+    access_tokens::revoke_all(&pool, user_id).await?;
+
+    tracing::info!(%user_id, %deletion_scheduled_at, "Account deletion scheduled");
+    Ok((
+        StatusCode::ACCEPTED,
+        AxumJson(serde_json::json!({ "deletion_scheduled_at": deletion_scheduled_at })),
+    ))
+}
+
+/// Keep an account that was scheduled for deletion, called when its owner signs in.
+pub async fn cancel_scheduled_deletion(pool: &PgPool, user_id: Uuid) -> AppResult<()> {
+    let cancelled = monitoring::timed(
+        "cancel_account_deletion",
+        sqlx::query!(
+            "UPDATE users SET deletion_scheduled_at = NULL WHERE id = $1 AND deletion_scheduled_at IS NOT NULL",
+            user_id
+        )
+        .execute(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Cancel account deletion error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    if cancelled {
+        tracing::info!(%user_id, "Account deletion cancelled by sign-in");
+    }
+    Ok(())
+}
+
+/// Background task: every `ACCOUNT_PURGE_INTERVAL_SECS` (default 3600), delete accounts
+/// whose deletion grace period has passed. Notes, sessions and everything else owned by
+/// the account go with it (`ON DELETE CASCADE`).
+pub async fn purge_deleted_accounts(pool: PgPool) {
+    let period = Duration::from_secs(utils::env_or("ACCOUNT_PURGE_INTERVAL_SECS", 3600));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let result = monitoring::timed(
+            "purge_deleted_accounts",
+            sqlx::query_scalar!(
+                "DELETE FROM users WHERE deletion_scheduled_at <= NOW() RETURNING id",
+            )
+            .fetch_all(&pool),
+        )
+        .await;
+        match result {
+            Ok(user_ids) => {
+                for user_id in user_ids {
+                    tracing::info!(%user_id, "Account deleted");
+                }
+            }
+            Err(e) => tracing::error!("Purge deleted accounts error: {:?}", e),
+        }
+    }
+}
@@ -1,46 +1,313 @@
-use crate::models::Claims;
-use anyhow::{Context, Result};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use std::env;
+use crate::{jwt_keys, models::Claims};
+use anyhow::{anyhow, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2, Params,
+};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, Extensions, HeaderMap},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bcrypt::DEFAULT_COST;
+use pulldown_cmark::{Event, Options, Parser, TagEnd};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{
+    convert::Infallible,
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::LazyLock,
+};
 
-/// Hash a plaintext password using bcrypt with the default cost.
-/// Returns the hash string.
-pub fn hash_password(password: &str) -> Result<String> {
-    // DEFAULT_COST is currently 12 in bcrypt crate; adjust via env if needed.
-    let hashed = hash(password, DEFAULT_COST).context("Failed to hash the password")?;
-    Ok(hashed)
+/// Password hashing configuration, read once from the environment:
+/// - `PASSWORD_HASH_ALGORITHM`: `argon2id` (default) or `bcrypt`
+/// - `ARGON2_MEMORY_KIB` (default 19456), `ARGON2_ITERATIONS` (default 2),
+///   `ARGON2_PARALLELISM` (default 1): Argon2id cost, defaults per OWASP
+/// - `BCRYPT_COST`: bcrypt cost (default 12)
+#[derive(Debug, Clone)]
+enum PasswordHashing {
+    Argon2id(Params),
+    Bcrypt(u32),
 }
 
-/// Verify a plaintext password against a bcrypt hash.
-/// Returns true if the password matches.
-pub fn verify_password(password: &str, hash_str: &str) -> Result<bool> {
-    let valid = verify(password, hash_str).context("Failed to verify the password")?;
-    Ok(valid)
+static PASSWORD_HASHING: LazyLock<PasswordHashing> = LazyLock::new(|| {
+    let algorithm = env::var("PASSWORD_HASH_ALGORITHM").unwrap_or_else(|_| "argon2id".to_string());
+    if algorithm.eq_ignore_ascii_case("bcrypt") {
+        return PasswordHashing::Bcrypt(env_or("BCRYPT_COST", DEFAULT_COST));
+    }
+    if !algorithm.eq_ignore_ascii_case("argon2id") {
+        tracing::error!(
+            "Unsupported PASSWORD_HASH_ALGORITHM {}, using argon2id",
+            algorithm
+        );
+    }
+    let params = Params::new(
+        env_or("ARGON2_MEMORY_KIB", 19_456),
+        env_or("ARGON2_ITERATIONS", 2),
+        env_or("ARGON2_PARALLELISM", 1),
+        None,
+    )
+    .unwrap_or_else(|err| {
+        tracing::error!("Invalid Argon2 parameters ({}), using defaults", err);
+        Params::new(19_456, 2, 1, None).expect("default Argon2 parameters are valid")
+    });
+    PasswordHashing::Argon2id(params)
+});
+
+/// Hash a plaintext password with the configured algorithm (Argon2id by default), on the
+/// blocking thread pool so the async workers stay free. Returns a PHC/bcrypt hash string.
+pub async fn hash_password(password: &str) -> Result<String> {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || match &*PASSWORD_HASHING {
+        PasswordHashing::Argon2id(params) => {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::new(
+                argon2::Algorithm::Argon2id,
+                argon2::Version::V0x13,
+                params.clone(),
+            )
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| anyhow!("Failed to hash the password: {}", err))
+        }
+        PasswordHashing::Bcrypt(cost) => {
+            bcrypt::hash(password, *cost).context("Failed to hash the password")
+        }
+    })
+    .await
+    .context("Password hashing task failed")?
+}
+
+/// Verify a plaintext password against an Argon2 or bcrypt hash, on the blocking thread
+/// pool. Returns true if the password matches.
+pub async fn verify_password(password: &str, hash_str: &str) -> Result<bool> {
+    let password = password.to_owned();
+    let hash_str = hash_str.to_owned();
+    tokio::task::spawn_blocking(move || {
+        if hash_str.starts_with("$argon2") {
+            let hash = PasswordHash::new(&hash_str)
+                .map_err(|err| anyhow!("Invalid Argon2 hash: {}", err))?;
+            // Parameters come from the hash itself
+            Ok(Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok())
+        } else {
+            bcrypt::verify(password, &hash_str).context("Failed to verify the password")
+        }
+    })
+    .await
+    .context("Password verification task failed")?
+}
+
+/// Whether a stored hash uses another algorithm or weaker parameters than configured,
+/// so it should be replaced after the next successful login.
+pub fn password_needs_rehash(hash_str: &str) -> bool {
+    match &*PASSWORD_HASHING {
+        PasswordHashing::Argon2id(params) => {
+            let Ok(hash) = PasswordHash::new(hash_str) else {
+                return true;
+            };
+            hash.algorithm != argon2::Algorithm::Argon2id.ident()
+                || Params::try_from(&hash).map_or(true, |current| {
+                    current.m_cost() < params.m_cost()
+                        || current.t_cost() < params.t_cost()
+                        || current.p_cost() < params.p_cost()
+                })
+        }
+        PasswordHashing::Bcrypt(cost) => match hash_str.parse::<bcrypt::HashParts>() {
+            Ok(parts) => parts.get_cost() < *cost,
+            Err(_) => true,
+        },
+    }
 }
 
-/// Encode a JWT from Claims using HMAC secret from env var JWT_SECRET.
-/// Uses Header::default() (HS256) and returns the token string.
+/// Sign a JWT from Claims with the configured key (see `jwt_keys`).
+/// HS256 with JWT_SECRET by default; RS256/EdDSA tokens carry the key's `kid`.
 pub fn encode_jwt(claims: &Claims) -> Result<String> {
-    let secret = env::var("JWT_SECRET").context("JWT_SECRET environment variable is not set")?;
-    let token = encode(
-        &Header::default(),
-        claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .context("Failed to encode JWT token")?;
-    Ok(token)
+    jwt_keys::keys()?.encode(claims)
 }
 
-/// Decode a JWT string into Claims using HMAC secret from env var JWT_SECRET.
-/// Uses Validation::default() (validates exp by default).
+/// Verify a JWT against the configured keys and decode it into Claims.
+/// Validates the signature, `alg` and `exp`.
 pub fn decode_jwt(token: &str) -> Result<Claims> {
-    let secret = env::var("JWT_SECRET").context("JWT_SECRET environment variable is not set")?;
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &Validation::default(),
-    )
-    .context("Failed to decode JWT token")?;
-    Ok(token_data.claims)
+    jwt_keys::keys()?.decode(token)
+}
+
+/// Generate an opaque, URL-safe random token (256 bits of entropy).
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// SHA-256 hex digest of an opaque token, the only form in which tokens are stored.
+/// (Tokens are high-entropy, so a fast unsalted hash is sufficient.)
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// The credential of an `Authorization: Bearer <token>` header, if present.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Decode the `Authorization: Bearer <jwt>` header, if present and valid.
+pub fn bearer_claims(headers: &HeaderMap) -> Option<Claims> {
+    decode_jwt(bearer_token(headers)?).ok()
+}
+
+/// Read and parse an env var, falling back to `default` when unset or invalid.
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Public URL of the web app (`APP_BASE_URL`, default `http://localhost:3000`), used to
+/// build links sent by email.
+pub fn app_base_url() -> String {
+    env::var("APP_BASE_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+/// Public URL of this server (`PUBLIC_BASE_URL`, default `http://localhost:8080`), used to
+/// build links to published pages.
+pub fn public_base_url() -> String {
+    env::var("PUBLIC_BASE_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "http://localhost:8080".to_string())
+}
+
+/// Extractor for the client IP address, see [`client_ip`].
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp(client_ip(&parts.headers, &parts.extensions)))
+    }
+}
+
+/// Client IP address of the request. Uses the peer address, or the right-most
+/// `X-Forwarded-For` entry when `TRUST_X_FORWARDED_FOR=true` (i.e. behind a trusted proxy).
+pub fn client_ip(headers: &HeaderMap, extensions: &Extensions) -> IpAddr {
+    let trust_forwarded = env::var("TRUST_X_FORWARDED_FOR")
+        .map(|v| v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if trust_forwarded {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+/// The start of a Markdown note as plain text on one line, cut at a word boundary after at
+/// most `max_chars` characters.
+pub fn excerpt(markdown: &str, max_chars: usize) -> String {
+    let mut text = String::new();
+    for event in Parser::new_ext(
+        markdown,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+    ) {
+        match event {
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(
+                TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item | TagEnd::TableCell,
+            ) => text.push(' '),
+            _ => {}
+        }
+        // Enough to fill the excerpt, whatever the whitespace
+        if text.len() > max_chars * 8 {
+            break;
+        }
+    }
+    let mut excerpt = String::new();
+    let mut len = 0;
+    for word in text.split_whitespace() {
+        let word_len = word.chars().count();
+        let spaced = usize::from(len > 0);
+        if len + spaced + word_len > max_chars {
+            if len == 0 {
+                excerpt.extend(word.chars().take(max_chars));
+            }
+            excerpt.push('…');
+            break;
+        }
+        if spaced == 1 {
+            excerpt.push(' ');
+        }
+        excerpt.push_str(word);
+        len += spaced + word_len;
+    }
+    excerpt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_ip_trusts_forwarded_for_only_when_configured() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "203.0.113.9, 198.51.100.7".parse().unwrap(),
+        );
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+
+        env::remove_var("TRUST_X_FORWARDED_FOR");
+        assert_eq!(
+            client_ip(&headers, &extensions),
+            IpAddr::from([10, 0, 0, 1])
+        );
+
+        // The right-most entry is the one the trusted proxy added
+        env::set_var("TRUST_X_FORWARDED_FOR", "true");
+        assert_eq!(
+            client_ip(&headers, &extensions),
+            IpAddr::from([198, 51, 100, 7])
+        );
+        headers.insert("x-forwarded-for", "not an ip".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, &extensions),
+            IpAddr::from([10, 0, 0, 1])
+        );
+        env::remove_var("TRUST_X_FORWARDED_FOR");
+
+        assert_eq!(
+            client_ip(&HeaderMap::new(), &Extensions::new()),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        );
+    }
+
+    #[test]
+    fn excerpt_is_plain_text_cut_at_a_word() {
+        let markdown = "# Title\n\nSome **bold** and `code`.\n\n- one\n- two";
+        assert_eq!(excerpt(markdown, 100), "Title Some bold and code. one two");
+        assert_eq!(excerpt(markdown, 15), "Title Some bold…");
+        assert_eq!(excerpt("Supercalifragilistic", 5), "Super…");
+        assert_eq!(excerpt("", 10), "");
+    }
 }
@@ -0,0 +1,1070 @@
+use crate::{
+    auth::SessionUser,
+    email_verification,
+    errors::{AppError, AppResult},
+    mailer::{self, Email, SharedMailer},
+    models::{Workspace, WorkspaceInvitation, WorkspaceMember},
+    monitoring,
+    permissions::Role,
+    utils,
+};
+use axum::{
+    extract::{Extension, Json, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+/// Longest workspace name, in characters.
+const MAX_NAME_LEN: usize = 100;
+
+/// A member's role in a workspace, ordered from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceRole {
+    /// Only notes shared with them individually
+    Guest,
+    /// Create notes, edit every note
+    Member,
+    /// Also rename the workspace, manage members and own every note
+    Admin,
+    /// Also delete the workspace and manage owners
+    Owner,
+}
+
+impl WorkspaceRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WorkspaceRole::Guest => "guest",
+            WorkspaceRole::Member => "member",
+            WorkspaceRole::Admin => "admin",
+            WorkspaceRole::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<WorkspaceRole> {
+        match value {
+            "guest" => Some(WorkspaceRole::Guest),
+            "member" => Some(WorkspaceRole::Member),
+            "admin" => Some(WorkspaceRole::Admin),
+            "owner" => Some(WorkspaceRole::Owner),
+            _ => None,
+        }
+    }
+
+    /// Access this role gives to every note of the workspace.
+    pub fn note_role(self) -> Option<Role> {
+        match self {
+            WorkspaceRole::Guest => None,
+            WorkspaceRole::Member => Some(Role::Editor),
+            WorkspaceRole::Admin | WorkspaceRole::Owner => Some(Role::Owner),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WorkspaceRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateMemberRequest {
+    pub role: WorkspaceRole,
+}
+
+#[derive(Deserialize)]
+pub struct InvitationRequest {
+    pub email: String,
+    pub role: WorkspaceRole,
+}
+
+#[derive(Deserialize)]
+pub struct AcceptInvitationRequest {
+    pub token: String,
+}
+
+fn validate_name(name: &str) -> AppResult<String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "Workspace name must be 1 to {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// The user's role in a workspace, if they are a member and it isn't deleted.
+pub async fn workspace_role(
+    pool: &PgPool,
+    workspace_id: Uuid,
+    user_id: Uuid,
+) -> AppResult<Option<WorkspaceRole>> {
+    let role = monitoring::timed(
+        "get_workspace_role",
+        sqlx::query_scalar!(
+            r#"
+            SELECT m.role FROM workspace_members m JOIN workspaces w ON w.id = m.workspace_id
+            WHERE m.workspace_id = $1 AND m.user_id = $2 AND w.deleted_at IS NULL
+            "#,
+            workspace_id,
+            user_id
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get workspace role error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    Ok(role.as_deref().and_then(WorkspaceRole::parse))
+}
+
+/// Require at least `min` in a workspace. Non-members get 404, like for notes.
+pub async fn require_workspace_role(
+    pool: &PgPool,
+    workspace_id: Uuid,
+    user_id: Uuid,
+    min: WorkspaceRole,
+) -> AppResult<WorkspaceRole> {
+    match workspace_role(pool, workspace_id, user_id).await? {
+        None => Err(AppError::NotFound),
+        Some(role) if role < min => Err(AppError::Forbidden(format!(
+            "This requires {} access to the workspace",
+            min.as_str()
+        ))),
+        Some(role) => Ok(role),
+    }
+}
+
+/// The user's personal workspace, created on first use.
+pub async fn personal_workspace(conn: &mut PgConnection, user_id: Uuid) -> AppResult<Uuid> {
+    let created = monitoring::timed(
+        "insert_personal_workspace",
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO workspaces (id, name, personal_owner_id, created_by)
+            VALUES ($1, 'Personal', $2, $2)
+            ON CONFLICT (personal_owner_id) DO NOTHING
+            RETURNING id
+            "#,
+            Uuid::new_v4(),
+            user_id
+        )
+        .fetch_optional(&mut *conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert personal workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    match created {
+        Some(workspace_id) => {
+            insert_member(conn, workspace_id, user_id, WorkspaceRole::Owner, None).await?;
+            Ok(workspace_id)
+        }
+        None => monitoring::timed(
+            "get_personal_workspace",
+            sqlx::query_scalar!(
+                "SELECT id FROM workspaces WHERE personal_owner_id = $1",
+                user_id
+            )
+            .fetch_one(&mut *conn),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Get personal workspace error: {:?}", e);
+            AppError::InternalServerError
+        }),
+    }
+}
+
+/// Add a member, keeping the role of someone who already is one.
+async fn insert_member(
+    conn: &mut PgConnection,
+    workspace_id: Uuid,
+    user_id: Uuid,
+    role: WorkspaceRole,
+    invited_by: Option<Uuid>,
+) -> AppResult<()> {
+    monitoring::timed(
+        "insert_workspace_member",
+        sqlx::query!(
+            r#"
+            INSERT INTO workspace_members (workspace_id, user_id, role, invited_by)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (workspace_id, user_id) DO NOTHING
+            "#,
+            workspace_id,
+            user_id,
+            role.as_str(),
+            invited_by
+        )
+        .execute(conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert workspace member error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    Ok(())
+}
+
+async fn fetch_workspace(pool: &PgPool, workspace_id: Uuid, user_id: Uuid) -> AppResult<Workspace> {
+    monitoring::timed(
+        "get_workspace",
+        sqlx::query_as!(
+            Workspace,
+            r#"
+            SELECT w.id, w.name, w.personal_owner_id IS NOT NULL AS "personal!", m.role,
+                   w.created_at, w.updated_at
+            FROM workspaces w JOIN workspace_members m ON m.workspace_id = w.id
+            WHERE w.id = $1 AND m.user_id = $2 AND w.deleted_at IS NULL
+            "#,
+            workspace_id,
+            user_id
+        )
+        .fetch_optional(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)
+}
+
+async fn fetch_members(pool: &PgPool, workspace_id: Uuid) -> AppResult<Vec<WorkspaceMember>> {
+    monitoring::timed(
+        "list_workspace_members",
+        sqlx::query_as!(
+            WorkspaceMember,
+            r#"
+            SELECT m.user_id, u.username, u.display_name, m.role, m.invited_by, m.joined_at
+            FROM workspace_members m JOIN users u ON u.id = m.user_id
+            WHERE m.workspace_id = $1
+            ORDER BY CASE m.role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 WHEN 'member' THEN 2 ELSE 3 END,
+                     m.joined_at
+            "#,
+            workspace_id
+        )
+        .fetch_all(pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List workspace members error: {:?}", e);
+        AppError::InternalServerError
+    })
+}
+
+/// Lock a team workspace for a membership change, failing for personal workspaces, which
+/// only ever have their owner.
+async fn lock_team_workspace(conn: &mut PgConnection, workspace_id: Uuid) -> AppResult<()> {
+    let personal = monitoring::timed(
+        "lock_workspace",
+        sqlx::query_scalar!(
+            r#"
+            SELECT personal_owner_id IS NOT NULL AS "personal!" FROM workspaces
+            WHERE id = $1 FOR UPDATE
+            "#,
+            workspace_id
+        )
+        .fetch_optional(conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Lock workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    if personal {
+        return Err(AppError::BadRequest(
+            "Personal workspaces can't have other members".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Fail unless the workspace has an owner besides `user_id`. Owners whose account is
+/// scheduled for deletion don't count.
+async fn ensure_other_owner(
+    conn: &mut PgConnection,
+    workspace_id: Uuid,
+    user_id: Uuid,
+) -> AppResult<()> {
+    let other_owner = monitoring::timed(
+        "check_other_workspace_owner",
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM workspace_members m JOIN users u ON u.id = m.user_id
+                WHERE m.workspace_id = $1 AND m.user_id <> $2 AND m.role = 'owner'
+                  AND u.deletion_scheduled_at IS NULL
+            ) AS "exists!"
+            "#,
+            workspace_id,
+            user_id
+        )
+        .fetch_one(conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Check other workspace owner error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    if !other_owner {
+        return Err(AppError::Conflict(
+            "A workspace needs at least one owner".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Fail while `user_id` is the only owner of a team workspace that isn't deleted, so that
+/// deleting their account leaves none without an owner. The workspaces stay locked until
+/// the transaction ends.
+pub async fn ensure_not_sole_owner(conn: &mut PgConnection, user_id: Uuid) -> AppResult<()> {
+    let owned = monitoring::timed(
+        "lock_owned_workspaces",
+        sqlx::query_scalar!(
+            r#"
+            SELECT w.id FROM workspaces w JOIN workspace_members m ON m.workspace_id = w.id
+            WHERE m.user_id = $1 AND m.role = 'owner'
+              AND w.personal_owner_id IS NULL AND w.deleted_at IS NULL
+            ORDER BY w.id
+            FOR UPDATE OF w
+            "#,
+            user_id
+        )
+        .fetch_all(&mut *conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Lock owned workspaces error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    for workspace_id in owned {
+        match ensure_other_owner(conn, workspace_id, user_id).await {
+            Err(AppError::Conflict(_)) => {
+                return Err(AppError::Conflict(
+                    "Make someone else an owner of your team workspaces, or delete them, first"
+                        .into(),
+                ))
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// POST /api/workspaces: create a team workspace owned by the caller.
+#[tracing::instrument(name = "workspaces.create", skip_all, fields(user_id = %user.id))]
+pub async fn create_workspace(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Json(payload): Json<WorkspaceRequest>,
+) -> AppResult<impl IntoResponse> {
+    let name = validate_name(&payload.name)?;
+    let workspace_id = Uuid::new_v4();
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "insert_workspace",
+        sqlx::query!(
+            "INSERT INTO workspaces (id, name, created_by) VALUES ($1, $2, $3)",
+            workspace_id,
+            name,
+            user.id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    insert_member(&mut tx, workspace_id, user.id, WorkspaceRole::Owner, None).await?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%workspace_id, "Workspace created");
+    let workspace = fetch_workspace(&pool, workspace_id, user.id).await?;
+    Ok((StatusCode::CREATED, AxumJson(workspace)))
+}
+
+/// GET /api/workspaces: the caller's workspaces, personal one first.
+#[tracing::instrument(name = "workspaces.list", skip_all, fields(user_id = %user.id))]
+pub async fn list_workspaces(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+) -> AppResult<impl IntoResponse> {
+    let mut conn = pool.acquire().await.map_err(|e| {
+        tracing::error!("Acquire connection error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    personal_workspace(&mut conn, user.id).await?;
+    let workspaces = monitoring::timed(
+        "list_workspaces",
+        sqlx::query_as!(
+            Workspace,
+            r#"
+            SELECT w.id, w.name, w.personal_owner_id IS NOT NULL AS "personal!", m.role,
+                   w.created_at, w.updated_at
+            FROM workspaces w JOIN workspace_members m ON m.workspace_id = w.id
+            WHERE m.user_id = $1 AND w.deleted_at IS NULL
+            ORDER BY w.personal_owner_id IS NULL, LOWER(w.name), w.created_at
+            "#,
+            user.id
+        )
+        .fetch_all(&mut *conn),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List workspaces error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(workspaces)))
+}
+
+/// GET /api/workspaces/{workspace_id}
+#[tracing::instrument(name = "workspaces.get", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn get_workspace(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let workspace = fetch_workspace(&pool, workspace_id, user.id).await?;
+    Ok((StatusCode::OK, AxumJson(workspace)))
+}
+
+/// PUT /api/workspaces/{workspace_id}: rename a workspace. Admins and owners only.
+#[tracing::instrument(name = "workspaces.rename", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn rename_workspace(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+    Json(payload): Json<WorkspaceRequest>,
+) -> AppResult<impl IntoResponse> {
+    require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Admin).await?;
+    let name = validate_name(&payload.name)?;
+    monitoring::timed(
+        "rename_workspace",
+        sqlx::query!(
+            "UPDATE workspaces SET name = $2, updated_at = NOW() WHERE id = $1",
+            workspace_id,
+            name
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Rename workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let workspace = fetch_workspace(&pool, workspace_id, user.id).await?;
+    Ok((StatusCode::OK, AxumJson(workspace)))
+}
+
+/// DELETE /api/workspaces/{workspace_id}: delete a team workspace, moving its notes and
+/// folders to the trash. Owners can restore it until it is purged with them. Owners only;
+/// personal workspaces go with their account.
+#[tracing::instrument(name = "workspaces.delete", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn delete_workspace(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Owner).await?;
+
+    // NOW() is the same for every statement of the transaction, which is how restoring
+    // tells what this deletion trashed
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let deleted = monitoring::timed(
+        "delete_workspace",
+        sqlx::query!(
+            r#"
+            UPDATE workspaces SET deleted_at = NOW(), deleted_by = $2
+            WHERE id = $1 AND personal_owner_id IS NULL AND deleted_at IS NULL
+            "#,
+            workspace_id,
+            user.id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    if !deleted {
+        return Err(AppError::BadRequest(
+            "Personal workspaces can't be deleted".into(),
+        ));
+    }
+    let trashed = monitoring::timed(
+        "trash_workspace_notes",
+        sqlx::query!(
+            r#"
+            UPDATE notes SET deleted_at = NOW(), deleted_by = $2
+            WHERE workspace_id = $1 AND deleted_at IS NULL
+            "#,
+            workspace_id,
+            user.id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Trash workspace notes error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected();
+    monitoring::timed(
+        "delete_workspace_folders",
+        sqlx::query!(
+            "UPDATE folders SET deleted_at = NOW() WHERE workspace_id = $1 AND deleted_at IS NULL",
+            workspace_id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete workspace folders error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%workspace_id, trashed, "Workspace deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/workspaces/{workspace_id}/restore: bring back a deleted workspace with the
+/// notes and folders its deletion moved to the trash. Owners only.
+#[tracing::instrument(name = "workspaces.restore", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn restore_workspace(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    // Members of a deleted workspace get 404 everywhere else, so check the role here
+    let deleted_at = monitoring::timed(
+        "lock_deleted_workspace",
+        sqlx::query_scalar!(
+            r#"
+            SELECT w.deleted_at AS "deleted_at!" FROM workspaces w
+            JOIN workspace_members m ON m.workspace_id = w.id
+            WHERE w.id = $1 AND w.deleted_at IS NOT NULL AND m.user_id = $2 AND m.role = 'owner'
+            FOR UPDATE OF w
+            "#,
+            workspace_id,
+            user.id
+        )
+        .fetch_optional(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Lock deleted workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or(AppError::NotFound)?;
+    let restored = monitoring::timed(
+        "restore_workspace_notes",
+        sqlx::query!(
+            r#"
+            UPDATE notes SET deleted_at = NULL, deleted_by = NULL
+            WHERE workspace_id = $1 AND deleted_at = $2
+            "#,
+            workspace_id,
+            deleted_at
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Restore workspace notes error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected();
+    monitoring::timed(
+        "restore_workspace_folders",
+        sqlx::query!(
+            "UPDATE folders SET deleted_at = NULL WHERE workspace_id = $1 AND deleted_at = $2",
+            workspace_id,
+            deleted_at
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Restore workspace folders error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    monitoring::timed(
+        "restore_workspace",
+        sqlx::query!(
+            "UPDATE workspaces SET deleted_at = NULL, deleted_by = NULL WHERE id = $1",
+            workspace_id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Restore workspace error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%workspace_id, restored, "Workspace restored");
+    let workspace = fetch_workspace(&pool, workspace_id, user.id).await?;
+    Ok((StatusCode::OK, AxumJson(workspace)))
+}
+
+/// GET /api/workspaces/{workspace_id}/members
+#[tracing::instrument(name = "workspaces.members", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn list_members(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Guest).await?;
+    let members = fetch_members(&pool, workspace_id).await?;
+    Ok((StatusCode::OK, AxumJson(members)))
+}
+
+/// PUT /api/workspaces/{workspace_id}/members/{user_id}: change a member's role. Admins
+/// manage admins, members and guests; only owners make or demote owners.
+#[tracing::instrument(name = "workspaces.update_member", skip_all, fields(%workspace_id, %user_id))]
+pub async fn update_member(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path((workspace_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<UpdateMemberRequest>,
+) -> AppResult<impl IntoResponse> {
+    let caller_role =
+        require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Admin).await?;
+    let current = workspace_role(&pool, workspace_id, user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if (current == WorkspaceRole::Owner || payload.role == WorkspaceRole::Owner)
+        && caller_role != WorkspaceRole::Owner
+    {
+        return Err(AppError::Forbidden(
+            "Only owners can make or demote owners".into(),
+        ));
+    }
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    lock_team_workspace(&mut tx, workspace_id).await?;
+    if payload.role != WorkspaceRole::Owner {
+        ensure_other_owner(&mut tx, workspace_id, user_id).await?;
+    }
+    let updated = monitoring::timed(
+        "update_workspace_member",
+        sqlx::query!(
+            "UPDATE workspace_members SET role = $3 WHERE workspace_id = $1 AND user_id = $2",
+            workspace_id,
+            user_id,
+            payload.role.as_str()
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Update workspace member error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    if !updated {
+        return Err(AppError::NotFound);
+    }
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%workspace_id, %user_id, role = payload.role.as_str(), "Workspace member changed");
+    let members = fetch_members(&pool, workspace_id).await?;
+    Ok((StatusCode::OK, AxumJson(members)))
+}
+
+/// DELETE /api/workspaces/{workspace_id}/members/{user_id}: remove a member (admins; owners
+/// for owners), or leave the workspace. The last owner can't leave.
+#[tracing::instrument(name = "workspaces.remove_member", skip_all, fields(%workspace_id, %user_id))]
+pub async fn remove_member(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path((workspace_id, user_id)): Path<(Uuid, Uuid)>,
+) -> AppResult<impl IntoResponse> {
+    let min = if user_id == user.id {
+        WorkspaceRole::Guest
+    } else {
+        WorkspaceRole::Admin
+    };
+    let caller_role = require_workspace_role(&pool, workspace_id, user.id, min).await?;
+    let current = workspace_role(&pool, workspace_id, user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if current == WorkspaceRole::Owner && caller_role != WorkspaceRole::Owner {
+        return Err(AppError::Forbidden("Only owners can remove owners".into()));
+    }
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    lock_team_workspace(&mut tx, workspace_id).await?;
+    ensure_other_owner(&mut tx, workspace_id, user_id).await?;
+    let removed = monitoring::timed(
+        "delete_workspace_member",
+        sqlx::query!(
+            "DELETE FROM workspace_members WHERE workspace_id = $1 AND user_id = $2",
+            workspace_id,
+            user_id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Delete workspace member error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+    if !removed {
+        return Err(AppError::NotFound);
+    }
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(%workspace_id, %user_id, "Workspace member removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/workspaces/{workspace_id}/invitations: email an invitation to join as admin,
+/// member or guest. A new invitation replaces a pending one for the same address.
+#[tracing::instrument(name = "workspaces.invite", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn create_invitation(
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<SharedMailer>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+    Json(payload): Json<InvitationRequest>,
+) -> AppResult<impl IntoResponse> {
+    require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Admin).await?;
+    if payload.role == WorkspaceRole::Owner {
+        return Err(AppError::BadRequest(
+            "Invite as admin, member or guest; members can be made owners after joining".into(),
+        ));
+    }
+    let email = email_verification::normalize_email(&payload.email)?.to_lowercase();
+
+    let already_member = monitoring::timed(
+        "check_workspace_member_email",
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM workspace_members m JOIN users u ON u.id = m.user_id
+                WHERE m.workspace_id = $1 AND LOWER(u.email) = $2
+            ) AS "exists!"
+            "#,
+            workspace_id,
+            email
+        )
+        .fetch_one(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Check workspace member email error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    if already_member {
+        return Err(AppError::Conflict(
+            "This address belongs to a member already".into(),
+        ));
+    }
+
+    let token = utils::generate_token();
+    let ttl_days: i64 = utils::env_or("WORKSPACE_INVITATION_TTL_DAYS", 7);
+    let expires_at = Utc::now() + chrono::Duration::days(ttl_days);
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    lock_team_workspace(&mut tx, workspace_id).await?;
+    monitoring::timed(
+        "revoke_pending_invitations",
+        sqlx::query!(
+            r#"
+            UPDATE workspace_invitations SET revoked_at = NOW()
+            WHERE workspace_id = $1 AND email = $2 AND accepted_at IS NULL AND revoked_at IS NULL
+            "#,
+            workspace_id,
+            email
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke pending invitations error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let invitation = monitoring::timed(
+        "insert_workspace_invitation",
+        sqlx::query_as!(
+            WorkspaceInvitation,
+            r#"
+            INSERT INTO workspace_invitations
+                (id, workspace_id, email, role, token_hash, invited_by, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, email, role, invited_by, expires_at, created_at
+            "#,
+            Uuid::new_v4(),
+            workspace_id,
+            email,
+            payload.role.as_str(),
+            utils::hash_token(&token),
+            user.id,
+            expires_at
+        )
+        .fetch_one(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Insert workspace invitation error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let context = monitoring::timed(
+        "get_invitation_context",
+        sqlx::query!(
+            r#"
+            SELECT w.name, COALESCE(u.display_name, u.username) AS "inviter!"
+            FROM workspaces w, users u WHERE w.id = $1 AND u.id = $2
+            "#,
+            workspace_id,
+            user.id
+        )
+        .fetch_one(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get invitation context error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    let link = format!("{}/invitations/{}", utils::app_base_url(), token);
+    mailer::send_in_background(
+        mailer,
+        Email {
+            to: email,
+            subject: format!("{} invited you to {}", context.inviter, context.name),
+            body: format!(
+                "{} invited you to join the workspace \"{}\" on Noteflow as {}:\n{}\n\nThe invitation expires in {} days.\n",
+                context.inviter,
+                context.name,
+                payload.role.as_str(),
+                link,
+                ttl_days
+            ),
+        },
+    );
+
+    tracing::info!(%workspace_id, invitation_id = %invitation.id, role = payload.role.as_str(), "Workspace invitation sent");
+    Ok((StatusCode::CREATED, AxumJson(invitation)))
+}
+
+/// GET /api/workspaces/{workspace_id}/invitations: invitations that can still be accepted.
+#[tracing::instrument(name = "workspaces.invitations", skip_all, fields(%workspace_id, user_id = %user.id))]
+pub async fn list_invitations(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path(workspace_id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Admin).await?;
+    let invitations = monitoring::timed(
+        "list_workspace_invitations",
+        sqlx::query_as!(
+            WorkspaceInvitation,
+            r#"
+            SELECT id, email, role, invited_by, expires_at, created_at
+            FROM workspace_invitations
+            WHERE workspace_id = $1 AND accepted_at IS NULL AND revoked_at IS NULL
+              AND expires_at > NOW()
+            ORDER BY created_at DESC
+            "#,
+            workspace_id
+        )
+        .fetch_all(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("List workspace invitations error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    Ok((StatusCode::OK, AxumJson(invitations)))
+}
+
+/// DELETE /api/workspaces/{workspace_id}/invitations/{invitation_id}
+#[tracing::instrument(name = "workspaces.revoke_invitation", skip_all, fields(%workspace_id, %invitation_id))]
+pub async fn revoke_invitation(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Path((workspace_id, invitation_id)): Path<(Uuid, Uuid)>,
+) -> AppResult<impl IntoResponse> {
+    require_workspace_role(&pool, workspace_id, user.id, WorkspaceRole::Admin).await?;
+    let revoked = monitoring::timed(
+        "revoke_workspace_invitation",
+        sqlx::query!(
+            r#"
+            UPDATE workspace_invitations SET revoked_at = NOW()
+            WHERE id = $1 AND workspace_id = $2 AND accepted_at IS NULL AND revoked_at IS NULL
+            "#,
+            invitation_id,
+            workspace_id
+        )
+        .execute(&pool),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Revoke workspace invitation error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .rows_affected()
+        > 0;
+
+    if !revoked {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/workspaces/invitations/accept: join a workspace with an emailed token. The
+/// caller's verified email must be the invited address; members keep their current role.
+#[tracing::instrument(name = "workspaces.accept_invitation", skip_all, fields(user_id = %user.id))]
+pub async fn accept_invitation(
+    Extension(pool): Extension<PgPool>,
+    user: SessionUser,
+    Json(payload): Json<AcceptInvitationRequest>,
+) -> AppResult<impl IntoResponse> {
+    let invalid = || AppError::BadRequest("Invalid or expired invitation".into());
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        tracing::error!("Begin transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    let invitation = monitoring::timed(
+        "get_workspace_invitation",
+        sqlx::query!(
+            r#"
+            SELECT i.id, i.workspace_id, i.email, i.role, i.invited_by
+            FROM workspace_invitations i JOIN workspaces w ON w.id = i.workspace_id
+            WHERE i.token_hash = $1 AND i.accepted_at IS NULL AND i.revoked_at IS NULL
+              AND i.expires_at > NOW() AND w.deleted_at IS NULL
+            FOR UPDATE OF i
+            "#,
+            utils::hash_token(&payload.token)
+        )
+        .fetch_optional(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Get workspace invitation error: {:?}", e);
+        AppError::InternalServerError
+    })?
+    .ok_or_else(invalid)?;
+
+    // A forwarded invitation doesn't let someone else in
+    let email_matches = monitoring::timed(
+        "check_invitation_email",
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM users
+                WHERE id = $1 AND LOWER(email) = $2 AND email_verified_at IS NOT NULL
+            ) AS "exists!"
+            "#,
+            user.id,
+            invitation.email
+        )
+        .fetch_one(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Check invitation email error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    if !email_matches {
+        return Err(AppError::Forbidden(
+            "This invitation was sent to another verified email address".into(),
+        ));
+    }
+
+    let role = WorkspaceRole::parse(&invitation.role).ok_or(AppError::InternalServerError)?;
+    insert_member(
+        &mut tx,
+        invitation.workspace_id,
+        user.id,
+        role,
+        invitation.invited_by,
+    )
+    .await?;
+    monitoring::timed(
+        "accept_workspace_invitation",
+        sqlx::query!(
+            "UPDATE workspace_invitations SET accepted_at = NOW() WHERE id = $1",
+            invitation.id
+        )
+        .execute(&mut *tx),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Accept workspace invitation error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Commit transaction error: {:?}", e);
+        AppError::InternalServerError
+    })?;
+
+    tracing::info!(workspace_id = %invitation.workspace_id, "Workspace invitation accepted");
+    let workspace = fetch_workspace(&pool, invitation.workspace_id, user.id).await?;
+    Ok((StatusCode::OK, AxumJson(workspace)))
+}
@@ -1,10 +1,28 @@
+use crate::{
+    access_tokens::Scope,
+    auth::AuthUser,
+    errors::{AppError, AppResult},
+    monitoring,
+    permissions::{self, Role},
+    revocation::TokenDenylist,
+    utils,
+};
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query,
+    },
+    http::HeaderMap,
     response::IntoResponse,
     Extension,
 };
-use futures::stream::StreamExt;
-use tokio::sync::broadcast;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::Instrument;
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
@@ -15,15 +33,124 @@ pub enum WsMessage {
 type Tx = broadcast::Sender<WsMessage>;
 type Rx = broadcast::Receiver<WsMessage>;
 
-pub async fn note_ws(ws: WebSocketUpgrade, Extension(tx): Extension<Tx>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, tx))
+/// Tracks how many sockets are connected to each note so the active room count can be reported.
+#[derive(Clone, Default)]
+pub struct Rooms(Arc<Mutex<HashMap<Uuid, usize>>>);
+
+impl Rooms {
+    fn join(&self, note_id: Uuid) {
+        let mut rooms = self.0.lock().unwrap();
+        *rooms.entry(note_id).or_insert(0) += 1;
+        monitoring::ws_connection_opened();
+        monitoring::set_ws_rooms(rooms.len());
+    }
+
+    fn leave(&self, note_id: Uuid) {
+        let mut rooms = self.0.lock().unwrap();
+        if let Some(count) = rooms.get_mut(&note_id) {
+            *count -= 1;
+            if *count == 0 {
+                rooms.remove(&note_id);
+            }
+        }
+        monitoring::ws_connection_closed();
+        monitoring::set_ws_rooms(rooms.len());
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WsQuery {
+    /// Access token; browsers can't send an `Authorization` header with a WebSocket
+    pub token: Option<String>,
+}
+
+/// Credentials of a socket, checked again while it stays open.
+struct Access {
+    pool: PgPool,
+    denylist: TokenDenylist,
+    token: String,
+    note_id: Uuid,
+}
+
+impl Access {
+    /// Whether the caller may send changes. Fails once the token is revoked or expired, or
+    /// the caller lost access to the note.
+    async fn check(&self) -> AppResult<bool> {
+        let user = AuthUser::from_token(&self.pool, &self.denylist, &self.token).await?;
+        user.require(Scope::NotesRead)?;
+        let role =
+            permissions::require_role(&self.pool, self.note_id, user.id, Role::Viewer).await?;
+        Ok(role >= Role::Editor && user.require(Scope::NotesWrite).is_ok())
+    }
 }
 
-async fn handle_socket(mut socket: WebSocket, tx: Tx) {
+/// Join a note's room. Requires access to the note; only editors (and tokens with
+/// `notes:write`) may send changes, everyone else just receives them. Access is checked
+/// again before relaying each change, and every `WS_ACCESS_CHECK_SECS` (default 30).
+#[tracing::instrument(name = "ws.upgrade", skip_all, fields(%note_id))]
+#[allow(clippy::too_many_arguments)]
+pub async fn note_ws(
+    ws: WebSocketUpgrade,
+    Path(note_id): Path<Uuid>,
+    Query(query): Query<WsQuery>,
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+    Extension(denylist): Extension<TokenDenylist>,
+    Extension(tx): Extension<Tx>,
+    Extension(rooms): Extension<Rooms>,
+) -> AppResult<impl IntoResponse> {
+    let token = utils::bearer_token(&headers)
+        .or(query.token.as_deref())
+        .ok_or(AppError::Unauthorized)?;
+    let user = AuthUser::from_token(&pool, &denylist, token).await?;
+    let access = Access {
+        pool,
+        denylist,
+        token: token.to_string(),
+        note_id,
+    };
+    let can_edit = access.check().await?;
+
+    // Long-lived span covering the whole session; linked to (not nested in) the upgrade request
+    let session = tracing::info_span!("ws.session", %note_id, user_id = %user.id, can_edit);
+    session.follows_from(tracing::Span::current());
+
+    Ok(ws.on_upgrade(move |socket| {
+        async move {
+            rooms.join(note_id);
+            tracing::info!("ws session opened");
+            handle_socket(socket, tx, access, can_edit).await;
+            rooms.leave(note_id);
+            tracing::info!("ws session closed");
+        }
+        .instrument(session)
+    }))
+}
+
+/// Relay changes between the sockets in the room of `access.note_id`. Messages for other
+/// notes are dropped both ways, as are incoming changes from sockets that may not edit. The
+/// socket is closed once its access is gone.
+async fn handle_socket(mut socket: WebSocket, tx: Tx, access: Access, mut can_edit: bool) {
+    let room = access.note_id;
     let mut rx: Rx = tx.subscribe();
+    let period = Duration::from_secs(utils::env_or("WS_ACCESS_CHECK_SECS", 30));
+    let mut recheck = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
 
     loop {
         tokio::select! {
+            // Receivers lose access too, so check it even when they send nothing
+            _ = recheck.tick() => {
+                match access.check().await {
+                    Ok(allowed) => can_edit = allowed,
+                    Err(err) => {
+                        tracing::info!(?err, "ws access lost");
+                        let _ = socket.send(Message::Close(None)).await;
+                        break;
+                    }
+                }
+            }
+
+
             // Incoming messages from the WebSocket client
             incoming = socket.recv() => {
                 match incoming {
@@ -32,6 +159,24 @@ async fn handle_socket(mut socket: WebSocket, tx: Tx) {
                             // Expected incoming format: "note_id:content"
                             if let Some((note_id_str, content)) = text.split_once(':') {
                                 if let Ok(note_id) = Uuid::parse_str(note_id_str) {
+                                    if note_id != room || !can_edit {
+                                        tracing::debug!(%note_id, "ws message rejected");
+                                        continue;
+                                    }
+                                    match access.check().await {
+                                        Ok(true) => {}
+                                        Ok(false) => {
+                                            can_edit = false;
+                                            tracing::debug!(%note_id, "ws message rejected");
+                                            continue;
+                                        }
+                                        Err(err) => {
+                                            tracing::info!(?err, "ws access lost");
+                                            let _ = socket.send(Message::Close(None)).await;
+                                            break;
+                                        }
+                                    }
+                                    tracing::debug!(%note_id, bytes = content.len(), "ws message received");
                                     let _ = tx.send(WsMessage::Sync {
                                         note_id,
                                         content: content.to_string(),
@@ -39,6 +184,7 @@ async fn handle_socket(mut socket: WebSocket, tx: Tx) {
                                 }
                             }
                         } else if let Message::Close(_) = msg {
+                            tracing::debug!("ws close frame received");
                             break;
                         }
                     }
@@ -51,13 +197,22 @@ async fn handle_socket(mut socket: WebSocket, tx: Tx) {
             broadcasted = rx.recv() => {
                 match broadcasted {
                     Ok(WsMessage::Sync { note_id, content }) => {
+                        if note_id != room {
+                            continue;
+                        }
                         let msg_text = format!("{}:{}", note_id, content);
                         // axum 0.8 expects Utf8Bytes for Message::Text; .into() converts String
                         if socket.send(Message::Text(msg_text.into())).await.is_err() {
                             break;
                         }
+                        tracing::debug!(%note_id, bytes = content.len(), "ws message sent");
+                    }
+                    // Slow receiver: skip the dropped messages and keep the session alive
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "ws broadcast lagged");
+                        monitoring::record_ws_lag(skipped);
                     }
-                    Err(_) => break,
+                    Err(RecvError::Closed) => break,
                 }
             }
         }